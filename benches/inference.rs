@@ -0,0 +1,85 @@
+//! Compares the ways a read-only network can be evaluated on the
+//! 100x100 mesh of points that `Classifier2D::draw` shades every frame.
+
+use std::hint::black_box;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use neural_net_fun::{
+    engine::{ActivationType, BatchBuffers, MultiLayerPerceptron},
+    layer::{Dense, LayerSpec, ReadOnlyLayer},
+};
+
+const NEURONS_PER_LAYER: usize = 16;
+
+fn mesh() -> Vec<f64> {
+    (-50..50)
+        .flat_map(|y| (-50..50).flat_map(move |x| [x as f64 / 30.0, y as f64 / 30.0]))
+        .collect()
+}
+
+/// Benchmarks a network with two hidden layers, like the default one
+/// in the app. The cost of sigmoid activations tends to dominate, so
+/// ReLU is benchmarked too.
+fn bench_inference(c: &mut Criterion) {
+    for activation in [ActivationType::Sigmoid, ActivationType::Relu] {
+        bench_activation(c, activation);
+    }
+}
+
+fn bench_activation(c: &mut Criterion, activation: ActivationType) {
+    let mesh = mesh();
+    let mut group = c.benchmark_group(format!("mesh_{activation:?}").to_lowercase());
+
+    // How dense layers were evaluated before they were turned into
+    // matrices: one neuron at a time.
+    let neuron_layers: Vec<Box<ReadOnlyLayer>> = vec![
+        Box::new(Dense::<f64>::new(2, activation, NEURONS_PER_LAYER)),
+        Box::new(Dense::<f64>::new(
+            NEURONS_PER_LAYER,
+            activation,
+            NEURONS_PER_LAYER,
+        )),
+        Box::new(Dense::<f64>::new(NEURONS_PER_LAYER, activation, 1)),
+    ];
+    group.bench_function("neurons", |b| {
+        b.iter(|| {
+            for inputs in mesh.chunks_exact(2) {
+                let mut outputs = inputs.to_vec();
+                for layer in &neuron_layers {
+                    outputs = layer.output(&outputs);
+                }
+                black_box(outputs);
+            }
+        })
+    });
+
+    let mlp = MultiLayerPerceptron::from_specs(
+        2,
+        activation,
+        vec![
+            LayerSpec::Dense(NEURONS_PER_LAYER),
+            LayerSpec::Dense(NEURONS_PER_LAYER),
+            LayerSpec::Dense(1),
+        ],
+    )
+    .read_only();
+    group.bench_function("matrix", |b| {
+        b.iter(|| {
+            for inputs in mesh.chunks_exact(2) {
+                black_box(mlp.output(inputs));
+            }
+        })
+    });
+
+    let mut buffers = BatchBuffers::default();
+    group.bench_function("matrix_batch", |b| {
+        b.iter(|| {
+            black_box(mlp.batch_output(&mesh, &mut buffers));
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_inference);
+criterion_main!(benches);
@@ -0,0 +1,172 @@
+//! Trains a network for every configuration in a hyperparameter search
+//! without opening a window, and prints the results from best to worst.
+
+use std::process::ExitCode;
+
+use neural_net_fun::{
+    dataset::load_datapoints,
+    search::{
+        SearchSpace, SearchStrategy, TrialResult, infer_task, parse_activation, parse_architecture,
+        rank, results_to_csv, results_to_json, run_trial, trial_configs,
+    },
+};
+
+const USAGE: &str = "Usage: hyperparameter_search DATASET [OPTIONS]
+
+DATASET is a CSV file of `x,y,target` lines, like the ones the app saves
+with F7. If any targets are numbers rather than labels, the networks are
+trained for regression.
+
+Options:
+  --steps N              Training steps per configuration (default 500)
+  --random N             Try N random configurations instead of all of them
+  --search-seed N        Seed for choosing random configurations (default 0)
+  --architectures LIST   Hidden layer widths to try (default none,16,16-16)
+  --activations LIST     Activation functions to try (default sigmoid,tanh,relu)
+  --learning-rates LIST  Learning rates to try (default 0.1,0.5,1)
+  --seeds LIST           Weight initialization seeds to try (default 1)
+  --format csv|json      Output format (default csv)
+
+Lists are separated by commas.";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Csv,
+    Json,
+}
+
+struct Args {
+    dataset: String,
+    steps: u64,
+    space: SearchSpace,
+    strategy: SearchStrategy,
+    format: Format,
+}
+
+fn parse_list<T>(value: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Vec<T>, String> {
+    value
+        .split(',')
+        .map(|item| parse(item.trim()).ok_or_else(|| format!("invalid value {item:?}")))
+        .collect()
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut dataset = None;
+    let mut steps = 500;
+    let mut num_random_trials = None;
+    let mut search_seed = 0;
+    let mut space = SearchSpace::default();
+    let mut format = Format::Csv;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if dataset.replace(arg.clone()).is_some() {
+                return Err(format!("unexpected argument {arg:?}"));
+            }
+            continue;
+        }
+        let value = args.next().ok_or_else(|| format!("{arg} needs a value"))?;
+        let number = || {
+            value
+                .parse()
+                .map_err(|_| format!("invalid value for {arg}"))
+        };
+        match arg.as_str() {
+            "--steps" => steps = number()?,
+            "--random" => num_random_trials = Some(number()? as usize),
+            "--search-seed" => search_seed = number()?,
+            "--architectures" => space.architectures = parse_list(value, parse_architecture)?,
+            "--activations" => space.activations = parse_list(value, parse_activation)?,
+            "--learning-rates" => {
+                space.learning_rates = parse_list(value, |rate| {
+                    rate.parse().ok().filter(|&rate: &f64| rate > 0.0)
+                })?
+            }
+            "--seeds" => space.seeds = parse_list(value, |seed| seed.parse().ok())?,
+            "--format" => {
+                format = match value.as_str() {
+                    "csv" => Format::Csv,
+                    "json" => Format::Json,
+                    _ => return Err(format!("unknown format {value:?}")),
+                }
+            }
+            _ => return Err(format!("unknown option {arg}")),
+        }
+    }
+    let strategy = match num_random_trials {
+        Some(num_trials) => SearchStrategy::Random {
+            num_trials,
+            seed: search_seed,
+        },
+        None => SearchStrategy::Grid,
+    };
+    Ok(Args {
+        dataset: dataset.ok_or("no dataset given")?,
+        steps,
+        space,
+        strategy,
+        format,
+    })
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args = match parse_args(&args) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let datapoints = match load_datapoints(&args.dataset) {
+        Ok(datapoints) => datapoints,
+        Err(err) => {
+            eprintln!("Unable to load {}: {err}", args.dataset);
+            return ExitCode::FAILURE;
+        }
+    };
+    let task = infer_task(&datapoints);
+    let configs = trial_configs(&args.space, args.strategy);
+    eprintln!(
+        "Training {} configurations for {} steps each on {} datapoints ({:?}).",
+        configs.len(),
+        args.steps,
+        datapoints.len(),
+        task
+    );
+    let mut results: Vec<TrialResult> = configs
+        .iter()
+        .enumerate()
+        .map(|(index, config)| {
+            let result = run_trial(&datapoints, task, config, args.steps);
+            let validation_text = match result.validation_loss {
+                Some(loss) => format!(", validation loss {loss:.4}"),
+                None => String::new(),
+            };
+            eprintln!(
+                "[{}/{}] {} {} lr={} seed={}: loss {:.4}{}",
+                index + 1,
+                configs.len(),
+                result.architecture,
+                config.activation.name(),
+                config.learning_rate,
+                config.seed,
+                result.loss,
+                validation_text
+            );
+            result
+        })
+        .collect();
+    rank(&mut results, task);
+    match args.format {
+        Format::Csv => print!("{}", results_to_csv(&results)),
+        Format::Json => match results_to_json(&results) {
+            Ok(json) => println!("{json}"),
+            Err(err) => {
+                eprintln!("Unable to write JSON: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+    }
+    ExitCode::SUCCESS
+}
@@ -1,8 +1,13 @@
-use std::fmt::Display;
+use std::{fmt::Display, ops::Range, path::Path};
 
 use crate::{
-    engine::{ActivationType, MultiLayerPerceptron, rand_f64},
+    engine::{ActivationType, BatchBuffers, MultiLayerPerceptron, Regularization, rand_f64},
+    export::to_rust_source,
+    features::Features,
+    layer::LayerSpec,
+    model_file::{ModelFile, ModelFileError, ModelFormat, ModelMetadata},
     plot::Plot,
+    summary::ModelSummary,
     value::Value,
 };
 
@@ -13,28 +18,55 @@ use rayon::prelude::*;
 /// as this will make the data much easier to fit.
 const POINT_SCALE: f64 = 30.0;
 
+/// The plot coordinates that the mesh of points shading the plot
+/// covers, along each axis.
+const MESH_RANGE: Range<i32> = -50..50;
+
+/// The number of cells along each side of a neuron's heatmap.
+const HEATMAP_RESOLUTION: i32 = 20;
+
+/// The length of the input gradient arrow, in plot units, for each unit
+/// of the gradient's magnitude.
+const INPUT_GRADIENT_ARROW_SCALE: f32 = 4.0;
+
+/// The longest that the input gradient arrow gets, in plot units.
+const MAX_INPUT_GRADIENT_ARROW_LENGTH: f32 = 20.0;
+
+/// How many datapoints each worker computes the gradient of at a time
+/// when training in parallel.
+const POINTS_PER_CHUNK: usize = 64;
+
+/// The most classes that multi-class classification supports, which
+/// is how many labels there are.
+pub const MAX_CLASSES: usize = 8;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Label2D {
     Blue,
     Red,
+    Green,
+    Orange,
+    Purple,
+    Cyan,
+    Yellow,
+    Pink,
 }
 
 impl From<Label2D> for f64 {
     /// Converts the label into its "idealized"
     /// float value, i.e. how a zero-loss
     /// model would classify a datapoint with the
-    /// label.
+    /// label. This is the label's index, so blue
+    /// and red are 0 and 1.
     fn from(value: Label2D) -> Self {
-        match value {
-            Label2D::Blue => 0.0,
-            Label2D::Red => 1.0,
-        }
+        value.index() as f64
     }
 }
 
 impl From<f64> for Label2D {
     /// Given a value between 0 and 1, returns the
-    /// closest appropriate label for it.
+    /// closest appropriate label for it, which is
+    /// either blue or red.
     fn from(value: f64) -> Self {
         if value <= 0.5 {
             Label2D::Blue
@@ -44,12 +76,305 @@ impl From<f64> for Label2D {
     }
 }
 
-const LIGHT_RED: Color = Color::new(1.0, 0.3333333333333333, 0.3333333333333333, 1.0);
-const RED: Color = Color::new(0.6666666666666666, 0.0, 0.0, 1.0);
-const LIGHT_BLUE: Color = Color::new(0.3333333333333333, 0.3333333333333333, 1.0, 1.0);
-const BLUE: Color = Color::new(0.0, 0.0, 0.6666666666666666, 1.0);
+/// What the network is being trained to predict.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Task {
+    /// Predicting the label of each point, with a sigmoid output.
+    #[default]
+    Classification,
+    /// Predicting which of the first `num_classes` labels each point
+    /// has, with one linear output per class that a softmax turns into
+    /// probabilities.
+    MultiClass { num_classes: usize },
+    /// Predicting a continuous value at each point, with a linear
+    /// output.
+    Regression,
+}
+
+impl Task {
+    /// The number of outputs that the network needs.
+    pub fn num_outputs(&self) -> usize {
+        match self {
+            Task::MultiClass { num_classes } => *num_classes,
+            Task::Classification | Task::Regression => 1,
+        }
+    }
+
+    /// Returns whether the network's outputs count as a correct
+    /// classification of a datapoint with the given target. Nothing
+    /// counts as correct for regression.
+    pub fn is_correct(&self, outputs: &[f64], target: f64) -> bool {
+        match self {
+            Task::Classification => Label2D::from(outputs[0]) == Label2D::from(target),
+            Task::MultiClass { num_classes } => argmax(outputs) == class(target, *num_classes),
+            Task::Regression => false,
+        }
+    }
+
+    /// Returns the loss of the network's outputs on a datapoint with the
+    /// given target: the cross-entropy of their softmax for multi-class
+    /// classification, and the squared error otherwise.
+    pub fn loss(&self, outputs: &[f64], target: f64) -> f64 {
+        match self {
+            Task::MultiClass { num_classes } => -softmax(outputs)[class(target, *num_classes)]
+                .max(f64::MIN_POSITIVE)
+                .ln(),
+            Task::Classification | Task::Regression => (target - outputs[0]).powi(2),
+        }
+    }
+
+    /// Returns the gradient of [Self::loss] with respect to each output.
+    pub fn loss_gradient(&self, outputs: &[f64], target: f64) -> Vec<f64> {
+        match self {
+            Task::MultiClass { num_classes } => {
+                let target_class = class(target, *num_classes);
+                softmax(outputs)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, p)| if index == target_class { p - 1.0 } else { p })
+                    .collect()
+            }
+            Task::Classification | Task::Regression => vec![2.0 * (outputs[0] - target)],
+        }
+    }
+
+    /// Returns the absolute error of the network's outputs. For
+    /// multi-class classification this is how far the probability of
+    /// the target's class is from 1.
+    pub fn absolute_error(&self, outputs: &[f64], target: f64) -> f64 {
+        match self {
+            Task::MultiClass { num_classes } => 1.0 - softmax(outputs)[class(target, *num_classes)],
+            Task::Classification | Task::Regression => (target - outputs[0]).abs(),
+        }
+    }
+
+    /// Returns the gradient, with respect to each output, of the value
+    /// that [Classifier2D::input_gradient] differentiates: the output
+    /// itself, or for multi-class classification the log-probability
+    /// of the predicted class.
+    fn prediction_gradient(&self, outputs: &[f64]) -> Vec<f64> {
+        match self {
+            Task::MultiClass { .. } => {
+                let predicted = argmax(outputs);
+                softmax(outputs)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, p)| if index == predicted { 1.0 - p } else { -p })
+                    .collect()
+            }
+            Task::Classification | Task::Regression => vec![1.0],
+        }
+    }
+
+    /// Returns the color that the plot is shaded with where the network
+    /// has the given outputs.
+    pub fn color(&self, outputs: &[f64], enable_shading: bool) -> Color {
+        match self {
+            Task::Classification => Label2D::dark_color(outputs[0], enable_shading),
+            Task::MultiClass { .. } => {
+                let probabilities = softmax(outputs);
+                let predicted = argmax(outputs);
+                // Shade by how much more confident the network is than
+                // it would be by guessing.
+                let chance = 1.0 / probabilities.len() as f64;
+                let confidence = (probabilities[predicted] - chance) / (1.0 - chance);
+                Label2D::from_index(predicted)
+                    .dark()
+                    .with_alpha(if enable_shading {
+                        confidence as f32
+                    } else {
+                        1.0
+                    })
+            }
+            Task::Regression => colormap(outputs[0]),
+        }
+    }
+
+    /// The activation function of the network's output.
+    pub fn output_activation(&self) -> ActivationType {
+        match self {
+            Task::Classification => ActivationType::Sigmoid,
+            // The softmax is applied by the loss instead.
+            Task::MultiClass { .. } | Task::Regression => ActivationType::Linear,
+        }
+    }
+
+    /// A short lowercase name for the task, e.g. `3-class classification`.
+    pub fn name(&self) -> String {
+        match self {
+            Task::Classification => "classification".to_owned(),
+            Task::MultiClass { num_classes } => format!("{num_classes}-class classification"),
+            Task::Regression => "regression".to_owned(),
+        }
+    }
+}
+
+/// Returns the class of a datapoint with the given target, out of
+/// `num_classes`.
+fn class(target: f64, num_classes: usize) -> usize {
+    (target.round().max(0.0) as usize).min(num_classes - 1)
+}
+
+/// Returns the index of the largest output.
+pub fn argmax(outputs: &[f64]) -> usize {
+    outputs
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map_or(0, |(index, _)| index)
+}
+
+/// Turns the outputs of a network into probabilities that sum to 1.
+pub fn softmax(outputs: &[f64]) -> Vec<f64> {
+    let max = outputs.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let exps: Vec<f64> = outputs.iter().map(|output| (output - max).exp()).collect();
+    let sum: f64 = exps.iter().sum();
+    exps.into_iter().map(|exp| exp / sum).collect()
+}
+
+/// Like [softmax], but returns the log-probability of a single class as
+/// part of an expression graph.
+fn log_softmax(outputs: &[Value], class: usize) -> Value {
+    // Subtracting the largest output, which is treated as a constant,
+    // keeps the exponentials from overflowing.
+    let max = outputs
+        .iter()
+        .map(Value::as_f64)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let sum = outputs
+        .iter()
+        .map(|output| (output.clone() - Value::from(max)).exp())
+        .fold(Value::from(0.0), |sum, exp| sum + exp);
+    outputs[class].clone() - Value::from(max) - sum.ln()
+}
+
+/// The value a datapoint should be given by the network.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target2D {
+    Label(Label2D),
+    Value(f64),
+}
+
+impl From<Label2D> for Target2D {
+    fn from(value: Label2D) -> Self {
+        Target2D::Label(value)
+    }
+}
+
+impl From<f64> for Target2D {
+    fn from(value: f64) -> Self {
+        Target2D::Value(value)
+    }
+}
+
+impl Target2D {
+    /// Labels are converted to their idealized values, so they can be
+    /// used for regression too.
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Target2D::Label(label) => label.as_f64(),
+            Target2D::Value(value) => *value,
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        match self {
+            Target2D::Label(label) => label.color(),
+            Target2D::Value(value) => colormap(*value),
+        }
+    }
+}
+
+/// The range of values that the colormap covers. Values outside of it
+/// are given the color at the nearest end.
+pub const COLORMAP_RANGE: (f64, f64) = (-1.0, 1.0);
+
+/// Evenly spaced stops of the colormap, which approximates viridis.
+const COLORMAP_STOPS: [Color; 5] = [
+    Color::new(0.267, 0.005, 0.329, 1.0),
+    Color::new(0.230, 0.322, 0.546, 1.0),
+    Color::new(0.128, 0.567, 0.551, 1.0),
+    Color::new(0.369, 0.789, 0.383, 1.0),
+    Color::new(0.993, 0.906, 0.144, 1.0),
+];
+
+/// Converts a value in [COLORMAP_RANGE] to a color on a continuous
+/// colormap that goes from dark purple to yellow.
+pub fn colormap(value: f64) -> Color {
+    let (min, max) = COLORMAP_RANGE;
+    colormap_fraction((value - min) / (max - min))
+}
+
+/// Like [colormap], but for a value between 0 and 1.
+pub fn colormap_fraction(fraction: f64) -> Color {
+    let t = fraction.clamp(0.0, 1.0) as f32;
+    let position = t * (COLORMAP_STOPS.len() - 1) as f32;
+    let index = (position.floor() as usize).min(COLORMAP_STOPS.len() - 2);
+    let fraction = position - index as f32;
+    let (from, to) = (COLORMAP_STOPS[index], COLORMAP_STOPS[index + 1]);
+    Color::new(
+        from.r + (to.r - from.r) * fraction,
+        from.g + (to.g - from.g) * fraction,
+        from.b + (to.b - from.b) * fraction,
+        1.0,
+    )
+}
+
+const LIGHT_RED: Color = Color::new(1.0, 0.333_333_34, 0.333_333_34, 1.0);
+const RED: Color = Color::new(0.666_666_7, 0.0, 0.0, 1.0);
+const LIGHT_BLUE: Color = Color::new(0.333_333_34, 0.333_333_34, 1.0, 1.0);
+const BLUE: Color = Color::new(0.0, 0.0, 0.666_666_7, 1.0);
+const LIGHT_GREEN: Color = Color::new(0.333_333_34, 0.866_666_7, 0.333_333_34, 1.0);
+const GREEN: Color = Color::new(0.0, 0.533_333_36, 0.0, 1.0);
+const LIGHT_ORANGE: Color = Color::new(1.0, 0.666_666_7, 0.2, 1.0);
+const ORANGE: Color = Color::new(0.8, 0.4, 0.0, 1.0);
+const LIGHT_PURPLE: Color = Color::new(0.733_333_35, 0.4, 1.0, 1.0);
+const PURPLE: Color = Color::new(0.4, 0.0, 0.6, 1.0);
+const LIGHT_CYAN: Color = Color::new(0.266_666_68, 0.866_666_7, 0.866_666_7, 1.0);
+const CYAN: Color = Color::new(0.0, 0.466_666_67, 0.533_333_36, 1.0);
+const LIGHT_YELLOW: Color = Color::new(1.0, 0.933_333_34, 0.266_666_68, 1.0);
+const YELLOW: Color = Color::new(0.666_666_7, 0.6, 0.0, 1.0);
+const LIGHT_PINK: Color = Color::new(1.0, 0.466_666_67, 0.8, 1.0);
+const PINK: Color = Color::new(0.733_333_35, 0.133_333_34, 0.533_333_36, 1.0);
 
 impl Label2D {
+    /// Every label, in the order of their indices.
+    pub const ALL: [Label2D; MAX_CLASSES] = [
+        Label2D::Blue,
+        Label2D::Red,
+        Label2D::Green,
+        Label2D::Orange,
+        Label2D::Purple,
+        Label2D::Cyan,
+        Label2D::Yellow,
+        Label2D::Pink,
+    ];
+
+    /// The label's position in [Self::ALL], which is the class that it
+    /// stands for in multi-class classification.
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// Returns the label with the given [Self::index].
+    pub fn from_index(index: usize) -> Self {
+        Self::ALL[index]
+    }
+
+    /// The label's name, e.g. `red`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Label2D::Blue => "blue",
+            Label2D::Red => "red",
+            Label2D::Green => "green",
+            Label2D::Orange => "orange",
+            Label2D::Purple => "purple",
+            Label2D::Cyan => "cyan",
+            Label2D::Yellow => "yellow",
+            Label2D::Pink => "pink",
+        }
+    }
+
     fn as_f64(&self) -> f64 {
         (*self).into()
     }
@@ -58,6 +383,27 @@ impl Label2D {
         match self {
             Label2D::Blue => LIGHT_BLUE,
             Label2D::Red => LIGHT_RED,
+            Label2D::Green => LIGHT_GREEN,
+            Label2D::Orange => LIGHT_ORANGE,
+            Label2D::Purple => LIGHT_PURPLE,
+            Label2D::Cyan => LIGHT_CYAN,
+            Label2D::Yellow => LIGHT_YELLOW,
+            Label2D::Pink => LIGHT_PINK,
+        }
+    }
+
+    /// The darker color that the plot is shaded with where the label is
+    /// predicted.
+    fn dark(&self) -> Color {
+        match self {
+            Label2D::Blue => BLUE,
+            Label2D::Red => RED,
+            Label2D::Green => GREEN,
+            Label2D::Orange => ORANGE,
+            Label2D::Purple => PURPLE,
+            Label2D::Cyan => CYAN,
+            Label2D::Yellow => YELLOW,
+            Label2D::Pink => PINK,
         }
     }
 
@@ -67,49 +413,252 @@ impl Label2D {
     /// 0.5, the less intense the color will be.
     fn dark_color(value: f64, enable_shading: bool) -> Color {
         let label: Label2D = value.into();
-        match label {
-            Label2D::Blue => BLUE.with_alpha(if enable_shading {
-                1.0 - value as f32 * 2.0
-            } else {
-                1.0
-            }),
-            Label2D::Red => RED.with_alpha(if enable_shading {
-                (value as f32 - 0.5) * 2.0
-            } else {
-                1.0
-            }),
-        }
+        let confidence = (value as f32 - 0.5).abs() * 2.0;
+        label
+            .dark()
+            .with_alpha(if enable_shading { confidence } else { 1.0 })
     }
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct Datapoint2D {
     pub pos: (i32, i32),
-    pub label: Label2D,
+    pub target: Target2D,
+    /// Whether the point is held out of training, and only used to
+    /// measure how well the network does on points it hasn't seen.
+    pub validation: bool,
 }
 
 impl Datapoint2D {
-    pub fn new(pos: (i32, i32), label: Label2D) -> Self {
-        Datapoint2D { pos, label }
+    /// Creates a training datapoint with either a label or a continuous
+    /// value.
+    pub fn new<T: Into<Target2D>>(pos: (i32, i32), target: T) -> Self {
+        Datapoint2D {
+            pos,
+            target: target.into(),
+            validation: false,
+        }
+    }
+
+    /// Sets whether the point is a validation point.
+    pub fn with_validation(mut self, validation: bool) -> Self {
+        self.validation = validation;
+        self
+    }
+
+    /// The inputs to the neural net for this datapoint.
+    fn inputs(&self, features: &Features) -> Vec<f64> {
+        features.expand(
+            self.pos.0 as f64 / POINT_SCALE,
+            self.pos.1 as f64 / POINT_SCALE,
+        )
     }
 }
 
-#[derive(Clone, Debug)]
+/// Which values of the hidden neurons [Classifier2D::draw_neuron_heatmaps]
+/// shows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeuronValues {
+    PreActivations,
+    Activations,
+}
+
+#[derive(Debug)]
 pub struct Weights2D(MultiLayerPerceptron<Value>);
 
 impl Weights2D {
-    pub fn new(mut hidden_layers: Vec<usize>) -> Self {
-        hidden_layers.push(1);
-        Self(MultiLayerPerceptron::new(
-            2,
+    /// Creates random weights with the given number of inputs, which
+    /// should match the [Features] they'll be given, and hidden layers.
+    /// If `output_skips` is true, the output neuron is connected to
+    /// every hidden dense and residual layer.
+    pub fn new(num_inputs: usize, hidden_layers: Vec<LayerSpec>, output_skips: bool) -> Self {
+        Self::from_specs(
+            num_inputs,
             ActivationType::Sigmoid,
             hidden_layers,
-        ))
+            output_skips,
+        )
+    }
+
+    /// Like [Self::new], but the hidden layers use the given activation
+    /// function. The output still uses a sigmoid.
+    pub fn from_specs(
+        num_inputs: usize,
+        activation: ActivationType,
+        mut hidden_layers: Vec<LayerSpec>,
+        output_skips: bool,
+    ) -> Self {
+        hidden_layers.push(LayerSpec::Dense(1));
+        let weights = Self(if output_skips {
+            MultiLayerPerceptron::from_specs_with_output_skips(
+                num_inputs,
+                activation,
+                hidden_layers,
+            )
+        } else {
+            MultiLayerPerceptron::from_specs(num_inputs, activation, hidden_layers)
+        });
+        if activation == ActivationType::Sigmoid {
+            weights
+        } else {
+            weights.with_output_activation(ActivationType::Sigmoid)
+        }
+    }
+
+    pub fn num_inputs(&self) -> usize {
+        self.0.num_inputs()
+    }
+
+    /// Replaces the output layer with one that uses the given activation
+    /// function.
+    pub fn with_output_activation(self, activation: ActivationType) -> Self {
+        Self(self.0.with_output_activation(activation))
+    }
+
+    /// Replaces the output layer with one that suits the task, i.e.
+    /// with [Task::num_outputs] outputs that use its
+    /// [Task::output_activation].
+    pub fn for_task(self, task: Task) -> Self {
+        Self(
+            self.0
+                .with_output_layer(task.num_outputs(), task.output_activation()),
+        )
+    }
+
+    pub fn num_outputs(&self) -> usize {
+        self.0.num_outputs()
     }
 
     pub fn num_params(&self) -> usize {
         self.0.params().len()
     }
+
+    /// Returns the indices of the layers that start each block of the
+    /// network, i.e. its dense layers and residual blocks. Each block
+    /// also includes any other layers that follow it, like
+    /// normalization layers.
+    fn block_starts(&self) -> Vec<usize> {
+        self.0
+            .specs()
+            .iter()
+            .enumerate()
+            .filter(|(_, spec)| matches!(spec, Some(LayerSpec::Dense(_) | LayerSpec::Residual(_))))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Returns the number of blocks in the network, including the
+    /// output layer.
+    pub fn num_blocks(&self) -> usize {
+        self.block_starts().len()
+    }
+
+    /// Returns how many blocks at the start of the network are frozen,
+    /// as set by [Self::freeze_first_blocks].
+    pub fn num_frozen_blocks(&self) -> usize {
+        self.block_starts()
+            .into_iter()
+            .take_while(|&start| self.0.is_frozen(start))
+            .count()
+    }
+
+    /// Freezes the first `count` blocks of the network, so that
+    /// training doesn't change them, and unfreezes the rest.
+    pub fn freeze_first_blocks(&mut self, count: usize) {
+        let first_unfrozen_layer = self
+            .block_starts()
+            .get(count)
+            .copied()
+            .unwrap_or(self.0.num_layers());
+        for index in 0..self.0.num_layers() {
+            self.0.set_frozen(index, index < first_unfrozen_layer);
+        }
+    }
+
+    /// Returns a short description of the architecture, e.g. `2-16-16-1`.
+    /// Each residual block counts as a layer with as many outputs as
+    /// inputs, and frozen layers are shown in brackets, e.g. `2-[16]-16-1`.
+    pub fn notation(&self) -> String {
+        let specs = self.0.specs();
+        let mut layers: Vec<(usize, bool)> = vec![];
+        let mut has_residual = false;
+        for (index, spec) in specs.iter().enumerate() {
+            let num_outputs = match spec {
+                Some(LayerSpec::Dense(num_outputs)) => *num_outputs,
+                Some(LayerSpec::Residual(_)) => {
+                    has_residual = true;
+                    layers
+                        .last()
+                        .map_or(self.0.num_inputs(), |&(num_outputs, _)| num_outputs)
+                }
+                _ => continue,
+            };
+            layers.push((num_outputs, self.0.is_frozen(index)));
+        }
+        let describe = |&(num_outputs, frozen): &(usize, bool)| {
+            if frozen {
+                format!("[{num_outputs}]")
+            } else {
+                num_outputs.to_string()
+            }
+        };
+        let output = layers
+            .pop()
+            .map_or("2".to_owned(), |layer| describe(&layer));
+        let hidden = layers;
+        let mut parts = vec![self.0.num_inputs().to_string()];
+        if hidden.len() > 2 && hidden.iter().all(|&layer| layer == (hidden[0].0, false)) {
+            parts.push(format!("{}x{}", hidden[0].0, hidden.len()));
+        } else {
+            parts.extend(hidden.iter().map(describe));
+        }
+        parts.push(output);
+        let normalization = if specs.contains(&Some(LayerSpec::BatchNorm)) {
+            " BN"
+        } else if specs.contains(&Some(LayerSpec::LayerNorm)) {
+            " LN"
+        } else {
+            ""
+        };
+        let residual = if has_residual { " Res" } else { "" };
+        let skips = if self.0.output_skips() { " Skip" } else { "" };
+        format!("{}{normalization}{residual}{skips}", parts.join("-"))
+    }
+
+    /// Returns a description of every layer and its parameters.
+    pub fn summary(&self) -> ModelSummary {
+        self.0.summary()
+    }
+
+    pub fn save<P: AsRef<Path>>(
+        &self,
+        path: P,
+        format: ModelFormat,
+        metadata: ModelMetadata,
+    ) -> Result<(), ModelFileError> {
+        self.0.to_model_file(metadata)?.save(path, format)
+    }
+
+    /// Loads weights saved in either format. The network must have
+    /// `num_inputs` inputs and `num_outputs` outputs.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        num_inputs: usize,
+        num_outputs: usize,
+    ) -> Result<(Self, ModelMetadata), ModelFileError> {
+        let file = ModelFile::load(path)?;
+        let mlp = MultiLayerPerceptron::<Value>::from_model_file(&file)?;
+        if mlp.num_inputs() != num_inputs || mlp.num_outputs() != num_outputs {
+            return Err(ModelFileError::ShapeMismatch(format!(
+                "expected a network with {} inputs and {} outputs, but it has {} inputs and {} outputs",
+                num_inputs,
+                num_outputs,
+                mlp.num_inputs(),
+                mlp.num_outputs()
+            )));
+        }
+        Ok((Self(mlp), file.metadata))
+    }
 }
 
 impl Display for Weights2D {
@@ -127,8 +676,16 @@ impl Display for Weights2D {
 pub struct Classifier2D {
     datapoints: Vec<Datapoint2D>,
     weights: Weights2D,
+    features: Features,
+    task: Task,
+    regularization: Option<Regularization>,
+    /// The mean squared error, or the mean cross-entropy for
+    /// multi-class classification.
     loss: f64,
+    regularization_loss: f64,
+    /// Only calculated for classification.
     accuracy: f64,
+    mean_absolute_error: f64,
     num_params: usize,
 }
 
@@ -138,84 +695,594 @@ impl Classifier2D {
         let mut classifier = Classifier2D {
             datapoints,
             weights,
+            features: Features::default(),
+            task: Task::Classification,
+            regularization: None,
             loss: 0.0,
+            regularization_loss: 0.0,
             accuracy: 0.0,
+            mean_absolute_error: 0.0,
             num_params,
         };
-        classifier.calculate_loss_and_accuracy(false);
+        // Networks with other numbers of inputs can't be evaluated
+        // until they're given their features.
+        if classifier.weights.num_inputs() == classifier.features.len() {
+            classifier.evaluate();
+        }
         classifier
     }
 
+    pub fn with_regularization(mut self, regularization: Option<Regularization>) -> Self {
+        self.set_regularization(regularization);
+        self
+    }
+
+    /// Sets the task. The weights should have been given the task's
+    /// output layer with [Weights2D::for_task].
+    pub fn with_task(mut self, task: Task) -> Self {
+        self.task = task;
+        self.evaluate();
+        self
+    }
+
+    pub fn task(&self) -> Task {
+        self.task
+    }
+
+    /// Sets the features that the datapoints are given to the network
+    /// as. There must be as many as the weights have inputs.
+    pub fn with_features(mut self, features: Features) -> Self {
+        assert_eq!(
+            features.len(),
+            self.weights.num_inputs(),
+            "the network needs as many inputs as there are features"
+        );
+        self.features = features;
+        self.evaluate();
+        self
+    }
+
+    pub fn features(&self) -> &Features {
+        &self.features
+    }
+
     pub fn num_params(&self) -> usize {
         self.num_params
     }
 
-    pub fn update(&mut self, learning_rate: f64) {
-        self.calculate_loss_and_accuracy(true);
-        self.learn(learning_rate);
-        //println!("new weights: {}", self.weights);
+    pub fn num_datapoints(&self) -> usize {
+        self.datapoints.len()
+    }
+
+    /// The number of datapoints that aren't validation points. Batches
+    /// are indices into these.
+    pub fn num_training_points(&self) -> usize {
+        self.datapoints
+            .iter()
+            .filter(|point| !point.validation)
+            .count()
+    }
+
+    pub fn datapoints(&self) -> &[Datapoint2D] {
+        &self.datapoints
+    }
+
+    /// Returns the outputs of the network for each datapoint.
+    pub fn outputs(&self) -> Vec<Vec<f64>> {
+        let mlp = self.weights.0.read_only();
+        let features = &self.features;
+        self.datapoints
+            .par_iter()
+            .map(|point| mlp.output(&point.inputs(features)))
+            .collect()
+    }
+
+    /// Calculates the gradient of the loss, including regularization,
+    /// with respect to all of the parameters, in the same order as
+    /// [Self::param_values]. The loss and accuracy are updated too. Only
+    /// the training points are used, and if `batch` is given, only the
+    /// training points with those indices.
+    pub fn gradients(&mut self, batch: Option<&[usize]>) -> Vec<f64> {
+        if let Some(grads) = self.parallel_gradients(batch) {
+            return grads;
+        }
+        self.calculate_loss_and_accuracy(batch, true, true);
+        self.weights.0.params().iter().map(|p| p.grad()).collect()
+    }
+
+    /// Like [Self::gradients], but the network is run in inference mode,
+    /// like it is by [Self::forward_loss] and [Self::loss_at]. This is
+    /// the gradient of the loss that they calculate, even with dropout
+    /// or batch normalization, and finding it doesn't change the
+    /// network's batch normalization statistics.
+    pub fn inference_gradients(&mut self, batch: Option<&[usize]>) -> Vec<f64> {
+        if self.training_matches_inference() {
+            return self.gradients(batch);
+        }
+        self.calculate_loss_and_accuracy(batch, true, false);
+        self.weights.0.params().iter().map(|p| p.grad()).collect()
+    }
+
+    /// Calculates the loss, including regularization, using only forward
+    /// passes of the network. The loss and accuracy are updated like
+    /// they are by [Self::gradients], using the same datapoints.
+    pub fn forward_loss(&mut self, batch: Option<&[usize]>) -> f64 {
+        let totals = self.forward_totals(batch);
+        let num_points = self.batch_points(batch).len() as f64;
+        self.loss = totals.loss / num_points;
+        self.accuracy = totals.correctly_classified as f64 / num_points;
+        self.mean_absolute_error = totals.absolute_error / num_points;
+        self.regularization_loss = self.current_regularization_loss();
+        self.loss + self.regularization_loss
+    }
+
+    /// Returns the loss, including regularization, that the network
+    /// would have with the given parameter values, without changing
+    /// anything.
+    pub fn loss_at(&mut self, values: &[f64], batch: Option<&[usize]>) -> f64 {
+        let current = self.param_values();
+        self.set_param_values(values);
+        let totals = self.forward_totals(batch);
+        let loss = totals.loss / self.batch_points(batch).len() as f64
+            + self.current_regularization_loss();
+        self.set_param_values(&current);
+        loss
+    }
+
+    /// Returns the gradient that [Self::inference_gradients] would return
+    /// if the parameters had the given values, without changing anything.
+    pub fn gradients_at(&mut self, values: &[f64], batch: Option<&[usize]>) -> Vec<f64> {
+        let current = self.param_values();
+        let metrics = (
+            self.loss,
+            self.regularization_loss,
+            self.accuracy,
+            self.mean_absolute_error,
+        );
+        self.set_param_values(values);
+        let grads = self.inference_gradients(batch);
+        self.set_param_values(&current);
+        (
+            self.loss,
+            self.regularization_loss,
+            self.accuracy,
+            self.mean_absolute_error,
+        ) = metrics;
+        grads
+    }
+
+    /// Recalculates the loss and accuracy on all the training points.
+    pub fn evaluate(&mut self) {
+        self.calculate_loss_and_accuracy(None, false, false);
+    }
+
+    /// Returns the values of all of the parameters.
+    pub fn param_values(&self) -> Vec<f64> {
+        self.weights.0.params().iter().map(|p| p.as_f64()).collect()
+    }
+
+    /// Returns whether each parameter, in the same order as
+    /// [Self::param_values], is changed by [Self::set_param_values].
+    pub fn trainable_params(&self) -> Vec<bool> {
+        self.weights.0.trainable_params()
+    }
+
+    /// Sets the values of all of the parameters, except for the ones in
+    /// frozen layers, which are left alone.
+    pub fn set_param_values(&mut self, values: &[f64]) {
+        let params = self.weights.0.params();
+        let trainable = self.weights.0.trainable_params();
+        for ((mut param, &value), trainable) in params.into_iter().zip(values).zip(trainable) {
+            if !trainable {
+                continue;
+            }
+            // Reset any infinite weights.
+            param.set(if value.is_finite() { value } else { rand_f64() });
+        }
     }
 
     /// Return the weights.
-    pub fn weights(&self) -> Weights2D {
-        self.weights.clone()
+    pub fn weights(&self) -> &Weights2D {
+        &self.weights
     }
 
+    /// Replaces the datapoints, keeping the current weights.
+    pub fn set_datapoints(&mut self, datapoints: Vec<Datapoint2D>) {
+        self.datapoints = datapoints;
+        self.evaluate();
+    }
+
+    /// The loss on the training points, not including any
+    /// regularization.
     pub fn loss(&self) -> f64 {
         self.loss
     }
 
+    /// Returns the loss, not including regularization, and the accuracy
+    /// on the validation points, or `None` if there aren't any. These
+    /// are calculated when asked for, since training never needs them.
+    pub fn validation_loss_and_accuracy(&self) -> Option<(f64, f64)> {
+        let points: Vec<&Datapoint2D> = self
+            .datapoints
+            .iter()
+            .filter(|point| point.validation)
+            .collect();
+        if points.is_empty() {
+            return None;
+        }
+        let mlp = self.weights.0.read_only();
+        let totals = self.error_totals(&points, |inputs| mlp.output(inputs));
+        let num_points = points.len() as f64;
+        Some((
+            totals.loss / num_points,
+            totals.correctly_classified as f64 / num_points,
+        ))
+    }
+
+    pub fn dropout(&self) -> f64 {
+        self.weights.0.dropout()
+    }
+
+    /// Sets the dropout probability of the hidden layers during training.
+    pub fn set_dropout(&mut self, dropout: f64) {
+        self.weights.0.set_dropout(dropout);
+    }
+
+    pub fn regularization(&self) -> Option<Regularization> {
+        self.regularization
+    }
+
+    pub fn set_regularization(&mut self, regularization: Option<Regularization>) {
+        self.regularization = regularization;
+        self.evaluate();
+    }
+
+    /// The part of the loss that comes from regularization.
+    pub fn regularization_loss(&self) -> f64 {
+        self.regularization_loss
+    }
+
+    /// The fraction of correctly classified training points. This is
+    /// always zero for regression.
     pub fn accuracy(&self) -> f64 {
         self.accuracy
     }
 
+    pub fn mean_absolute_error(&self) -> f64 {
+        self.mean_absolute_error
+    }
+
     pub fn draw(&self, plot: &Plot, enable_shading: bool) {
+        let colors: Vec<Color> = self
+            .mesh_outputs()
+            .iter()
+            .map(|outputs| self.task.color(outputs, enable_shading))
+            .collect();
+        draw_mesh(plot, &colors);
+        self.draw_datapoints(plot);
+    }
+
+    /// Returns the loss and accuracy that a model other
+    /// than this classifier's network has on the training points. The
+    /// model is given the same inputs as the network, and has a single
+    /// output, so it can't be scored on several classes and this returns
+    /// `None` for [Task::MultiClass].
+    pub fn score<F: Fn(&[f64]) -> f64 + Sync>(&self, output: F) -> Option<(f64, f64)> {
+        if let Task::MultiClass { .. } = self.task {
+            return None;
+        }
+        let points = self.batch_points(None);
+        let totals = self.error_totals(&points, |inputs| vec![output(inputs)]);
+        let num_points = points.len() as f64;
+        Some((
+            totals.loss / num_points,
+            totals.correctly_classified as f64 / num_points,
+        ))
+    }
+
+    /// Like [Self::draw], but shades the plot with the outputs of a model
+    /// other than this classifier's network, which has a single output
+    /// like for [Self::score]. Returns whether it was drawn, which it
+    /// isn't for [Task::MultiClass].
+    pub fn draw_model<F: Fn(&[f64]) -> f64 + Sync>(
+        &self,
+        plot: &Plot,
+        enable_shading: bool,
+        output: F,
+    ) -> bool {
+        if let Task::MultiClass { .. } = self.task {
+            return false;
+        }
+        let features = &self.features;
+        let task = self.task;
+        let colors: Vec<Color> = MESH_RANGE
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                let output = &output;
+                MESH_RANGE.map(move |x| {
+                    let inputs = features.expand(x as f64 / POINT_SCALE, y as f64 / POINT_SCALE);
+                    output(&inputs)
+                })
+            })
+            .map(|output| task.color(&[output], enable_shading))
+            .collect();
+        draw_mesh(plot, &colors);
+        self.draw_datapoints(plot);
+        true
+    }
+
+    /// Returns the outputs of the network at every point of the mesh
+    /// that the plot is shaded with, row by row from the bottom.
+    pub fn mesh_outputs(&self) -> Vec<Vec<f64>> {
         let mlp = self.weights.0.read_only();
+        let features = &self.features;
+        let num_outputs = self.weights.num_outputs();
+
+        // Each row of the mesh is evaluated as a single batch.
+        MESH_RANGE
+            .into_par_iter()
+            .map_init(
+                || (vec![], BatchBuffers::default()),
+                |(inputs, buffers), y| {
+                    inputs.clear();
+                    for x in MESH_RANGE {
+                        features.expand_into(
+                            x as f64 / POINT_SCALE,
+                            y as f64 / POINT_SCALE,
+                            inputs,
+                        );
+                    }
+                    mlp.batch_output(inputs, buffers)
+                        .chunks(num_outputs)
+                        .map(<[f64]>::to_vec)
+                        .collect::<Vec<_>>()
+                },
+            )
+            .flatten_iter()
+            .collect()
+    }
 
-        let all_points = (-50..50)
-            .flat_map(|y| (-50..50).map(move |x| (x, y)))
-            .collect::<Vec<_>>();
+    /// Draws the datapoints, with validation points as rings.
+    pub fn draw_datapoints(&self, plot: &Plot) {
+        for point in self.datapoints.iter() {
+            let (x, y) = (point.pos.0 as f32, point.pos.1 as f32);
+            if self.task == Task::Regression {
+                // Outline the points so they stand out from the
+                // background, which uses the same colors.
+                plot.draw_circle(x, y, 0.65, BLACK);
+            }
+            plot.draw_circle(x, y, 0.5, point.target.color());
+            if point.validation {
+                plot.draw_circle(x, y, 0.25, BLACK);
+            }
+        }
+    }
+
+    /// Draws a small heatmap for every hidden neuron, showing its value
+    /// over the same area as the plot. Each layer gets its own row,
+    /// starting at the given screen position, and each heatmap is
+    /// `size` pixels wide. The colors of each heatmap are scaled to
+    /// its own range of values.
+    pub fn draw_neuron_heatmaps(&self, x: f32, y: f32, size: f32, values: NeuronValues) {
+        let mlp = self.weights.0.read_only();
+        let mlp = &mlp;
+        let features = &self.features;
+        let step = 100 / HEATMAP_RESOLUTION;
 
-        let points = all_points
+        // The values of every hidden layer at the center of each cell,
+        // row by row from the top.
+        let cells: Vec<Vec<Vec<f64>>> = (0..HEATMAP_RESOLUTION)
             .into_par_iter()
-            .map(|(x, y)| {
-                let inputs = vec![x as f64 / POINT_SCALE, y as f64 / POINT_SCALE];
-                let output = *mlp.output(&inputs).first().unwrap();
-                let color = Label2D::dark_color(output, enable_shading);
-                (x, y, color)
+            .flat_map_iter(|row| {
+                let plot_y = 50 - step / 2 - row * step;
+                (0..HEATMAP_RESOLUTION).map(move |column| {
+                    let plot_x = -50 + step / 2 + column * step;
+                    let inputs =
+                        features.expand(plot_x as f64 / POINT_SCALE, plot_y as f64 / POINT_SCALE);
+                    mlp.neuron_activations(&inputs)
+                        .into_iter()
+                        .map(|layer| match values {
+                            NeuronValues::PreActivations => layer.pre_activations,
+                            NeuronValues::Activations => layer.activations,
+                        })
+                        .collect()
+                })
             })
-            .collect::<Vec<_>>();
+            .collect();
+        let Some(first_cell) = cells.first() else {
+            return;
+        };
 
-        for (x, y, color) in points {
-            plot.draw_point(x as f32, y as f32, color);
+        let gap = size / 8.0;
+        let cell_size = size / HEATMAP_RESOLUTION as f32;
+        for (layer, neurons) in first_cell.iter().enumerate() {
+            let top = y + layer as f32 * (size + gap);
+            for neuron in 0..neurons.len() {
+                let left = x + neuron as f32 * (size + gap);
+                let neuron_values = || cells.iter().map(|cell| cell[layer][neuron]);
+                let min = neuron_values().fold(f64::INFINITY, f64::min);
+                let max = neuron_values().fold(f64::NEG_INFINITY, f64::max);
+                for (index, value) in neuron_values().enumerate() {
+                    let row = index as i32 / HEATMAP_RESOLUTION;
+                    let column = index as i32 % HEATMAP_RESOLUTION;
+                    let fraction = if max - min > 1e-9 {
+                        (value - min) / (max - min)
+                    } else {
+                        0.5
+                    };
+                    draw_rectangle(
+                        left + column as f32 * cell_size,
+                        top + row as f32 * cell_size,
+                        cell_size,
+                        cell_size,
+                        colormap_fraction(fraction),
+                    );
+                }
+            }
         }
+    }
+
+    /// Returns the gradient of the network's output with respect to the
+    /// (normalized) coordinates of the given point, found by making the
+    /// coordinates leaves of the expression graph. For multi-class
+    /// classification, the log-probability of the predicted class is
+    /// used as the output.
+    pub fn input_gradient(&self, x: f64, y: f64) -> (f64, f64) {
+        let x = Value::from(x / POINT_SCALE);
+        let y = Value::from(y / POINT_SCALE);
+        let inputs = self.features.expand_values(&x, &y);
+        let mut outputs = self.weights.0.output(&inputs);
+        let mut output = match self.task {
+            Task::MultiClass { .. } => {
+                let values: Vec<f64> = outputs.iter().map(Value::as_f64).collect();
+                log_softmax(&outputs, argmax(&values))
+            }
+            Task::Classification | Task::Regression => outputs.pop().unwrap(),
+        };
+        // This also leaves gradients in the parameters, but they're
+        // always zeroed before they're used for training.
+        output.backward();
+        (x.grad(), y.grad())
+    }
+
+    /// Returns [Self::input_gradient] at every point of the mesh that the
+    /// plot is shaded with, row by row from the bottom.
+    pub fn mesh_input_gradients(&self) -> Vec<(f64, f64)> {
+        let mlp = self.weights.0.read_only();
+        let features = &self.features;
+        let task = self.task;
+        let points: Vec<(f64, f64)> = MESH_RANGE
+            .flat_map(|y| MESH_RANGE.map(move |x| (x as f64 / POINT_SCALE, y as f64 / POINT_SCALE)))
+            .collect();
+        // Backprop without an expression graph is much faster, but not
+        // every layer supports it.
+        let gradients: Option<Vec<(f64, f64)>> = points
+            .par_iter()
+            .map_init(
+                || vec![0.0; mlp.num_params()],
+                |param_grads, &(x, y)| {
+                    let pass = mlp.forward(&features.expand(x, y));
+                    let output_grads = task.prediction_gradient(pass.output());
+                    let feature_grads = mlp.backward(&pass, &output_grads, param_grads)?;
+                    Some(features.coordinate_gradient(x, y, &feature_grads))
+                },
+            )
+            .collect();
+        gradients.unwrap_or_else(|| {
+            points
+                .into_iter()
+                .map(|(x, y)| self.input_gradient(x * POINT_SCALE, y * POINT_SCALE))
+                .collect()
+        })
+    }
+
+    /// Shades the plot by the magnitude of the input gradient, scaled so
+    /// the largest one is at the top of the colormap. This shows where
+    /// the output is most sensitive to moving the point.
+    pub fn draw_input_gradient_heatmap(&self, plot: &Plot) {
+        let magnitudes: Vec<f64> = self
+            .mesh_input_gradients()
+            .into_iter()
+            .map(|(x_grad, y_grad)| x_grad.hypot(y_grad))
+            .collect();
+        let max = magnitudes.iter().copied().fold(0.0, f64::max);
+        let colors: Vec<Color> = magnitudes
+            .into_iter()
+            .map(|magnitude| colormap_fraction(if max > 0.0 { magnitude / max } else { 0.0 }))
+            .collect();
+        draw_mesh(plot, &colors);
+        self.draw_datapoints(plot);
+    }
+
+    /// Draws an arrow from the given point in the direction that
+    /// increases the output fastest, whose length shows how fast.
+    pub fn draw_input_gradient(&self, plot: &Plot, x: f32, y: f32) {
+        let (x_grad, y_grad) = self.input_gradient(x as f64, y as f64);
+        let gradient = vec2(x_grad as f32, y_grad as f32);
+        let length =
+            (gradient.length() * INPUT_GRADIENT_ARROW_SCALE).min(MAX_INPUT_GRADIENT_ARROW_LENGTH);
+        let end = vec2(x, y) + gradient.normalize_or_zero() * length;
+        plot.draw_arrow(x, y, end.x, end.y, 1.5, WHITE);
+    }
+
+    /// Generates a standalone Rust module that computes the network's
+    /// output, whose test checks that it gives exactly the same output
+    /// for every datapoint. See [to_rust_source].
+    pub fn to_rust_source(&self) -> Result<String, ModelFileError> {
+        let description = format!(
+            "A {} {} network for points in 2D space.\n\n\
+             Its inputs are the features `{}` of a point, whose coordinates\n\
+             are first divided by {POINT_SCALE:?}.",
+            self.weights.notation(),
+            self.task.name(),
+            self.features.description()
+        );
+        let test_inputs: Vec<Vec<f64>> = self
+            .datapoints
+            .iter()
+            .map(|point| point.inputs(&self.features))
+            .collect();
+        to_rust_source(&self.weights.0.read_only(), &description, &test_inputs)
+    }
 
-        // Draw datapoints.
-        for (_index, point) in self.datapoints.iter().enumerate() {
-            plot.draw_circle(
-                point.pos.0 as f32,
-                point.pos.1 as f32,
-                0.5,
-                point.label.color(),
-            );
+    /// Returns the training points with the given indices, or all of
+    /// them.
+    fn batch_points(&self, batch: Option<&[usize]>) -> Vec<&Datapoint2D> {
+        let training_points = self.datapoints.iter().filter(|point| !point.validation);
+        match batch {
+            Some(indices) => {
+                let training_points: Vec<&Datapoint2D> = training_points.collect();
+                indices.iter().map(|&i| training_points[i]).collect()
+            }
+            None => training_points.collect(),
         }
     }
 
-    fn calculate_loss_and_accuracy(&mut self, calc_grad: bool) {
+    /// Calculates the loss and accuracy with an expression graph, running
+    /// the network in training mode if `training` is true.
+    fn calculate_loss_and_accuracy(
+        &mut self,
+        batch: Option<&[usize]>,
+        calc_grad: bool,
+        training: bool,
+    ) {
+        let points: Vec<Datapoint2D> = self.batch_points(batch).into_iter().copied().collect();
+        let batch: Vec<Vec<Value>> = points
+            .iter()
+            .map(|point| {
+                point
+                    .inputs(&self.features)
+                    .into_iter()
+                    .map(Value::from)
+                    .collect()
+            })
+            .collect();
+        let outputs = if training {
+            self.weights.0.training_output(&batch)
+        } else {
+            batch
+                .iter()
+                .map(|inputs| self.weights.0.output(inputs))
+                .collect()
+        };
         let mut loss = Value::from(0.0);
         let mut correctly_classified = 0;
-        for point in &self.datapoints {
-            let inputs = vec![
-                Value::from(point.pos.0 as f64 / POINT_SCALE),
-                Value::from(point.pos.1 as f64 / POINT_SCALE),
-            ];
-            let output = self.weights.0.output(&inputs).pop().unwrap();
-            let y = Value::from(point.label.as_f64());
-            let predicted_label: Label2D = output.as_f64().into();
-            if predicted_label == point.label {
+        let mut absolute_error = 0.0;
+        for (point, outputs) in points.iter().zip(outputs) {
+            let values: Vec<f64> = outputs.iter().map(Value::as_f64).collect();
+            let y = point.target.as_f64();
+            if self.task.is_correct(&values, y) {
                 correctly_classified += 1;
             }
-            let single_loss = (y - output.clone()).pow(2.0);
+            absolute_error += self.task.absolute_error(&values, y);
+            let single_loss = match self.task {
+                Task::MultiClass { num_classes } => {
+                    Value::from(0.0) - log_softmax(&outputs, class(y, num_classes))
+                }
+                Task::Classification | Task::Regression => {
+                    (Value::from(y) - outputs[0].clone()).pow(2.0)
+                }
+            };
             // println!(
             //     "{point:?}, sigmoid={:0.2} loss={:0.2}",
             //     sigmoid.as_f64(),
@@ -223,9 +1290,18 @@ impl Classifier2D {
             // );
             loss = loss + single_loss;
         }
-        loss = loss / Value::from(self.datapoints.len() as f64);
+        loss = loss / Value::from(points.len() as f64);
         self.loss = loss.as_f64();
-        self.accuracy = correctly_classified as f64 / self.datapoints.len() as f64;
+        self.accuracy = correctly_classified as f64 / points.len() as f64;
+        self.mean_absolute_error = absolute_error / points.len() as f64;
+
+        if let Some(regularization) = &self.regularization {
+            let regularization_loss = regularization.loss(&self.weights.0);
+            self.regularization_loss = regularization_loss.as_f64();
+            loss = loss + regularization_loss;
+        } else {
+            self.regularization_loss = 0.0;
+        }
 
         if calc_grad {
             for param in self.weights.0.params().iter_mut() {
@@ -235,14 +1311,462 @@ impl Classifier2D {
         }
     }
 
-    fn learn(&self, learning_rate: f64) {
-        for mut value in self.weights.0.params() {
-            let mut new_f64 = value.as_f64() + -1.0 * learning_rate * value.grad();
-            // Reset any infinite weights.
-            if !new_f64.is_finite() {
-                new_f64 = rand_f64();
+    /// Calculates the loss, accuracy and gradient of the network by
+    /// splitting the datapoints between threads, which is much faster
+    /// than building an expression graph for all of them. This isn't
+    /// possible for the reasons given by [Self::serial_gradients_reason],
+    /// or with layers that don't support analytic backprop, in which
+    /// case `None` is returned.
+    fn parallel_gradients(&mut self, batch: Option<&[usize]>) -> Option<Vec<f64>> {
+        if self.serial_gradients_reason().is_some() {
+            return None;
+        }
+        let mlp = self.weights.0.read_only();
+        let num_params = mlp.num_params();
+        let points = self.batch_points(batch);
+        let num_points = points.len() as f64;
+        let task = self.task;
+        let features = &self.features;
+        let (mut grads, totals) = points
+            .par_chunks(POINTS_PER_CHUNK)
+            .map(|points| {
+                let mut grads = vec![0.0; num_params];
+                let mut totals = ErrorTotals::default();
+                for point in points {
+                    let pass = mlp.forward(&point.inputs(features));
+                    let y = point.target.as_f64();
+                    totals.record(task, pass.output(), y);
+                    let output_grads: Vec<f64> = task
+                        .loss_gradient(pass.output(), y)
+                        .into_iter()
+                        .map(|grad| grad / num_points)
+                        .collect();
+                    mlp.backward(&pass, &output_grads, &mut grads)?;
+                }
+                Some((grads, totals))
+            })
+            .try_reduce(
+                || (vec![0.0; num_params], ErrorTotals::default()),
+                |(mut grads, totals), (chunk_grads, chunk_totals)| {
+                    for (grad, chunk_grad) in grads.iter_mut().zip(chunk_grads) {
+                        *grad += chunk_grad;
+                    }
+                    Some((grads, totals.add(chunk_totals)))
+                },
+            )?;
+        self.loss = totals.loss / num_points;
+        self.accuracy = totals.correctly_classified as f64 / num_points;
+        self.mean_absolute_error = totals.absolute_error / num_points;
+
+        // The regularization term only involves the parameters, so its
+        // expression graph is small.
+        self.regularization_loss = 0.0;
+        if let Some(regularization) = &self.regularization {
+            let mut regularization_loss = regularization.loss(&self.weights.0);
+            self.regularization_loss = regularization_loss.as_f64();
+            let mut params = self.weights.0.params();
+            for param in params.iter_mut() {
+                param.zero_grad();
+            }
+            regularization_loss.backward();
+            for (grad, param) in grads.iter_mut().zip(params) {
+                *grad += param.grad();
             }
-            value.set(new_f64);
         }
+        Some(grads)
+    }
+
+    /// Sums the errors of the network on the training points, splitting
+    /// them between threads.
+    fn forward_totals(&self, batch: Option<&[usize]>) -> ErrorTotals {
+        let mlp = self.weights.0.read_only();
+        self.error_totals(&self.batch_points(batch), |inputs| mlp.output(inputs))
+    }
+
+    /// Sums the errors of any function of a datapoint's inputs on the
+    /// given datapoints, splitting them between threads.
+    fn error_totals<F: Fn(&[f64]) -> Vec<f64> + Sync>(
+        &self,
+        points: &[&Datapoint2D],
+        output: F,
+    ) -> ErrorTotals {
+        let task = self.task;
+        let features = &self.features;
+        points
+            .par_chunks(POINTS_PER_CHUNK)
+            .map(|points| {
+                let mut totals = ErrorTotals::default();
+                for point in points {
+                    let outputs = output(&point.inputs(features));
+                    totals.record(task, &outputs, point.target.as_f64());
+                }
+                totals
+            })
+            .reduce(ErrorTotals::default, ErrorTotals::add)
+    }
+
+    /// Returns why [Self::gradients] has to build an expression graph for
+    /// all the points, on a single thread, rather than splitting them
+    /// between threads, or `None` if it doesn't. During training, batch
+    /// normalization uses the statistics of the whole batch, which ties
+    /// the gradients of all the points together, and dropout needs a
+    /// fresh mask for every point, which only the expression graph
+    /// supports.
+    pub fn serial_gradients_reason(&self) -> Option<&'static str> {
+        if self.weights.0.specs().contains(&Some(LayerSpec::BatchNorm)) {
+            Some("batch norm")
+        } else if self.dropout() > 0.0 {
+            Some("dropout")
+        } else {
+            None
+        }
+    }
+
+    /// Whether the network gives the same outputs in training mode as in
+    /// inference mode, i.e. it has neither dropout nor batch
+    /// normalization.
+    pub fn training_matches_inference(&self) -> bool {
+        self.dropout() == 0.0 && !self.weights.0.specs().contains(&Some(LayerSpec::BatchNorm))
+    }
+
+    fn current_regularization_loss(&self) -> f64 {
+        self.regularization.as_ref().map_or(0.0, |regularization| {
+            regularization.loss(&self.weights.0).as_f64()
+        })
+    }
+
+    /// Freezes the first `count` blocks of the network. See
+    /// [Weights2D::freeze_first_blocks].
+    pub fn freeze_first_blocks(&mut self, count: usize) {
+        self.weights.freeze_first_blocks(count);
+    }
+}
+
+/// Shades the plot with the given colors, one for each point of the
+/// mesh, row by row from the bottom.
+pub fn draw_mesh(plot: &Plot, colors: &[Color]) {
+    let width = MESH_RANGE.len();
+    for (index, &color) in colors.iter().enumerate() {
+        let x = MESH_RANGE.start + (index % width) as i32;
+        let y = MESH_RANGE.start + (index / width) as i32;
+        plot.draw_point(x as f32, y as f32, color);
+    }
+}
+
+/// The errors of a chunk of datapoints, summed.
+#[derive(Default)]
+struct ErrorTotals {
+    /// The sum of [Task::loss].
+    loss: f64,
+    absolute_error: f64,
+    correctly_classified: usize,
+}
+
+impl ErrorTotals {
+    fn add(self, other: ErrorTotals) -> Self {
+        ErrorTotals {
+            loss: self.loss + other.loss,
+            absolute_error: self.absolute_error + other.absolute_error,
+            correctly_classified: self.correctly_classified + other.correctly_classified,
+        }
+    }
+
+    /// Adds the errors of a datapoint on which the network has the given
+    /// outputs.
+    fn record(&mut self, task: Task, outputs: &[f64], target: f64) {
+        if task.is_correct(outputs, target) {
+            self.correctly_classified += 1;
+        }
+        self.loss += task.loss(outputs, target);
+        self.absolute_error += task.absolute_error(outputs, target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::Penalty,
+        trainer::{Optimizer, Trainer},
+    };
+
+    #[test]
+    fn test_parallel_gradients_match_expression_graph() {
+        let datapoints = (0..300)
+            .map(|i| {
+                let label = if i % 3 == 0 {
+                    Label2D::Red
+                } else {
+                    Label2D::Blue
+                };
+                Datapoint2D::new((i % 40 - 20, i / 10 - 15), label)
+            })
+            .collect();
+        let weights = Weights2D::new(
+            2,
+            vec![
+                LayerSpec::Dense(8),
+                LayerSpec::LayerNorm,
+                LayerSpec::Residual(1),
+            ],
+            true,
+        );
+        let mut classifier = Classifier2D::new(datapoints, weights)
+            .with_regularization(Some(Regularization::new(Penalty::L2, 0.01)));
+
+        let odd_points: Vec<usize> = (0..classifier.num_datapoints())
+            .skip(1)
+            .step_by(2)
+            .collect();
+        for batch in [None, Some(odd_points.as_slice())] {
+            let grads = classifier.parallel_gradients(batch).unwrap();
+            let (loss, accuracy) = (classifier.loss(), classifier.accuracy());
+            let regularization_loss = classifier.regularization_loss();
+            let mean_absolute_error = classifier.mean_absolute_error();
+
+            classifier.calculate_loss_and_accuracy(batch, true, true);
+            assert!((classifier.loss() - loss).abs() < 1e-12);
+            assert_eq!(classifier.accuracy(), accuracy);
+            assert!((classifier.mean_absolute_error() - mean_absolute_error).abs() < 1e-12);
+            assert!((classifier.regularization_loss() - regularization_loss).abs() < 1e-12);
+            for (param, grad) in classifier.weights.0.params().iter().zip(grads) {
+                assert!((param.grad() - grad).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_multi_class_gradients_match_expression_graph() {
+        let task = Task::MultiClass { num_classes: 3 };
+        let datapoints = (0..90)
+            .map(|i| {
+                Datapoint2D::new(
+                    (i % 20 - 10, i / 5 - 9),
+                    Label2D::from_index(i as usize % 3),
+                )
+            })
+            .collect();
+        let weights = Weights2D::new(2, vec![LayerSpec::Dense(8)], false).for_task(task);
+        assert_eq!(weights.notation(), "2-8-3");
+        let mut classifier = Classifier2D::new(datapoints, weights).with_task(task);
+
+        let grads = classifier.parallel_gradients(None).unwrap();
+        let (loss, accuracy) = (classifier.loss(), classifier.accuracy());
+        classifier.calculate_loss_and_accuracy(None, true, true);
+        assert!((classifier.loss() - loss).abs() < 1e-12);
+        assert_eq!(classifier.accuracy(), accuracy);
+        for (param, grad) in classifier.weights.0.params().iter().zip(grads) {
+            assert!((param.grad() - grad).abs() < 1e-9);
+        }
+
+        // The input gradient is of the predicted class's log-probability,
+        // with or without an expression graph.
+        let (x_grad, y_grad) = classifier.input_gradient(-20.0, 7.0);
+        let mesh_index =
+            ((7 - MESH_RANGE.start) * MESH_RANGE.len() as i32 + (-20 - MESH_RANGE.start)) as usize;
+        let (mesh_x_grad, mesh_y_grad) = classifier.mesh_input_gradients()[mesh_index];
+        assert!((mesh_x_grad - x_grad).abs() < 1e-12);
+        assert!((mesh_y_grad - y_grad).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_multi_class_separates_stripes() {
+        let task = Task::MultiClass { num_classes: 4 };
+        let datapoints = (-20..20)
+            .map(|x| {
+                let label = Label2D::from_index(((x + 20) / 10) as usize);
+                Datapoint2D::new((x, (x * 7) % 13), label)
+            })
+            .collect();
+        let weights = Weights2D::new(2, vec![LayerSpec::Dense(8)], false).for_task(task);
+        let classifier = Classifier2D::new(datapoints, weights).with_task(task);
+        let trainer = train(classifier, 1.0, 2000);
+        let classifier = trainer.classifier();
+        assert_eq!(classifier.accuracy(), 1.0);
+        let outputs = &classifier.mesh_outputs()[0];
+        assert_eq!(outputs.len(), 4);
+        assert!((softmax(outputs).iter().sum::<f64>() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_validation_points_are_not_trained_on() {
+        let training_points = vec![
+            Datapoint2D::new((-10, 9), Label2D::Red),
+            Datapoint2D::new((5, -5), Label2D::Blue),
+            Datapoint2D::new((8, 8), Label2D::Red),
+        ];
+        let mut datapoints = training_points.clone();
+        datapoints.insert(
+            1,
+            Datapoint2D::new((0, 0), Label2D::Red).with_validation(true),
+        );
+        let weights = Weights2D::new(2, vec![LayerSpec::Dense(4)], false);
+        let mut classifier = Classifier2D::new(datapoints, weights);
+        assert_eq!(classifier.num_training_points(), 3);
+        let grads = classifier.gradients(Some(&[1, 2]));
+        let validation_output = classifier.outputs()[1][0];
+        let (loss, accuracy) = classifier.validation_loss_and_accuracy().unwrap();
+        assert!((loss - (1.0 - validation_output).powi(2)).abs() < 1e-12);
+        assert_eq!(accuracy, if validation_output > 0.5 { 1.0 } else { 0.0 });
+
+        classifier.set_datapoints(training_points);
+        assert_eq!(classifier.gradients(Some(&[1, 2])), grads);
+        assert_eq!(classifier.validation_loss_and_accuracy(), None);
+    }
+
+    #[test]
+    fn test_gradients_at_is_the_gradient_of_loss_at() {
+        let datapoints = (0..20)
+            .map(|i| {
+                let label = Label2D::from_index(i as usize % 2);
+                Datapoint2D::new((i * 3 - 30, (i * 7) % 13), label)
+            })
+            .collect();
+        let weights = Weights2D::new(2, vec![LayerSpec::Dense(4), LayerSpec::BatchNorm], false);
+        let mut classifier = Classifier2D::new(datapoints, weights);
+        classifier.set_dropout(0.5);
+        let outputs = classifier.outputs();
+        let params = classifier.param_values();
+
+        let grads = classifier.gradients_at(&params, None);
+        // The batch normalization statistics are left alone.
+        assert_eq!(classifier.outputs(), outputs);
+        let epsilon = 1e-6;
+        for index in [0, 9, params.len() - 1] {
+            let mut shifted = params.clone();
+            shifted[index] += epsilon;
+            let above = classifier.loss_at(&shifted, None);
+            shifted[index] -= 2.0 * epsilon;
+            let below = classifier.loss_at(&shifted, None);
+            let difference = (above - below) / (2.0 * epsilon);
+            assert!((grads[index] - difference).abs() < 1e-6, "param {index}");
+        }
+    }
+
+    #[test]
+    fn test_frozen_blocks_dont_learn() {
+        let weights = Weights2D::new(
+            2,
+            vec![
+                LayerSpec::Dense(4),
+                LayerSpec::LayerNorm,
+                LayerSpec::Dense(4),
+                LayerSpec::Dense(4),
+            ],
+            false,
+        );
+        assert_eq!(weights.notation(), "2-4x3-1 LN");
+        let mut classifier =
+            Classifier2D::new(vec![Datapoint2D::new((5, 5), Label2D::Red)], weights);
+        classifier.freeze_first_blocks(2);
+        assert_eq!(classifier.weights().notation(), "2-[4]-[4]-4-1 LN");
+        let before = classifier.weights().0.read_only().params();
+        let grads = classifier.gradients(None);
+        let params: Vec<f64> = (classifier.param_values().iter().zip(grads))
+            .map(|(param, grad)| param - 0.5 * grad)
+            .collect();
+        classifier.set_param_values(&params);
+        let after = classifier.weights().0.read_only().params();
+        // The first dense layer, its layer norm and the second dense
+        // layer don't change.
+        let num_frozen = 12 + 8 + 20;
+        assert_eq!(before[..num_frozen], after[..num_frozen]);
+        assert_ne!(before[num_frozen..], after[num_frozen..]);
+    }
+
+    /// Trains the classifier with full-batch gradient descent for the
+    /// given number of steps, and evaluates the result.
+    fn train(classifier: Classifier2D, learning_rate: f64, steps: u64) -> Trainer {
+        let mut trainer = Trainer::new(classifier, Optimizer::Sgd { learning_rate });
+        trainer.run_until(|metrics| metrics.step >= steps);
+        trainer.classifier_mut().evaluate();
+        trainer
+    }
+
+    #[test]
+    fn test_regression_fits_continuous_targets() {
+        // The target is a linear function of the position, which a
+        // network without hidden layers can fit exactly.
+        let datapoints = (-10..10)
+            .map(|i| {
+                let pos = (i, (i * 7) % 11);
+                Datapoint2D::new(pos, (pos.0 - pos.1) as f64 / 20.0)
+            })
+            .collect();
+        let weights = Weights2D::new(2, vec![], false)
+            .with_output_activation(Task::Regression.output_activation());
+        let classifier = Classifier2D::new(datapoints, weights).with_task(Task::Regression);
+        let trainer = train(classifier, 0.5, 1000);
+        let classifier = trainer.classifier();
+        assert!(classifier.loss() < 1e-6, "loss is {}", classifier.loss());
+        assert!(classifier.mean_absolute_error() < 1e-3);
+        assert_eq!(classifier.accuracy(), 0.0);
+    }
+
+    #[test]
+    fn test_polynomial_features_separate_circles() {
+        let datapoints = (-4..=4)
+            .flat_map(|x| (-4..=4).map(move |y| (x * 5, y * 5)))
+            .map(|pos| {
+                let label = if pos.0 * pos.0 + pos.1 * pos.1 < 300 {
+                    Label2D::Red
+                } else {
+                    Label2D::Blue
+                };
+                Datapoint2D::new(pos, label)
+            })
+            .collect();
+        let features = Features::default().with_polynomial();
+        let weights = Weights2D::new(features.len(), vec![], false);
+        let classifier = Classifier2D::new(datapoints, weights).with_features(features);
+        let trainer = train(classifier, 2.0, 2000);
+        assert_eq!(trainer.classifier().accuracy(), 1.0);
+    }
+
+    #[test]
+    fn test_input_gradients_match_finite_differences() {
+        let features = Features::default().with_trigonometric();
+        let weights = Weights2D::new(features.len(), vec![LayerSpec::Dense(4)], false);
+        let classifier = Classifier2D::new(vec![], weights).with_features(features);
+        let output = |x: f64, y: f64| {
+            let inputs = classifier
+                .features()
+                .expand(x / POINT_SCALE, y / POINT_SCALE);
+            classifier.weights.0.read_only().forward(&inputs).output()[0]
+        };
+        let (x, y) = (-20.0, 7.0);
+        let (x_grad, y_grad) = classifier.input_gradient(x, y);
+        let epsilon = 1e-4;
+        let x_difference =
+            (output(x + epsilon, y) - output(x - epsilon, y)) / (2.0 * epsilon / POINT_SCALE);
+        let y_difference =
+            (output(x, y + epsilon) - output(x, y - epsilon)) / (2.0 * epsilon / POINT_SCALE);
+        assert!((x_grad - x_difference).abs() < 1e-6);
+        assert!((y_grad - y_difference).abs() < 1e-6);
+
+        // The mesh starts at the bottom left and goes row by row.
+        let mesh_index = ((y as i32 - MESH_RANGE.start) * MESH_RANGE.len() as i32
+            + (x as i32 - MESH_RANGE.start)) as usize;
+        let (mesh_x_grad, mesh_y_grad) = classifier.mesh_input_gradients()[mesh_index];
+        assert!((mesh_x_grad - x_grad).abs() < 1e-12);
+        assert!((mesh_y_grad - y_grad).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_colormap_clamps_to_its_range() {
+        let (min, max) = COLORMAP_RANGE;
+        assert_eq!(colormap(min - 1.0), colormap(min));
+        assert_eq!(colormap(max + 1.0), colormap(max));
+        assert!((colormap(max).r - COLORMAP_STOPS[COLORMAP_STOPS.len() - 1].r).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_no_parallel_gradients_with_dropout() {
+        let mut classifier = Classifier2D::new(
+            vec![Datapoint2D::new((0, 0), Label2D::Red)],
+            Weights2D::new(2, vec![LayerSpec::Dense(4)], false),
+        );
+        classifier.set_dropout(0.5);
+        assert_eq!(classifier.parallel_gradients(None), None);
     }
 }
@@ -0,0 +1,209 @@
+use std::{fmt::Display, path::Path};
+
+use crate::{
+    classifier_2d::{Datapoint2D, Label2D, Target2D},
+    engine::SplitMix64,
+};
+
+/// The header line written at the top of dataset files.
+const HEADER: &str = "x,y,target,split";
+
+/// The header line of dataset files from before they had a split column.
+const HEADER_WITHOUT_SPLIT: &str = "x,y,target";
+
+#[derive(Debug)]
+pub enum DatasetError {
+    Io(std::io::Error),
+    /// The given line (starting from 1) couldn't be parsed.
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl Display for DatasetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatasetError::Io(err) => write!(f, "I/O error: {err}"),
+            DatasetError::Parse { line, message } => {
+                write!(f, "invalid dataset on line {line}: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DatasetError {}
+
+impl From<std::io::Error> for DatasetError {
+    fn from(value: std::io::Error) -> Self {
+        DatasetError::Io(value)
+    }
+}
+
+/// Parses datapoints in CSV format. Each line is `x,y,target,split`,
+/// where `x` and `y` are integers, `target` is either the name of a
+/// label, like `blue` or `red`, or a number, and `split` is either
+/// `train` or `validation`. The split can be left out, in which case
+/// the point is a training point. Blank lines, lines starting with `#`
+/// and the header line are ignored.
+pub fn parse_datapoints(text: &str) -> Result<Vec<Datapoint2D>, DatasetError> {
+    let mut datapoints = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line == HEADER
+            || line == HEADER_WITHOUT_SPLIT
+        {
+            continue;
+        }
+        let error = |message: String| DatasetError::Parse {
+            line: index + 1,
+            message,
+        };
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let (x, y, target, split) = match fields[..] {
+            [x, y, target] => (x, y, target, "train"),
+            [x, y, target, split] => (x, y, target, split),
+            _ => {
+                return Err(error(format!(
+                    "expected 3 or 4 fields but found {}",
+                    fields.len()
+                )));
+            }
+        };
+        let validation = match split.to_lowercase().as_str() {
+            "train" => false,
+            "validation" => true,
+            _ => return Err(error(format!("invalid split {split:?}"))),
+        };
+        let coordinate = |field: &str| {
+            field
+                .parse::<i32>()
+                .map_err(|_| error(format!("invalid coordinate {field:?}")))
+        };
+        let name = target.to_lowercase();
+        let label = Label2D::ALL.into_iter().find(|label| label.name() == name);
+        let target = match label {
+            Some(label) => Target2D::Label(label),
+            None => match name.parse::<f64>() {
+                Ok(value) if value.is_finite() => Target2D::Value(value),
+                _ => return Err(error(format!("invalid target {target:?}"))),
+            },
+        };
+        datapoints.push(
+            Datapoint2D::new((coordinate(x)?, coordinate(y)?), target).with_validation(validation),
+        );
+    }
+    Ok(datapoints)
+}
+
+/// Converts datapoints to the format read by [parse_datapoints].
+pub fn datapoints_to_csv(datapoints: &[Datapoint2D]) -> String {
+    let mut csv = format!("{HEADER}\n");
+    for point in datapoints {
+        let target = match point.target {
+            Target2D::Label(label) => label.name().to_owned(),
+            Target2D::Value(value) => value.to_string(),
+        };
+        let split = if point.validation {
+            "validation"
+        } else {
+            "train"
+        };
+        csv.push_str(&format!(
+            "{},{},{},{}\n",
+            point.pos.0, point.pos.1, target, split
+        ));
+    }
+    csv
+}
+
+/// Marks a random `fraction` of the datapoints, rounded to the nearest
+/// whole number of them, as validation points, and the rest as training
+/// points.
+pub fn split_validation(datapoints: &mut [Datapoint2D], fraction: f64, seed: u64) {
+    let mut rng = SplitMix64::new(seed);
+    let mut indices: Vec<usize> = (0..datapoints.len()).collect();
+    let num_validation = (fraction.clamp(0.0, 1.0) * datapoints.len() as f64).round() as usize;
+    // A partial Fisher-Yates shuffle picks the validation points.
+    for i in 0..num_validation {
+        let j = i + rng.next_index(indices.len() - i);
+        indices.swap(i, j);
+    }
+    for point in datapoints.iter_mut() {
+        point.validation = false;
+    }
+    for &index in &indices[..num_validation] {
+        datapoints[index].validation = true;
+    }
+}
+
+pub fn load_datapoints<P: AsRef<Path>>(path: P) -> Result<Vec<Datapoint2D>, DatasetError> {
+    parse_datapoints(&std::fs::read_to_string(path)?)
+}
+
+pub fn save_datapoints<P: AsRef<Path>>(
+    path: P,
+    datapoints: &[Datapoint2D],
+) -> Result<(), DatasetError> {
+    Ok(std::fs::write(path, datapoints_to_csv(datapoints))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let datapoints = vec![
+            Datapoint2D::new((-10, 9), Label2D::Red),
+            Datapoint2D::new((5, -5), Label2D::Blue),
+            Datapoint2D::new((7, 1), Label2D::Purple).with_validation(true),
+            Datapoint2D::new((0, 3), -0.25),
+        ];
+        let parsed = parse_datapoints(&datapoints_to_csv(&datapoints)).unwrap();
+        assert_eq!(parsed.len(), datapoints.len());
+        for (parsed, point) in parsed.iter().zip(&datapoints) {
+            assert_eq!(parsed.pos, point.pos);
+            assert_eq!(parsed.target, point.target);
+            assert_eq!(parsed.validation, point.validation);
+        }
+
+        // Files without a split column only have training points.
+        let parsed = parse_datapoints("x,y,target\n1,2,red\n").unwrap();
+        assert!(!parsed[0].validation);
+    }
+
+    #[test]
+    fn test_split_validation() {
+        let mut datapoints: Vec<Datapoint2D> = (0..10)
+            .map(|i| Datapoint2D::new((i, i), Label2D::Blue).with_validation(true))
+            .collect();
+        split_validation(&mut datapoints, 0.26, 7);
+        let num_validation = datapoints.iter().filter(|point| point.validation).count();
+        assert_eq!(num_validation, 3);
+        let mut again = datapoints.clone();
+        split_validation(&mut again, 0.26, 7);
+        assert!(
+            again
+                .iter()
+                .zip(&datapoints)
+                .all(|(a, b)| a.validation == b.validation)
+        );
+        split_validation(&mut again, 0.0, 7);
+        assert!(again.iter().all(|point| !point.validation));
+    }
+
+    #[test]
+    fn test_parse_errors_have_line_numbers() {
+        let err = parse_datapoints("# comment\n1,2,red\n\n3,four,blue\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid dataset on line 4: invalid coordinate \"four\""
+        );
+        assert!(parse_datapoints("1,2").is_err());
+        assert!(parse_datapoints("1,2,magenta").is_err());
+        assert!(parse_datapoints("1,2,red,test").is_err());
+    }
+}
@@ -1,8 +1,17 @@
-use std::ops::{Add, Div, Mul};
+use std::{
+    collections::BTreeSet,
+    ops::{Add, Div, Mul, Sub},
+};
 
-use macroquad::rand::rand;
+use macroquad::rand::{gen_range, rand};
+use serde::{Deserialize, Serialize};
 
-use crate::value::Value;
+use crate::{
+    layer::{Layer, LayerSpec, ReadOnlyLayer, TrainableLayer, layer_from_file},
+    model_file::{FORMAT_VERSION, ModelFile, ModelFileError, ModelMetadata},
+    summary::{LayerSummary, ModelSummary, WeightStats},
+    value::Value,
+};
 
 /// Returns a random floating-point number between -1 and 1,
 /// I *think* it's inclusive but I'm not 100% sure (I wish
@@ -12,176 +21,629 @@ pub fn rand_f64() -> f64 {
     (rand() as f64 / u32::MAX as f64) * 2.0 - 1.0
 }
 
+/// A tiny deterministic random number generator, for when results
+/// shouldn't depend on (or disturb) macroquad's global random state.
+#[derive(Clone, Debug)]
+pub(crate) struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub(crate) fn new(seed: u64) -> Self {
+        SplitMix64(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in [0, len). The tiny bias towards small
+    /// numbers doesn't matter for our purposes.
+    pub(crate) fn next_index(&mut self, len: usize) -> usize {
+        (self.next_u64() % len as u64) as usize
+    }
+
+    /// Returns a number in [0, 1).
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns a normally distributed number, using the Box-Muller
+    /// transform.
+    pub(crate) fn next_normal(&mut self) -> f64 {
+        let u1 = 1.0 - self.next_f64();
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
 /// A trait that represents the underlying value used by a
 /// neuron. If backprop is a concern, the implementation in
 /// `Value` can be used, but otherwise the `f64`
 /// implementation is much more efficient and [Send]-able.
 pub trait NeuronValue:
-    Clone
+    'static
+    + Clone
     + std::fmt::Debug
     + From<f64>
     + Mul<Self, Output = Self>
     + Add<Self, Output = Self>
+    + Sub<Self, Output = Self>
     + Div<Self, Output = Self>
 {
+    /// The type of layer that neural nets using this value are
+    /// made of.
+    type Layer: ?Sized + Layer<Self>;
+
     fn exp(&self) -> Self;
 
+    fn relu(&self) -> Self;
+
+    fn abs(&self) -> Self;
+
+    fn pow(&self, exponent: f64) -> Self;
+
     fn as_f64(&self) -> f64;
 }
 
 impl NeuronValue for Value {
+    type Layer = TrainableLayer;
+
     fn exp(&self) -> Value {
         self.exp()
     }
 
+    fn relu(&self) -> Value {
+        self.relu()
+    }
+
+    fn abs(&self) -> Value {
+        self.abs()
+    }
+
+    fn pow(&self, exponent: f64) -> Value {
+        self.pow(exponent)
+    }
+
     fn as_f64(&self) -> f64 {
         self.as_f64()
     }
 }
 
 impl NeuronValue for f64 {
+    /// These layers are [Send] and [Sync], so read-only neural nets
+    /// can be used from multiple threads at once.
+    type Layer = ReadOnlyLayer;
+
     fn exp(&self) -> f64 {
         f64::exp(*self)
     }
 
+    fn relu(&self) -> f64 {
+        f64::max(*self, 0.0)
+    }
+
+    fn abs(&self) -> f64 {
+        f64::abs(*self)
+    }
+
+    fn pow(&self, exponent: f64) -> f64 {
+        f64::powf(*self, exponent)
+    }
+
     fn as_f64(&self) -> f64 {
         *self
     }
 }
 
 /// Represents an activation function for neurons.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ActivationType {
     Sigmoid,
+    Tanh,
+    Relu,
+    /// Passes its input through unchanged.
+    Linear,
 }
 
 impl ActivationType {
-    fn activate<V: NeuronValue>(&self, value: V) -> V {
+    /// Returns the name used for the activation function on the command
+    /// line, in results and in generated code.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ActivationType::Sigmoid => "sigmoid",
+            ActivationType::Tanh => "tanh",
+            ActivationType::Relu => "relu",
+            ActivationType::Linear => "linear",
+        }
+    }
+
+    pub fn activate<V: NeuronValue>(&self, value: V) -> V {
         match self {
             ActivationType::Sigmoid => {
                 V::from(1.0) / (V::from(1.0) + (value * (-1.0).into()).exp())
             }
+            ActivationType::Tanh => {
+                V::from(2.0) / (V::from(1.0) + (value * (-2.0).into()).exp()) - V::from(1.0)
+            }
+            ActivationType::Relu => value.relu(),
+            ActivationType::Linear => value,
+        }
+    }
+
+    /// Returns the derivative of the activation function at the point
+    /// where it returned the given output. All our activation functions
+    /// have derivatives that are easy to express this way.
+    pub fn derivative_from_output(&self, output: f64) -> f64 {
+        match self {
+            ActivationType::Sigmoid => output * (1.0 - output),
+            ActivationType::Tanh => 1.0 - output * output,
+            ActivationType::Relu => {
+                if output > 0.0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            ActivationType::Linear => 1.0,
         }
     }
 }
 
-/// A single neuron in a neural net. It can have any number of
-/// inputs and always produces a single output value.
-#[derive(Clone, Debug)]
-struct Neuron<V: NeuronValue> {
-    weights: Vec<V>,
-    bias: V,
-    activation: ActivationType,
+/// A neural net with multiple layers, some of which may
+/// be hidden.
+#[derive(Debug)]
+pub struct MultiLayerPerceptron<V: NeuronValue> {
+    num_inputs: usize,
+    layers: Vec<Box<V::Layer>>,
+    /// The probability that each hidden neuron's output is dropped
+    /// (set to zero) during training.
+    dropout: f64,
+    /// Whether the final layer sees the outputs of all the hidden
+    /// dense and residual layers, concatenated, rather than just the
+    /// output of the last hidden layer.
+    output_skips: bool,
+    /// The indices of the layers whose parameters shouldn't be changed
+    /// by training.
+    frozen: BTreeSet<usize>,
 }
 
-impl<V: NeuronValue> Neuron<V> {
-    fn new(num_inputs: usize, activation: ActivationType) -> Self {
-        Neuron {
-            weights: (0..num_inputs).map(|_| rand_f64().into()).collect(),
-            bias: rand_f64().into(),
-            activation,
+impl MultiLayerPerceptron<Value> {
+    /// Creates a neural net out of the given layers, which can be
+    /// of any kind, as long as each one's number of outputs matches
+    /// the next one's number of inputs.
+    pub fn from_layers(num_inputs: usize, layers: Vec<Box<TrainableLayer>>) -> Self {
+        Self {
+            num_inputs,
+            layers,
+            dropout: 0.0,
+            output_skips: false,
+            frozen: BTreeSet::new(),
         }
     }
 
-    fn read_only(&self) -> Neuron<f64> {
-        Neuron {
-            weights: self.weights.iter().map(|weight| weight.as_f64()).collect(),
-            bias: self.bias.as_f64(),
-            activation: self.activation,
-        }
+    /// Creates a neural net of dense layers with the given numbers of
+    /// outputs, all using the given activation function.
+    pub fn new(
+        num_inputs: usize,
+        activation: ActivationType,
+        num_layer_outputs: Vec<usize>,
+    ) -> Self {
+        Self::from_specs(
+            num_inputs,
+            activation,
+            num_layer_outputs
+                .into_iter()
+                .map(LayerSpec::Dense)
+                .collect(),
+        )
     }
 
-    fn output(&self, inputs: &Vec<V>) -> V {
-        assert_eq!(self.weights.len(), inputs.len());
-        let mut sum = self.bias.clone();
-        for (weight, input) in self.weights.iter().zip(inputs) {
-            sum = sum + weight.clone() * input.clone();
+    /// Creates a neural net with the given built-in layers. The
+    /// activation function is used by all the dense layers.
+    pub fn from_specs(
+        num_inputs: usize,
+        activation: ActivationType,
+        specs: Vec<LayerSpec>,
+    ) -> Self {
+        let mut layers = vec![];
+        let mut next_num_inputs = num_inputs;
+        for spec in specs {
+            let layer = spec.build(next_num_inputs, activation);
+            next_num_inputs = layer.num_outputs();
+            layers.push(layer);
         }
-        self.activation.activate(sum)
+        Self::from_layers(num_inputs, layers)
+    }
+
+    /// Like [Self::from_specs], but the final layer is connected to
+    /// every hidden dense and residual layer rather than just the last
+    /// hidden layer. Such skip connections give the gradient a short
+    /// path to the early layers of deep networks.
+    pub fn from_specs_with_output_skips(
+        num_inputs: usize,
+        activation: ActivationType,
+        mut specs: Vec<LayerSpec>,
+    ) -> Self {
+        let Some(final_spec) = specs.pop() else {
+            return Self::from_specs(num_inputs, activation, specs);
+        };
+        let mut mlp = Self::from_specs(num_inputs, activation, specs);
+        mlp.output_skips = true;
+        let final_num_inputs = mlp.final_layer_num_inputs();
+        mlp.layers
+            .push(final_spec.build(final_num_inputs, activation));
+        mlp
     }
 
-    fn params(&self) -> Vec<V> {
-        let mut params = self.weights.clone();
-        params.push(self.bias.clone());
-        params
+    /// Replaces the final layer with a freshly initialized dense layer
+    /// of the same width that uses the given activation function. For
+    /// example, regression needs a linear output so that it isn't
+    /// limited to the range of the hidden layers' activation function.
+    pub fn with_output_activation(self, activation: ActivationType) -> Self {
+        let num_outputs = self.num_outputs();
+        self.with_output_layer(num_outputs, activation)
     }
-}
 
-/// A layer in a neural net.
-#[derive(Clone, Debug)]
-struct Layer<V: NeuronValue> {
-    neurons: Vec<Neuron<V>>,
-}
+    /// Like [Self::with_output_activation], but the new final layer has
+    /// the given number of outputs.
+    pub fn with_output_layer(mut self, num_outputs: usize, activation: ActivationType) -> Self {
+        self.layers.pop().expect("network has no layers");
+        let num_inputs = self.final_layer_num_inputs();
+        self.layers
+            .push(LayerSpec::Dense(num_outputs).build(num_inputs, activation));
+        self
+    }
 
-impl<V: NeuronValue> Layer<V> {
-    fn new(num_inputs: usize, activation: ActivationType, num_outputs: usize) -> Self {
-        Layer {
-            neurons: (0..num_outputs)
-                .map(|_| Neuron::new(num_inputs, activation))
-                .collect(),
+    /// Rebuilds a network from its on-disk representation.
+    pub fn from_model_file(file: &ModelFile) -> Result<Self, ModelFileError> {
+        if !(0.0..1.0).contains(&file.dropout) {
+            return Err(ModelFileError::ShapeMismatch(format!(
+                "invalid dropout {}",
+                file.dropout
+            )));
         }
+        let mut mlp = Self::from_layers(file.num_inputs, vec![]);
+        mlp.dropout = file.dropout;
+        mlp.output_skips = file.output_skips;
+        let num_layers = file.layers.len();
+        for (index, layer_file) in file.layers.iter().enumerate() {
+            let num_inputs = if index == num_layers - 1 {
+                mlp.final_layer_num_inputs()
+            } else {
+                mlp.num_outputs()
+            };
+            mlp.layers
+                .push(layer_from_file(layer_file, num_inputs, index)?);
+        }
+        Ok(mlp)
     }
+}
 
-    fn read_only(&self) -> Layer<f64> {
-        Layer {
-            neurons: self
-                .neurons
-                .iter()
-                .map(|neuron| neuron.read_only())
-                .collect(),
-        }
+impl MultiLayerPerceptron<f64> {
+    /// Rebuilds a read-only network from its on-disk representation.
+    pub fn from_model_file(file: &ModelFile) -> Result<Self, ModelFileError> {
+        Ok(MultiLayerPerceptron::<Value>::from_model_file(file)?.read_only())
     }
+}
 
-    fn output(&self, inputs: &Vec<V>) -> Vec<V> {
-        self.neurons
-            .iter()
-            .map(|neuron| neuron.output(inputs))
-            .collect()
+impl MultiLayerPerceptron<f64> {
+    /// Runs the network in inference mode, remembering what each layer
+    /// was given and returned so that [Self::backward] can use it.
+    pub fn forward(&self, inputs: &[f64]) -> ForwardPass {
+        let num_hidden_layers = self.layers.len().saturating_sub(1);
+        let has_skips = self.skip_width(num_hidden_layers) > 0;
+        let mut pass = ForwardPass {
+            inputs: vec![],
+            outputs: vec![],
+            output: inputs.to_vec(),
+        };
+        let mut skipped = vec![];
+        for (index, layer) in self.layers.iter().enumerate() {
+            let is_hidden = index < num_hidden_layers;
+            let inputs = if !is_hidden && has_skips {
+                std::mem::take(&mut skipped)
+            } else {
+                pass.output.clone()
+            };
+            pass.output = layer.output(&inputs);
+            if is_hidden && self.is_skip_source(index) {
+                skipped.extend_from_slice(&pass.output);
+            }
+            pass.inputs.push(inputs);
+            pass.outputs.push(pass.output.clone());
+        }
+        pass
     }
 
-    fn params(&self) -> Vec<V> {
-        self.neurons
+    /// Returns the values of the neurons in every hidden layer that has
+    /// them, i.e. dense layers, residual blocks and activation layers,
+    /// for the given input.
+    pub fn neuron_activations(&self, inputs: &[f64]) -> Vec<NeuronActivations> {
+        let pass = self.forward(inputs);
+        let num_hidden_layers = self.layers.len().saturating_sub(1);
+        self.layers[..num_hidden_layers]
             .iter()
-            .flat_map(|neuron| neuron.params())
+            .enumerate()
+            .filter_map(|(index, layer)| {
+                Some(NeuronActivations {
+                    layer: index,
+                    pre_activations: layer.pre_activations(&pass.inputs[index])?,
+                    activations: pass.outputs[index].clone(),
+                })
+            })
             .collect()
     }
-}
 
-/// A neural net with multiple layers, some of which may
-/// be hidden.
-#[derive(Clone, Debug)]
-pub struct MultiLayerPerceptron<V: NeuronValue> {
-    layers: Vec<Layer<V>>,
+    /// Backpropagates the gradient of the loss with respect to the
+    /// outputs of the given forward pass, without building an
+    /// expression graph. The gradients of the parameters are added to
+    /// `param_grads`, which is in the same order as [Self::params], so
+    /// they can be accumulated over many passes. Returns the gradient
+    /// with respect to the inputs, or `None` if any of the layers don't
+    /// support [Layer::backward].
+    pub fn backward(
+        &self,
+        pass: &ForwardPass,
+        output_grads: &[f64],
+        param_grads: &mut [f64],
+    ) -> Option<Vec<f64>> {
+        let num_layers = self.layers.len();
+        let num_hidden_layers = num_layers.saturating_sub(1);
+        let skip_width = self.skip_width(num_hidden_layers);
+        let mut grads = output_grads.to_vec();
+        // The gradient with respect to the outputs of the hidden layers
+        // that feed the final layer directly, in reverse order.
+        let mut skip_grads = vec![];
+        let mut param_end = param_grads.len();
+        for (index, layer) in self.layers.iter().enumerate().rev() {
+            let is_hidden = index < num_hidden_layers;
+            if is_hidden && skip_width > 0 {
+                if index == num_hidden_layers - 1 {
+                    // Only the skip connections lead out of the last
+                    // hidden layer.
+                    grads = vec![0.0; layer.num_outputs()];
+                }
+                if self.is_skip_source(index) {
+                    let start = skip_grads.len() - layer.num_outputs();
+                    for (grad, skip_grad) in grads.iter_mut().zip(skip_grads.drain(start..)) {
+                        *grad += skip_grad;
+                    }
+                }
+            }
+            let param_start = param_end - layer.num_params();
+            grads = layer.backward(
+                &pass.inputs[index],
+                &pass.outputs[index],
+                &grads,
+                &mut param_grads[param_start..param_end],
+            )?;
+            param_end = param_start;
+            if !is_hidden && skip_width > 0 {
+                skip_grads = std::mem::take(&mut grads);
+            }
+        }
+        Some(grads)
+    }
 }
 
 impl<V: NeuronValue> MultiLayerPerceptron<V> {
-    pub fn new(
-        num_inputs: usize,
-        activation: ActivationType,
-        num_layer_outputs: Vec<usize>,
-    ) -> Self {
+    /// Returns the on-disk representation of the network. This
+    /// fails if any of its layers can't be saved.
+    pub fn to_model_file(&self, metadata: ModelMetadata) -> Result<ModelFile, ModelFileError> {
         let mut layers = vec![];
-        let mut next_num_inputs = num_inputs;
-        for num_outputs in num_layer_outputs {
-            layers.push(Layer::new(next_num_inputs, activation, num_outputs));
-            next_num_inputs = num_outputs;
+        for (index, layer) in self.layers.iter().enumerate() {
+            let Some(file) = layer.to_file() else {
+                return Err(ModelFileError::ShapeMismatch(format!(
+                    "layer {index} can't be saved"
+                )));
+            };
+            layers.push(file);
+        }
+        Ok(ModelFile {
+            version: FORMAT_VERSION,
+            num_inputs: self.num_inputs,
+            dropout: self.dropout,
+            output_skips: self.output_skips,
+            layers,
+            metadata,
+        })
+    }
+
+    pub fn num_inputs(&self) -> usize {
+        self.num_inputs
+    }
+
+    pub fn num_outputs(&self) -> usize {
+        self.layers
+            .last()
+            .map(|layer| layer.num_outputs())
+            .unwrap_or(self.num_inputs)
+    }
+
+    /// Returns descriptions of all the layers of the network, or
+    /// `None` for layers that aren't built-in.
+    pub fn specs(&self) -> Vec<Option<LayerSpec>> {
+        self.layers.iter().map(|layer| layer.spec()).collect()
+    }
+
+    pub fn dropout(&self) -> f64 {
+        self.dropout
+    }
+
+    pub fn output_skips(&self) -> bool {
+        self.output_skips
+    }
+
+    /// Whether the hidden layer with the given index is the last layer of
+    /// its block, i.e. it isn't followed by a layer that continues it.
+    fn ends_block(&self, index: usize) -> bool {
+        !self
+            .layers
+            .get(index + 1)
+            .is_some_and(|layer| continues_block(layer.spec()))
+    }
+
+    /// Whether dropout is applied to the outputs of the layer with the
+    /// given index during training. It's applied once per hidden block,
+    /// after any normalization, so that batch normalization sees the
+    /// same statistics as it does at inference time.
+    fn applies_dropout_after(&self, index: usize) -> bool {
+        index + 1 < self.layers.len() && self.ends_block(index)
+    }
+
+    /// Whether the outputs of the hidden layer with the given index are
+    /// fed straight to the final layer. Those are the outputs of each
+    /// block that starts with a dense layer or a residual block, after
+    /// any normalization or activation layers.
+    pub(crate) fn is_skip_source(&self, index: usize) -> bool {
+        self.output_skips
+            && self.ends_block(index)
+            && self.layers[..=index]
+                .iter()
+                .rev()
+                .find(|layer| !continues_block(layer.spec()))
+                .is_some_and(|layer| {
+                    matches!(
+                        layer.spec(),
+                        Some(LayerSpec::Dense(_) | LayerSpec::Residual(_))
+                    )
+                })
+    }
+
+    /// Returns the total number of outputs of the first
+    /// `num_hidden_layers` layers that are fed straight to the final
+    /// layer. If this is zero, the final layer just sees the output of
+    /// the last hidden layer.
+    fn skip_width(&self, num_hidden_layers: usize) -> usize {
+        (0..num_hidden_layers)
+            .filter(|&index| self.is_skip_source(index))
+            .map(|index| self.layers[index].num_outputs())
+            .sum()
+    }
+
+    /// Returns how many inputs a final layer added to the current
+    /// layers would have.
+    fn final_layer_num_inputs(&self) -> usize {
+        match self.skip_width(self.layers.len()) {
+            0 => self.num_outputs(),
+            skip_width => skip_width,
         }
-        Self { layers }
+    }
+
+    pub fn set_dropout(&mut self, dropout: f64) {
+        assert!((0.0..1.0).contains(&dropout));
+        self.dropout = dropout;
     }
 
     pub fn read_only(&self) -> MultiLayerPerceptron<f64> {
         MultiLayerPerceptron {
+            num_inputs: self.num_inputs,
             layers: self.layers.iter().map(|layer| layer.read_only()).collect(),
+            dropout: self.dropout,
+            output_skips: self.output_skips,
+            frozen: self.frozen.clone(),
         }
     }
 
-    pub fn output(&self, inputs: &Vec<V>) -> Vec<V> {
-        let mut next_inputs = inputs.clone();
-        for layer in &self.layers {
-            let layer_outputs = layer.output(&next_inputs);
-            next_inputs = layer_outputs;
+    /// Returns the output of the network in inference mode, i.e.
+    /// when it's being used rather than trained.
+    pub fn output(&self, inputs: &[V]) -> Vec<V> {
+        let Some((final_layer, hidden_layers)) = self.layers.split_last() else {
+            return inputs.to_vec();
+        };
+        let mut skipped = vec![];
+        let mut next_inputs = inputs.to_vec();
+        for (index, layer) in hidden_layers.iter().enumerate() {
+            next_inputs = layer.output(&next_inputs);
+            if self.is_skip_source(index) {
+                skipped.extend(next_inputs.iter().cloned());
+            }
+        }
+        if !skipped.is_empty() {
+            next_inputs = skipped;
+        }
+        final_layer.output(&next_inputs)
+    }
+
+    /// Returns the outputs of the network in inference mode for a
+    /// whole batch of inputs, which are laid out one after the other.
+    /// The outputs are laid out the same way. Once the buffers have
+    /// grown big enough, this doesn't allocate as long as all the
+    /// layers implement [Layer::batch_output] without allocating.
+    pub fn batch_output<'a>(&self, inputs: &[V], buffers: &'a mut BatchBuffers<V>) -> &'a [V] {
+        let BatchBuffers {
+            outputs,
+            scratch,
+            layer_scratch,
+            skipped,
+        } = buffers;
+        outputs.clear();
+        outputs.extend_from_slice(inputs);
+        let Some((final_layer, hidden_layers)) = self.layers.split_last() else {
+            return outputs;
+        };
+        let skip_width = self.skip_width(hidden_layers.len());
+        skipped.clear();
+        skipped.resize(inputs.len() / self.num_inputs * skip_width, V::from(0.0));
+        let mut skip_offset = 0;
+        let mut num_inputs = self.num_inputs;
+        for (index, layer) in hidden_layers.iter().enumerate() {
+            layer.batch_output(num_inputs, outputs, scratch, layer_scratch);
+            std::mem::swap(outputs, scratch);
+            num_inputs = layer.num_outputs();
+            if self.is_skip_source(index) {
+                for (skipped, outputs) in skipped
+                    .chunks_exact_mut(skip_width)
+                    .zip(outputs.chunks_exact(num_inputs))
+                {
+                    skipped[skip_offset..skip_offset + num_inputs].clone_from_slice(outputs);
+                }
+                skip_offset += num_inputs;
+            }
         }
-        next_inputs
+        if skip_width > 0 {
+            final_layer.batch_output(skip_width, skipped, scratch, layer_scratch);
+        } else {
+            final_layer.batch_output(num_inputs, outputs, scratch, layer_scratch);
+        }
+        scratch
+    }
+
+    /// Returns the outputs of the network for a whole batch of
+    /// inputs in training mode, which means that dropout is applied
+    /// to its hidden layers and that batch normalization layers use
+    /// (and keep track of) the statistics of the batch.
+    pub fn training_output(&mut self, batch: &[Vec<V>]) -> Vec<Vec<V>> {
+        let num_layers = self.layers.len();
+        if num_layers == 0 {
+            return batch.to_vec();
+        }
+        let has_skips = self.skip_width(num_layers - 1) > 0;
+        let mut skipped = vec![vec![]; batch.len()];
+        let mut next_batch = batch.to_vec();
+        for index in 0..num_layers {
+            let is_hidden = index < num_layers - 1;
+            if !is_hidden && has_skips {
+                next_batch = std::mem::take(&mut skipped);
+            }
+            next_batch = self.layers[index].training_output(&next_batch);
+            if self.dropout > 0.0 && self.applies_dropout_after(index) {
+                next_batch = next_batch
+                    .into_iter()
+                    .map(|outputs| dropout(outputs, self.dropout))
+                    .collect();
+            }
+            if is_hidden && self.is_skip_source(index) {
+                for (skipped, outputs) in skipped.iter_mut().zip(&next_batch) {
+                    skipped.extend(outputs.iter().cloned());
+                }
+            }
+        }
+        next_batch
     }
 
     pub fn params(&self) -> Vec<V> {
@@ -190,4 +652,600 @@ impl<V: NeuronValue> MultiLayerPerceptron<V> {
             .flat_map(|layer| layer.params())
             .collect()
     }
+
+    pub fn num_params(&self) -> usize {
+        self.layers.iter().map(|layer| layer.num_params()).sum()
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Returns the number of parameters of the given layer.
+    pub fn layer_num_params(&self, index: usize) -> usize {
+        self.layers[index].num_params()
+    }
+
+    pub fn is_frozen(&self, index: usize) -> bool {
+        self.frozen.contains(&index)
+    }
+
+    /// Freezes or unfreezes the given layer. A frozen layer still takes
+    /// part in the forward pass, but its parameters shouldn't be changed
+    /// by training.
+    pub fn set_frozen(&mut self, index: usize, frozen: bool) {
+        assert!(index < self.layers.len());
+        if frozen {
+            self.frozen.insert(index);
+        } else {
+            self.frozen.remove(&index);
+        }
+    }
+
+    /// Returns whether each of the parameters, in the same order as
+    /// [Self::params], belongs to a layer that isn't frozen.
+    pub fn trainable_params(&self) -> Vec<bool> {
+        self.layers
+            .iter()
+            .enumerate()
+            .flat_map(|(index, layer)| {
+                std::iter::repeat_n(!self.is_frozen(index), layer.num_params())
+            })
+            .collect()
+    }
+
+    /// Returns all the parameters that aren't biases.
+    pub fn weights(&self) -> Vec<V> {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.weights())
+            .collect()
+    }
+
+    /// Returns a description of every layer of the network and its
+    /// parameters.
+    pub fn summary(&self) -> ModelSummary {
+        let num_hidden_layers = self.layers.len().saturating_sub(1);
+        let skip_width = self.skip_width(num_hidden_layers);
+        let mut num_inputs = self.num_inputs;
+        let layers = self
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(index, layer)| {
+                if index == num_hidden_layers && skip_width > 0 {
+                    num_inputs = skip_width;
+                }
+                let weights: Vec<f64> = layer.weights().iter().map(V::as_f64).collect();
+                let summary = LayerSummary {
+                    spec: layer.spec(),
+                    num_inputs,
+                    num_outputs: layer.num_outputs(),
+                    activation: layer.activation(),
+                    num_weights: weights.len(),
+                    num_biases: layer.num_params() - weights.len(),
+                    frozen: self.is_frozen(index),
+                    weight_stats: WeightStats::new(&weights),
+                };
+                num_inputs = layer.num_outputs();
+                summary
+            })
+            .collect();
+        ModelSummary {
+            layers,
+            output_skips: self.output_skips,
+        }
+    }
+}
+
+/// What each layer of a read-only network was given and returned
+/// during [MultiLayerPerceptron::forward].
+#[derive(Debug)]
+pub struct ForwardPass {
+    inputs: Vec<Vec<f64>>,
+    outputs: Vec<Vec<f64>>,
+    output: Vec<f64>,
+}
+
+impl ForwardPass {
+    /// The output of the network.
+    pub fn output(&self) -> &[f64] {
+        &self.output
+    }
+}
+
+/// The values of the neurons of a hidden layer, as returned by
+/// [MultiLayerPerceptron::neuron_activations].
+#[derive(Clone, Debug, PartialEq)]
+pub struct NeuronActivations {
+    /// The index of the layer in the network.
+    pub layer: usize,
+    /// The values of the neurons before their activation function.
+    pub pre_activations: Vec<f64>,
+    /// The outputs of the layer.
+    pub activations: Vec<f64>,
+}
+
+/// Whether a layer with the given spec belongs to the same block as the layer before it,
+/// like the normalization and activation layers that follow a dense
+/// layer. Blocks are the unit that dropout and output skips apply to.
+fn continues_block(spec: Option<LayerSpec>) -> bool {
+    matches!(
+        spec,
+        Some(LayerSpec::BatchNorm | LayerSpec::LayerNorm | LayerSpec::Activation(_))
+    )
+}
+
+/// Buffers that [MultiLayerPerceptron::batch_output] can reuse
+/// between calls.
+#[derive(Debug)]
+pub struct BatchBuffers<V: NeuronValue> {
+    outputs: Vec<V>,
+    scratch: Vec<V>,
+    /// Spare buffers for layers that are made of other layers.
+    layer_scratch: Vec<Vec<V>>,
+    /// The outputs of the hidden layers that are fed straight to the
+    /// final layer.
+    skipped: Vec<V>,
+}
+
+impl<V: NeuronValue> Default for BatchBuffers<V> {
+    fn default() -> Self {
+        BatchBuffers {
+            outputs: vec![],
+            scratch: vec![],
+            layer_scratch: vec![],
+            skipped: vec![],
+        }
+    }
+}
+
+/// Randomly zeroes each of the given values with the given
+/// probability. The surviving values are scaled up so that the
+/// expected value of each output is the same as its input, which
+/// means nothing needs to be scaled at inference time.
+fn dropout<V: NeuronValue>(values: Vec<V>, probability: f64) -> Vec<V> {
+    let scale = V::from(1.0 / (1.0 - probability));
+    values
+        .into_iter()
+        .map(|value| {
+            if gen_range(0.0, 1.0) < probability {
+                V::from(0.0)
+            } else {
+                value * scale.clone()
+            }
+        })
+        .collect()
+}
+
+/// A penalty on the size of a network's weights.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Penalty {
+    /// The sum of the absolute values of the weights, which
+    /// tends to push unimportant weights all the way to zero.
+    L1,
+    /// Half the sum of the squares of the weights. With plain
+    /// gradient descent this is the same thing as weight decay,
+    /// i.e. shrinking every weight by `learning_rate * strength`
+    /// of itself on each update.
+    L2,
+}
+
+/// Regularization adds a penalty on a network's weights to its
+/// loss, which discourages it from overfitting its data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Regularization {
+    pub penalty: Penalty,
+    pub strength: f64,
+    /// Whether biases are penalized too. They usually aren't, since
+    /// they don't make the network any more sensitive to its inputs.
+    pub include_biases: bool,
+}
+
+impl Regularization {
+    pub fn new(penalty: Penalty, strength: f64) -> Self {
+        Regularization {
+            penalty,
+            strength,
+            include_biases: false,
+        }
+    }
+
+    pub fn with_biases(mut self) -> Self {
+        self.include_biases = true;
+        self
+    }
+
+    /// Returns the regularization term to add to the given
+    /// network's loss.
+    pub fn loss<V: NeuronValue>(&self, mlp: &MultiLayerPerceptron<V>) -> V {
+        let params = if self.include_biases {
+            mlp.params()
+        } else {
+            mlp.weights()
+        };
+        let mut sum = V::from(0.0);
+        for param in params {
+            sum = sum
+                + match self.penalty {
+                    Penalty::L1 => param.abs(),
+                    Penalty::L2 => param.clone() * param,
+                };
+        }
+        let scale = match self.penalty {
+            Penalty::L1 => self.strength,
+            Penalty::L2 => self.strength * 0.5,
+        };
+        sum * scale.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::{Activation, Dense, ReadOnlyLayer, Residual};
+
+    #[test]
+    fn test_regularization_excludes_biases_by_default() {
+        let mlp = MultiLayerPerceptron::new(2, ActivationType::Sigmoid, vec![3, 1]).read_only();
+        let weights = mlp.weights();
+        assert_eq!(weights.len(), 2 * 3 + 3);
+        assert_eq!(mlp.params().len(), weights.len() + 3 + 1);
+
+        let l1 = Regularization::new(Penalty::L1, 0.1).loss(&mlp);
+        let expected_l1: f64 = weights.iter().map(|w| w.abs()).sum::<f64>() * 0.1;
+        assert!((l1 - expected_l1).abs() < 1e-12);
+
+        let l2 = Regularization::new(Penalty::L2, 0.1)
+            .with_biases()
+            .loss(&mlp);
+        let expected_l2: f64 = mlp.params().iter().map(|w| w * w).sum::<f64>() * 0.05;
+        assert!((l2 - expected_l2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_dropout_only_applies_in_training_mode() {
+        let mut mlp = MultiLayerPerceptron::from_specs(
+            2,
+            ActivationType::Sigmoid,
+            vec![LayerSpec::Dense(16), LayerSpec::Dense(1)],
+        );
+        mlp.set_dropout(0.5);
+        let mut mlp = mlp.read_only();
+        let inputs = vec![0.25, -0.5];
+        let output = mlp.output(&inputs);
+        let num_different = (0..20)
+            .filter(|_| mlp.training_output(std::slice::from_ref(&inputs))[0] != output)
+            .count();
+        assert!(num_different > 0);
+    }
+
+    #[test]
+    fn test_dropout_applies_once_per_block() {
+        let mut mlp = MultiLayerPerceptron::from_specs(
+            2,
+            ActivationType::Sigmoid,
+            vec![
+                LayerSpec::Dense(4),
+                LayerSpec::BatchNorm,
+                LayerSpec::Dense(1),
+            ],
+        );
+        mlp.set_dropout(0.5);
+        // A single mask, after the batch normalization, and none after
+        // the output layer.
+        let masks: Vec<usize> = (0..mlp.num_layers())
+            .filter(|&index| mlp.applies_dropout_after(index))
+            .collect();
+        assert_eq!(masks, [1]);
+
+        let mut mlp = MultiLayerPerceptron::from_specs_with_output_skips(
+            2,
+            ActivationType::Linear,
+            vec![
+                LayerSpec::Dense(4),
+                LayerSpec::Activation(ActivationType::Relu),
+                LayerSpec::Dense(3),
+                LayerSpec::Dense(1),
+            ],
+        );
+        mlp.set_dropout(0.5);
+        // The activation layer belongs to the first block, so it gets
+        // the mask and the skip connection.
+        let masks: Vec<usize> = (0..mlp.num_layers())
+            .filter(|&index| mlp.applies_dropout_after(index))
+            .collect();
+        assert_eq!(masks, [1, 2]);
+        let skip_sources: Vec<usize> = (0..mlp.num_layers() - 1)
+            .filter(|&index| mlp.is_skip_source(index))
+            .collect();
+        assert_eq!(skip_sources, [1, 2]);
+    }
+
+    /// A layer that doubles its inputs, to make sure layers from
+    /// outside the engine can be mixed with built-in ones.
+    #[derive(Debug)]
+    struct Double(usize);
+
+    impl<V: NeuronValue> Layer<V> for Double {
+        fn output(&self, inputs: &[V]) -> Vec<V> {
+            inputs
+                .iter()
+                .map(|input| input.clone() * 2.0.into())
+                .collect()
+        }
+
+        fn params(&self) -> Vec<V> {
+            vec![]
+        }
+
+        fn read_only(&self) -> Box<ReadOnlyLayer> {
+            Box::new(Double(self.0))
+        }
+
+        fn num_outputs(&self) -> usize {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_mixed_layers() {
+        let mlp = MultiLayerPerceptron::from_layers(
+            2,
+            vec![
+                Box::new(Dense::new(2, ActivationType::Linear, 3)),
+                Box::new(Double(3)),
+                Box::new(Activation::new(ActivationType::Relu, 3)),
+                Box::new(Dense::new(3, ActivationType::Tanh, 1)),
+            ],
+        );
+        assert_eq!(mlp.num_outputs(), 1);
+        assert_eq!(
+            mlp.specs(),
+            vec![
+                Some(LayerSpec::Dense(3)),
+                None,
+                Some(LayerSpec::Activation(ActivationType::Relu)),
+                Some(LayerSpec::Dense(1)),
+            ]
+        );
+        let inputs = [0.3, -0.8];
+        let value_output = mlp.output(&inputs.map(Value::from));
+        let f64_output = mlp.read_only().output(&inputs);
+        assert_eq!(value_output[0].as_f64(), f64_output[0]);
+        assert!(mlp.to_model_file(Default::default()).is_err());
+    }
+
+    #[test]
+    fn test_output_skips() {
+        let mut mlp = MultiLayerPerceptron::from_specs_with_output_skips(
+            2,
+            ActivationType::Tanh,
+            vec![
+                LayerSpec::Dense(3),
+                LayerSpec::LayerNorm,
+                LayerSpec::Residual(2),
+                LayerSpec::Dense(4),
+                LayerSpec::Dense(1),
+            ],
+        );
+        // The final layer sees the outputs of the first block, after
+        // its normalization, the residual block and the second dense
+        // layer.
+        assert_eq!(
+            mlp.params().len(),
+            (2 + 1) * 3 + 3 * 2 + 2 * (3 + 1) * 3 + (3 + 1) * 4 + 11
+        );
+        let inputs = [0.3, -0.8];
+        let value_output = mlp.output(&inputs.map(Value::from));
+        let training_output = mlp.training_output(&[inputs.map(Value::from).to_vec()]);
+        let f64_output = mlp.read_only().output(&inputs);
+        assert_eq!(value_output[0].as_f64(), f64_output[0]);
+        assert_eq!(training_output[0][0].as_f64(), f64_output[0]);
+    }
+
+    #[test]
+    fn test_output_skips_train_every_param() {
+        let mlp = MultiLayerPerceptron::from_specs_with_output_skips(
+            2,
+            ActivationType::Tanh,
+            vec![
+                LayerSpec::Dense(4),
+                LayerSpec::LayerNorm,
+                LayerSpec::Dense(3),
+                LayerSpec::LayerNorm,
+                LayerSpec::Dense(1),
+            ],
+        )
+        .read_only();
+        let pass = mlp.forward(&[0.3, -0.8]);
+        let mut param_grads = vec![0.0; mlp.params().len()];
+        mlp.backward(&pass, &[1.0], &mut param_grads).unwrap();
+        assert!(param_grads.iter().all(|&grad| grad != 0.0));
+    }
+
+    #[test]
+    fn test_backward_matches_value_backward() {
+        let mut mlp = MultiLayerPerceptron::from_specs_with_output_skips(
+            3,
+            ActivationType::Tanh,
+            vec![
+                LayerSpec::Dense(4),
+                LayerSpec::BatchNorm,
+                LayerSpec::Residual(2),
+                LayerSpec::LayerNorm,
+                LayerSpec::Activation(ActivationType::Relu),
+                LayerSpec::Dense(3),
+                LayerSpec::Dense(2),
+            ],
+        );
+        // Give the batch norm layer some running statistics.
+        mlp.training_output(&[
+            vec![0.1, 0.2, 0.3].into_iter().map(Value::from).collect(),
+            vec![-0.4, 0.5, 0.9].into_iter().map(Value::from).collect(),
+        ]);
+        let inputs = [0.3, -0.8, 0.5];
+        let output_grads = [0.7, -1.3];
+
+        let value_inputs = inputs.map(Value::from);
+        let value_outputs = mlp.output(&value_inputs);
+        let mut loss = Value::from(0.0);
+        for (output, &grad) in value_outputs.into_iter().zip(&output_grads) {
+            loss = loss + output * grad.into();
+        }
+        loss.backward();
+
+        let read_only = mlp.read_only();
+        let pass = read_only.forward(&inputs);
+        assert_eq!(pass.output(), read_only.output(&inputs));
+        let mut param_grads = vec![0.0; read_only.num_params()];
+        let input_grads = read_only
+            .backward(&pass, &output_grads, &mut param_grads)
+            .unwrap();
+
+        let params = mlp.params();
+        assert_eq!(params.len(), param_grads.len());
+        for (param, grad) in params.iter().zip(&param_grads) {
+            assert!((param.grad() - grad).abs() < 1e-9);
+        }
+        for (input, grad) in value_inputs.iter().zip(&input_grads) {
+            assert!((input.grad() - grad).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_backward_needs_layer_support() {
+        let mlp = MultiLayerPerceptron::from_layers(2, vec![Box::new(Double(2))]).read_only();
+        let pass = mlp.forward(&[1.0, 2.0]);
+        assert_eq!(pass.output(), [2.0, 4.0]);
+        assert_eq!(mlp.backward(&pass, &[1.0, 1.0], &mut []), None);
+    }
+
+    #[test]
+    fn test_trainable_params() {
+        let mut mlp = MultiLayerPerceptron::from_specs(
+            2,
+            ActivationType::Sigmoid,
+            vec![
+                LayerSpec::Dense(3),
+                LayerSpec::LayerNorm,
+                LayerSpec::Dense(1),
+            ],
+        );
+        mlp.set_frozen(0, true);
+        mlp.set_frozen(1, true);
+        mlp.set_frozen(1, false);
+        let mlp = mlp.read_only();
+        assert!(mlp.is_frozen(0));
+        assert!(!mlp.is_frozen(1));
+        let mut expected = vec![false; 9];
+        expected.extend([true; 6 + 4]);
+        assert_eq!(mlp.trainable_params(), expected);
+    }
+
+    #[test]
+    fn test_batch_output_matches_output() {
+        let mlp = MultiLayerPerceptron::from_specs_with_output_skips(
+            2,
+            ActivationType::Sigmoid,
+            vec![
+                LayerSpec::Dense(5),
+                LayerSpec::BatchNorm,
+                LayerSpec::Residual(2),
+                LayerSpec::LayerNorm,
+                LayerSpec::Activation(ActivationType::Relu),
+                LayerSpec::Dense(3),
+                LayerSpec::Dense(2),
+            ],
+        )
+        .read_only();
+        let batch = [0.3, -0.8, 0.1, 0.5, -1.0, 0.0];
+        let mut buffers = BatchBuffers::default();
+        let expected: Vec<f64> = batch
+            .chunks(2)
+            .flat_map(|inputs| mlp.output(inputs))
+            .collect();
+        assert_eq!(mlp.batch_output(&batch, &mut buffers), expected);
+        // Reusing the buffers with a smaller batch still works.
+        assert_eq!(mlp.batch_output(&batch[..2], &mut buffers), &expected[..2]);
+    }
+
+    #[test]
+    fn test_batch_output_reuses_buffers() {
+        let relu = ActivationType::Relu;
+        // A residual block inside another one needs a scratch buffer
+        // of its own.
+        let inner: Box<TrainableLayer> = Box::new(Residual::<Value>::new(
+            3,
+            vec![LayerSpec::Dense(3).build(3, relu)],
+        ));
+        let mlp = MultiLayerPerceptron::from_layers(
+            2,
+            vec![
+                LayerSpec::Dense(3).build(2, relu),
+                Box::new(Residual::new(
+                    3,
+                    vec![inner, LayerSpec::Dense(3).build(3, relu)],
+                )),
+                LayerSpec::Residual(3).build(3, relu),
+                LayerSpec::Dense(1).build(3, relu),
+            ],
+        )
+        .read_only();
+        let batch = [0.3, -0.8, 0.1, 0.5, -1.0, 0.0];
+        let mut buffers = BatchBuffers::default();
+        let expected = mlp.batch_output(&batch, &mut buffers).to_vec();
+        let pointers = |buffers: &BatchBuffers<f64>| {
+            let mut pointers = vec![buffers.outputs.as_ptr(), buffers.scratch.as_ptr()];
+            pointers.extend(buffers.layer_scratch.iter().map(|buffer| buffer.as_ptr()));
+            pointers.sort();
+            pointers
+        };
+        let before = pointers(&buffers);
+        assert_eq!(before.len(), 4);
+        assert_eq!(mlp.batch_output(&batch, &mut buffers), expected);
+        assert_eq!(pointers(&buffers), before);
+    }
+
+    #[test]
+    fn test_neuron_activations() {
+        let mlp = MultiLayerPerceptron::from_specs(
+            2,
+            ActivationType::Tanh,
+            vec![
+                LayerSpec::Dense(4),
+                LayerSpec::LayerNorm,
+                LayerSpec::Residual(1),
+                LayerSpec::Dense(1),
+            ],
+        )
+        .read_only();
+        let activations = mlp.neuron_activations(&[0.4, -0.2]);
+        // The layer norm has no neurons, and the output layer isn't
+        // hidden.
+        assert_eq!(
+            activations
+                .iter()
+                .map(|layer| layer.layer)
+                .collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        let pass = mlp.forward(&[0.4, -0.2]);
+        let dense = &activations[0];
+        assert_eq!(dense.activations, pass.outputs[0]);
+        for (pre_activation, activation) in dense.pre_activations.iter().zip(&dense.activations) {
+            assert_eq!(ActivationType::Tanh.activate(*pre_activation), *activation);
+        }
+        // The residual block's outputs include its inputs.
+        let residual = &activations[1];
+        for ((pre_activation, activation), input) in residual
+            .pre_activations
+            .iter()
+            .zip(&residual.activations)
+            .zip(&pass.inputs[2])
+        {
+            let expected = input + ActivationType::Tanh.activate(*pre_activation);
+            assert!((expected - activation).abs() < 1e-12);
+        }
+    }
 }
@@ -0,0 +1,408 @@
+use macroquad::prelude::*;
+
+use crate::{
+    classifier_2d::{COLORMAP_RANGE, Classifier2D, Datapoint2D, Task, argmax, draw_mesh, softmax},
+    plot::Plot,
+    trainer::{EarlyStopping, Optimizer, StopReason, Trainer},
+};
+
+/// How the outputs of the members of an ensemble are combined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Combination {
+    /// The mean of the outputs.
+    Average,
+    /// For classification, the fraction of members that predict each
+    /// label, so the majority wins. For regression, the median output.
+    Vote,
+}
+
+/// The combined output of an ensemble at a single point.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prediction {
+    /// The combined outputs, which have the same form as a single
+    /// network's. For multi-class classification they're the logarithms
+    /// of the combined probabilities, which a softmax turns back into
+    /// the probabilities.
+    pub outputs: Vec<f64>,
+    /// How much the members disagree, from 0 (not at all) to 1.
+    pub disagreement: f64,
+}
+
+/// Several independently initialized networks that are trained on the
+/// same datapoints, and whose outputs are combined.
+pub struct Ensemble {
+    /// The first member is the primary one, whose details are shown
+    /// when a single network needs to be shown.
+    members: Vec<Trainer>,
+    combination: Combination,
+}
+
+impl Ensemble {
+    pub fn new(members: Vec<Trainer>, combination: Combination) -> Self {
+        assert!(!members.is_empty(), "an ensemble needs at least one member");
+        Ensemble {
+            members,
+            combination,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn primary(&self) -> &Trainer {
+        &self.members[0]
+    }
+
+    pub fn primary_mut(&mut self) -> &mut Trainer {
+        &mut self.members[0]
+    }
+
+    pub fn members(&self) -> &[Trainer] {
+        &self.members
+    }
+
+    pub fn members_mut(&mut self) -> &mut [Trainer] {
+        &mut self.members
+    }
+
+    pub fn combination(&self) -> Combination {
+        self.combination
+    }
+
+    pub fn set_combination(&mut self, combination: Combination) {
+        self.combination = combination;
+    }
+
+    pub fn set_optimizer(&mut self, optimizer: Optimizer) {
+        for member in &mut self.members {
+            member.set_optimizer(optimizer);
+        }
+    }
+
+    pub fn set_early_stopping(&mut self, early_stopping: EarlyStopping) {
+        for member in &mut self.members {
+            member.set_early_stopping(early_stopping);
+        }
+    }
+
+    pub fn set_datapoints(&mut self, datapoints: Vec<Datapoint2D>) {
+        for member in &mut self.members {
+            member.set_datapoints(datapoints.clone());
+        }
+    }
+
+    /// Adds or removes members so there are `len` of them. New members
+    /// are trained the same way as the primary one.
+    pub fn resize<F: FnMut() -> Classifier2D>(&mut self, len: usize, mut make_classifier: F) {
+        assert!(len > 0, "an ensemble needs at least one member");
+        let optimizer = self.primary().optimizer();
+        let early_stopping = self.primary().early_stopping();
+        self.members.truncate(len);
+        while self.members.len() < len {
+            self.members.push(
+                Trainer::new(make_classifier(), optimizer).with_early_stopping(early_stopping),
+            );
+        }
+    }
+
+    /// Starts training a fresh classifier in every member.
+    pub fn replace_classifiers<F: FnMut() -> Classifier2D>(&mut self, mut make_classifier: F) {
+        for member in &mut self.members {
+            member.replace_classifier(make_classifier(), 0);
+        }
+    }
+
+    /// Changes every member's classifier in the same way, recalculating
+    /// their metrics afterwards.
+    pub fn update_classifiers<F: FnMut(&mut Classifier2D)>(&mut self, mut update: F) {
+        for member in &mut self.members {
+            update(member.classifier_mut());
+            member.refresh();
+        }
+    }
+
+    /// Takes a step with every member that hasn't stopped training.
+    /// Returns why the primary member stopped once they all have.
+    pub fn step(&mut self) -> Option<StopReason> {
+        let mut all_stopped = true;
+        for member in &mut self.members {
+            all_stopped &= member.step().is_some();
+        }
+        if all_stopped {
+            self.primary().stop_reason()
+        } else {
+            None
+        }
+    }
+
+    fn task(&self) -> Task {
+        self.primary().classifier().task()
+    }
+
+    /// Combines the outputs of the members at a single point.
+    pub fn combine(&self, outputs: &[Vec<f64>]) -> Prediction {
+        combine(self.task(), self.combination, outputs)
+    }
+
+    /// Returns the loss and accuracy of the combined predictions on the
+    /// training points, or `None` if there aren't any.
+    pub fn loss_and_accuracy(&self) -> Option<(f64, f64)> {
+        let outputs: Vec<Vec<Vec<f64>>> = self
+            .members
+            .iter()
+            .map(|member| member.classifier().outputs())
+            .collect();
+        let task = self.task();
+        let datapoints = self.primary().classifier().datapoints();
+        let mut loss = 0.0;
+        let mut correctly_classified = 0;
+        let mut num_points = 0;
+        for (index, point) in datapoints.iter().enumerate() {
+            if point.validation {
+                continue;
+            }
+            num_points += 1;
+            let member_outputs: Vec<Vec<f64>> = outputs
+                .iter()
+                .map(|outputs| outputs[index].clone())
+                .collect();
+            let output = self.combine(&member_outputs).outputs;
+            let target = point.target.as_f64();
+            loss += task.loss(&output, target);
+            if task.is_correct(&output, target) {
+                correctly_classified += 1;
+            }
+        }
+        if num_points == 0 {
+            return None;
+        }
+        let num_points = num_points as f64;
+        Some((loss / num_points, correctly_classified as f64 / num_points))
+    }
+
+    /// Shades the plot with the combined predictions, fading them to
+    /// white where the members disagree.
+    pub fn draw(&self, plot: &Plot, enable_shading: bool) {
+        let task = self.task();
+        let outputs: Vec<Vec<Vec<f64>>> = self
+            .members
+            .iter()
+            .map(|member| member.classifier().mesh_outputs())
+            .collect();
+        let colors: Vec<Color> = (0..outputs[0].len())
+            .map(|index| {
+                let member_outputs: Vec<Vec<f64>> = outputs
+                    .iter()
+                    .map(|outputs| outputs[index].clone())
+                    .collect();
+                let prediction = self.combine(&member_outputs);
+                let color = task.color(&prediction.outputs, enable_shading);
+                mix(color, WHITE, prediction.disagreement as f32)
+            })
+            .collect();
+        draw_mesh(plot, &colors);
+        self.primary().classifier().draw_datapoints(plot);
+    }
+}
+
+/// Combines the outputs of each member at a single point.
+fn combine(task: Task, combination: Combination, member_outputs: &[Vec<f64>]) -> Prediction {
+    let outputs: Vec<f64> = member_outputs.iter().map(|outputs| outputs[0]).collect();
+    let num_outputs = outputs.len() as f64;
+    let mean = outputs.iter().sum::<f64>() / num_outputs;
+    match task {
+        Task::MultiClass { num_classes } => {
+            combine_classes(num_classes, combination, member_outputs)
+        }
+        Task::Classification => {
+            let output = match combination {
+                Combination::Average => mean,
+                Combination::Vote => {
+                    let red_votes = outputs
+                        .iter()
+                        .filter(|&&output| task.is_correct(&[output], 1.0))
+                        .count();
+                    red_votes as f64 / num_outputs
+                }
+            };
+            // The fraction of members that disagree with the combined
+            // prediction is at most a half.
+            let agrees_with_red = task.is_correct(&[output], 1.0);
+            let dissenters = outputs
+                .iter()
+                .filter(|&&member| task.is_correct(&[member], 1.0) != agrees_with_red)
+                .count();
+            Prediction {
+                outputs: vec![output],
+                disagreement: (2.0 * dissenters as f64 / num_outputs).min(1.0),
+            }
+        }
+        Task::Regression => {
+            let output = match combination {
+                Combination::Average => mean,
+                Combination::Vote => median(&outputs),
+            };
+            let variance = outputs
+                .iter()
+                .map(|output| (output - mean).powi(2))
+                .sum::<f64>()
+                / num_outputs;
+            // A standard deviation of half the colormap's range counts as
+            // complete disagreement.
+            let (min, max) = COLORMAP_RANGE;
+            Prediction {
+                outputs: vec![output],
+                disagreement: (variance.sqrt() / ((max - min) / 2.0)).min(1.0),
+            }
+        }
+    }
+}
+
+/// Like [combine], but for multi-class classification. Averaging
+/// combines the members' probabilities, and voting gives each class the
+/// fraction of members that predict it.
+fn combine_classes(
+    num_classes: usize,
+    combination: Combination,
+    member_outputs: &[Vec<f64>],
+) -> Prediction {
+    let num_members = member_outputs.len() as f64;
+    let predictions: Vec<usize> = member_outputs
+        .iter()
+        .map(|outputs| argmax(outputs))
+        .collect();
+    let mut probabilities = vec![0.0; num_classes];
+    for (outputs, &prediction) in member_outputs.iter().zip(&predictions) {
+        match combination {
+            Combination::Average => {
+                for (probability, member) in probabilities.iter_mut().zip(softmax(outputs)) {
+                    *probability += member / num_members;
+                }
+            }
+            Combination::Vote => probabilities[prediction] += 1.0 / num_members,
+        }
+    }
+    // Unless there's a tie, at most all but one in `num_classes` of the
+    // members disagree with the winner of a vote.
+    let predicted = argmax(&probabilities);
+    let dissenters = predictions
+        .iter()
+        .filter(|&&prediction| prediction != predicted)
+        .count();
+    let max_dissent = 1.0 - 1.0 / num_classes as f64;
+    Prediction {
+        outputs: probabilities.into_iter().map(f64::ln).collect(),
+        disagreement: (dissenters as f64 / num_members / max_dissent).min(1.0),
+    }
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let middle = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[middle - 1] + sorted[middle]) / 2.0
+    } else {
+        sorted[middle]
+    }
+}
+
+/// Returns a color `amount` of the way from `from` to `to`.
+fn mix(from: Color, to: Color, amount: f32) -> Color {
+    Color::new(
+        from.r + (to.r - from.r) * amount,
+        from.g + (to.g - from.g) * amount,
+        from.b + (to.b - from.b) * amount,
+        from.a + (to.a - from.a) * amount,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classifier_2d::{Label2D, Weights2D};
+
+    /// The outputs of members that each have a single output.
+    fn scalars(outputs: &[f64]) -> Vec<Vec<f64>> {
+        outputs.iter().map(|&output| vec![output]).collect()
+    }
+
+    #[test]
+    fn test_combine_classification() {
+        let task = Task::Classification;
+        let average = combine(task, Combination::Average, &scalars(&[0.9, 0.8, 0.1]));
+        assert!((average.outputs[0] - 0.6).abs() < 1e-12);
+        assert!((average.disagreement - 2.0 / 3.0).abs() < 1e-12);
+
+        let vote = combine(task, Combination::Vote, &scalars(&[0.9, 0.6, 0.1, 0.2]));
+        assert_eq!(vote.outputs, [0.5]);
+        assert_eq!(vote.disagreement, 1.0);
+
+        let unanimous = combine(task, Combination::Vote, &scalars(&[0.9, 0.6]));
+        assert_eq!(unanimous.outputs, [1.0]);
+        assert_eq!(unanimous.disagreement, 0.0);
+    }
+
+    #[test]
+    fn test_combine_multi_class() {
+        let task = Task::MultiClass { num_classes: 3 };
+        let outputs = vec![
+            vec![2.0, 0.0, 0.0],
+            vec![0.0, 0.0, 2.0],
+            vec![3.0, 0.0, 0.0],
+        ];
+        let vote = combine(task, Combination::Vote, &outputs);
+        let probabilities = softmax(&vote.outputs);
+        assert!((probabilities[0] - 2.0 / 3.0).abs() < 1e-12);
+        assert!((probabilities[2] - 1.0 / 3.0).abs() < 1e-12);
+        assert!((vote.disagreement - 0.5).abs() < 1e-12);
+
+        let average = combine(task, Combination::Average, &outputs);
+        let probabilities = softmax(&average.outputs);
+        assert!((probabilities.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(task.is_correct(&average.outputs, 0.0));
+    }
+
+    #[test]
+    fn test_combine_regression() {
+        let task = Task::Regression;
+        let vote = combine(task, Combination::Vote, &scalars(&[0.5, -0.5, 0.4]));
+        assert_eq!(vote.outputs, [0.4]);
+        let agreement = combine(task, Combination::Average, &scalars(&[0.2, 0.2]));
+        assert_eq!(agreement.outputs, [0.2]);
+        assert_eq!(agreement.disagreement, 0.0);
+    }
+
+    #[test]
+    fn test_members_train_independently() {
+        let datapoints = vec![
+            Datapoint2D::new((-10, 9), Label2D::Red),
+            Datapoint2D::new((5, -5), Label2D::Blue),
+        ];
+        let mut ensemble = Ensemble::new(
+            (0..3)
+                .map(|_| {
+                    let weights = Weights2D::new(2, vec![], false);
+                    let classifier = Classifier2D::new(datapoints.clone(), weights);
+                    Trainer::new(classifier, Optimizer::Sgd { learning_rate: 0.5 })
+                })
+                .collect(),
+            Combination::Average,
+        );
+        for _ in 0..200 {
+            ensemble.step();
+        }
+        let params: Vec<Vec<f64>> = ensemble
+            .members()
+            .iter()
+            .map(|member| member.classifier().param_values())
+            .collect();
+        assert_ne!(params[0], params[1]);
+        assert_eq!(ensemble.loss_and_accuracy().unwrap().1, 1.0);
+    }
+}
@@ -0,0 +1,449 @@
+use std::{collections::BTreeSet, fmt::Write};
+
+use crate::{
+    engine::{ActivationType, MultiLayerPerceptron},
+    layer::NORM_EPSILON,
+    model_file::{LayerFile, ModelFileError, ModelMetadata},
+};
+
+/// The helper functions that the generated `predict` function may call,
+/// which are only included in the module if they're needed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Helper {
+    Dense,
+    Activate,
+    Residual,
+    BatchNorm,
+    LayerNorm,
+    Activation(ActivationType),
+}
+
+/// The body of the function for the given activation, which does
+/// exactly the same floating point operations as
+/// [ActivationType::activate].
+fn activation_body(activation: ActivationType) -> &'static str {
+    match activation {
+        ActivationType::Sigmoid => "1.0 / (1.0 + (x * -1.0).exp())",
+        ActivationType::Tanh => "2.0 / (1.0 + (x * -2.0).exp()) - 1.0",
+        ActivationType::Relu => "x.max(0.0)",
+        ActivationType::Linear => "x",
+    }
+}
+
+/// Builds the generated module piece by piece.
+#[derive(Default)]
+struct Generator {
+    constants: String,
+    body: String,
+    helpers: BTreeSet<Helper>,
+}
+
+impl Generator {
+    /// Adds the code for a layer that reads the array named `input`, of
+    /// the given width, to the body of `predict`, returning the name and
+    /// width of the array it puts its outputs in.
+    fn layer(
+        &mut self,
+        layer: &LayerFile,
+        name: &str,
+        input_name: &str,
+        width: usize,
+    ) -> (String, usize) {
+        let prefix = name.to_uppercase();
+        let output = name.to_owned();
+        // The inputs of `predict` are already a reference.
+        let input = if input_name == "inputs" {
+            input_name.to_owned()
+        } else {
+            format!("&{input_name}")
+        };
+        match layer {
+            LayerFile::Dense {
+                activation,
+                weights,
+                biases,
+            } => {
+                self.helpers.insert(Helper::Dense);
+                self.helpers.insert(Helper::Activation(*activation));
+                self.matrix(&format!("{prefix}_WEIGHTS"), weights, width);
+                self.array(&format!("{prefix}_BIASES"), biases);
+                self.line(&format!(
+                    "let {output} = dense(&{prefix}_WEIGHTS, &{prefix}_BIASES, {input}, {});",
+                    activation.name()
+                ));
+                (output, biases.len())
+            }
+            LayerFile::Activation { activation, .. } => {
+                self.helpers.insert(Helper::Activate);
+                self.helpers.insert(Helper::Activation(*activation));
+                self.line(&format!(
+                    "let {output} = activate({input}, {});",
+                    activation.name()
+                ));
+                (output, width)
+            }
+            LayerFile::BatchNorm {
+                scale,
+                shift,
+                running_mean,
+                running_variance,
+            } => {
+                self.helpers.insert(Helper::BatchNorm);
+                self.array(&format!("{prefix}_SCALE"), scale);
+                self.array(&format!("{prefix}_SHIFT"), shift);
+                self.array(&format!("{prefix}_RUNNING_MEAN"), running_mean);
+                self.array(&format!("{prefix}_RUNNING_VARIANCE"), running_variance);
+                self.line(&format!(
+                    "let {output} = batch_norm(&{prefix}_SCALE, &{prefix}_SHIFT, \
+                     &{prefix}_RUNNING_MEAN, &{prefix}_RUNNING_VARIANCE, {input});"
+                ));
+                (output, width)
+            }
+            LayerFile::LayerNorm { scale, shift } => {
+                self.helpers.insert(Helper::LayerNorm);
+                self.array(&format!("{prefix}_SCALE"), scale);
+                self.array(&format!("{prefix}_SHIFT"), shift);
+                self.line(&format!(
+                    "let {output} = layer_norm(&{prefix}_SCALE, &{prefix}_SHIFT, {input});"
+                ));
+                (output, width)
+            }
+            LayerFile::Residual { layers } => {
+                self.helpers.insert(Helper::Residual);
+                let mut inner = (input_name.to_owned(), width);
+                for (index, layer) in layers.iter().enumerate() {
+                    inner = self.layer(layer, &format!("{name}_{index}"), &inner.0, inner.1);
+                }
+                self.line(&format!("let {output} = residual({input}, &{});", inner.0));
+                (output, width)
+            }
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        writeln!(self.body, "    {line}").unwrap();
+    }
+
+    fn array(&mut self, name: &str, values: &[f64]) {
+        writeln!(
+            self.constants,
+            "const {name}: [f64; {}] = {};",
+            values.len(),
+            array_literal(values)
+        )
+        .unwrap();
+    }
+
+    fn matrix(&mut self, name: &str, rows: &[Vec<f64>], width: usize) {
+        writeln!(
+            self.constants,
+            "const {name}: [[f64; {width}]; {}] = [",
+            rows.len()
+        )
+        .unwrap();
+        for row in rows {
+            writeln!(self.constants, "    {},", array_literal(row)).unwrap();
+        }
+        writeln!(self.constants, "];").unwrap();
+    }
+}
+
+/// Formats a number so that it parses back to exactly the same value.
+fn float_literal(value: f64) -> String {
+    if value.is_nan() {
+        "f64::NAN".to_owned()
+    } else if value.is_infinite() {
+        if value > 0.0 {
+            "f64::INFINITY".to_owned()
+        } else {
+            "f64::NEG_INFINITY".to_owned()
+        }
+    } else {
+        // Debug formatting always includes a decimal point or exponent,
+        // so the literal is a float, and round-trips exactly.
+        format!("{value:?}")
+    }
+}
+
+fn array_literal(values: &[f64]) -> String {
+    let values: Vec<String> = values.iter().map(|&value| float_literal(value)).collect();
+    format!("[{}]", values.join(", "))
+}
+
+fn helper_source(helper: Helper) -> String {
+    match helper {
+        // These add things up in the same order as the original network,
+        // so the results are exactly the same.
+        Helper::Dense => "fn dense<const I: usize, const O: usize>(
+    weights: &[[f64; I]; O],
+    biases: &[f64; O],
+    inputs: &[f64; I],
+    activation: fn(f64) -> f64,
+) -> [f64; O] {
+    let mut outputs = *biases;
+    for (output, weights) in outputs.iter_mut().zip(weights) {
+        for (weight, input) in weights.iter().zip(inputs) {
+            *output += weight * input;
+        }
+        *output = activation(*output);
+    }
+    outputs
+}
+"
+        .to_owned(),
+        Helper::Activate => "fn activate<const N: usize>(inputs: &[f64; N], activation: fn(f64) -> f64) -> [f64; N] {
+    inputs.map(activation)
+}
+"
+        .to_owned(),
+        Helper::Residual => "fn residual<const N: usize>(inputs: &[f64; N], outputs: &[f64; N]) -> [f64; N] {
+    let mut sums = *inputs;
+    for (sum, output) in sums.iter_mut().zip(outputs) {
+        *sum += output;
+    }
+    sums
+}
+"
+        .to_owned(),
+        Helper::BatchNorm => format!(
+            "fn batch_norm<const N: usize>(
+    scale: &[f64; N],
+    shift: &[f64; N],
+    running_mean: &[f64; N],
+    running_variance: &[f64; N],
+    inputs: &[f64; N],
+) -> [f64; N] {{
+    let mut outputs = [0.0; N];
+    for i in 0..N {{
+        let inv_std = (running_variance[i] + {}).powf(-0.5);
+        outputs[i] = (inputs[i] - running_mean[i]) * inv_std * scale[i] + shift[i];
+    }}
+    outputs
+}}
+",
+            float_literal(NORM_EPSILON)
+        ),
+        Helper::LayerNorm => format!(
+            "fn layer_norm<const N: usize>(scale: &[f64; N], shift: &[f64; N], inputs: &[f64; N]) -> [f64; N] {{
+    let inv_len = 1.0 / N as f64;
+    let mut sum = 0.0;
+    for input in inputs {{
+        sum += input;
+    }}
+    let mean = sum * inv_len;
+    let mut squared_sum = 0.0;
+    for input in inputs {{
+        let diff = input - mean;
+        squared_sum += diff * diff;
+    }}
+    let inv_std = (squared_sum * inv_len + {}).powf(-0.5);
+    let mut outputs = [0.0; N];
+    for i in 0..N {{
+        outputs[i] = (inputs[i] - mean) * inv_std * scale[i] + shift[i];
+    }}
+    outputs
+}}
+",
+            float_literal(NORM_EPSILON)
+        ),
+        Helper::Activation(activation) => format!(
+            "fn {}(x: f64) -> f64 {{
+    {}
+}}
+",
+            activation.name(),
+            activation_body(activation)
+        ),
+    }
+}
+
+/// Generates a self-contained Rust module that computes the same outputs
+/// as the given network, with its parameters in constant arrays and an
+/// allocation-free `predict` function. The module has a test checking
+/// that `predict` gives exactly the same outputs as the network for each
+/// of `test_inputs`. Each line of `description` becomes a line of the
+/// module's doc comment.
+///
+/// This fails if any of the network's layers can't be saved, since the
+/// code is generated from the layers' saved representation.
+pub fn to_rust_source(
+    mlp: &MultiLayerPerceptron<f64>,
+    description: &str,
+    test_inputs: &[Vec<f64>],
+) -> Result<String, ModelFileError> {
+    let model = mlp.to_model_file(ModelMetadata::default())?;
+    let num_inputs = mlp.num_inputs();
+    let num_outputs = mlp.num_outputs();
+    let mut generator = Generator::default();
+
+    let mut current = ("inputs".to_owned(), num_inputs);
+    if let Some((final_layer, hidden_layers)) = model.layers.split_last() {
+        // With skip connections, the final layer doesn't see the outputs
+        // of any hidden layers after the last one that's connected to it.
+        let num_used_layers = match (0..hidden_layers.len())
+            .rev()
+            .find(|&index| mlp.is_skip_source(index))
+        {
+            Some(last_source) => last_source + 1,
+            None => hidden_layers.len(),
+        };
+        let mut skipped = vec![];
+        for (index, layer) in hidden_layers[..num_used_layers].iter().enumerate() {
+            current = generator.layer(layer, &format!("layer_{index}"), &current.0, current.1);
+            if mlp.is_skip_source(index) {
+                skipped.push(current.clone());
+            }
+        }
+        if !skipped.is_empty() {
+            let skip_width: usize = skipped.iter().map(|(_, width)| width).sum();
+            generator.line(&format!("let mut skipped = [0.0; {skip_width}];"));
+            let mut start = 0;
+            for (name, width) in skipped {
+                generator.line(&format!(
+                    "skipped[{start}..{}].copy_from_slice(&{name});",
+                    start + width
+                ));
+                start += width;
+            }
+            current = ("skipped".to_owned(), skip_width);
+        }
+        let index = hidden_layers.len();
+        current = generator.layer(
+            final_layer,
+            &format!("layer_{index}"),
+            &current.0,
+            current.1,
+        );
+    }
+
+    let mut source = String::new();
+    for line in description.lines() {
+        writeln!(source, "//! {line}").unwrap();
+    }
+    if !description.is_empty() {
+        writeln!(source, "//!").unwrap();
+    }
+    writeln!(
+        source,
+        "//! Generated by neural-net-fun. `predict` doesn't allocate, and gives
+//! exactly the same outputs as the network it was generated from.
+
+pub const NUM_INPUTS: usize = {num_inputs};
+
+pub const NUM_OUTPUTS: usize = {num_outputs};
+"
+    )
+    .unwrap();
+    source.push_str(&generator.constants);
+    let outputs = if current.0 == "inputs" {
+        "*inputs".to_owned()
+    } else {
+        current.0
+    };
+    writeln!(
+        source,
+        "
+pub fn predict(inputs: &[f64; NUM_INPUTS]) -> [f64; NUM_OUTPUTS] {{
+{}    {outputs}
+}}",
+        generator.body
+    )
+    .unwrap();
+    for helper in &generator.helpers {
+        writeln!(source, "\n{}", helper_source(*helper).trim_end()).unwrap();
+    }
+
+    writeln!(
+        source,
+        "
+#[cfg(test)]
+mod tests {{
+    use super::*;
+
+    /// Inputs, and the outputs the original network gave for them.
+    const CASES: [([f64; NUM_INPUTS], [f64; NUM_OUTPUTS]); {}] = [",
+        test_inputs.len()
+    )
+    .unwrap();
+    for inputs in test_inputs {
+        writeln!(
+            source,
+            "        ({}, {}),",
+            array_literal(inputs),
+            array_literal(&mlp.output(inputs))
+        )
+        .unwrap();
+    }
+    writeln!(
+        source,
+        "    ];
+
+    #[test]
+    fn predict_matches_original_network() {{
+        for (inputs, outputs) in CASES {{
+            assert_eq!(predict(&inputs), outputs);
+        }}
+    }}
+}}"
+    )
+    .unwrap();
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use crate::layer::LayerSpec;
+
+    #[test]
+    fn test_generated_module_matches_network() {
+        let mlp = MultiLayerPerceptron::from_specs_with_output_skips(
+            3,
+            ActivationType::Tanh,
+            vec![
+                LayerSpec::Dense(5),
+                LayerSpec::LayerNorm,
+                LayerSpec::BatchNorm,
+                LayerSpec::Residual(2),
+                LayerSpec::Activation(ActivationType::Relu),
+                LayerSpec::Dense(2),
+            ],
+        )
+        .with_output_activation(ActivationType::Sigmoid)
+        .read_only();
+        let test_inputs: Vec<Vec<f64>> = (0..10)
+            .map(|i| vec![i as f64 / 3.0 - 1.5, (i * i) as f64 / 40.0, -0.7])
+            .collect();
+        let source = to_rust_source(&mlp, "A test network.", &test_inputs).unwrap();
+        assert!(source.starts_with("//! A test network.\n"));
+
+        // Compile the generated module's tests and run them.
+        let dir =
+            std::env::temp_dir().join(format!("neural-net-fun-export-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source_path = dir.join("model.rs");
+        let binary_path = dir.join("model_tests");
+        std::fs::write(&source_path, &source).unwrap();
+        let compiled = Command::new(std::env::var("RUSTC").unwrap_or("rustc".to_owned()))
+            .args(["--edition", "2021", "--test", "-D", "warnings", "-o"])
+            .arg(&binary_path)
+            .arg(&source_path)
+            .output()
+            .unwrap();
+        assert!(
+            compiled.status.success(),
+            "{}\n{source}",
+            String::from_utf8_lossy(&compiled.stderr)
+        );
+        let ran = Command::new(&binary_path).output().unwrap();
+        assert!(
+            ran.status.success(),
+            "{}",
+            String::from_utf8_lossy(&ran.stdout)
+        );
+        assert!(String::from_utf8_lossy(&ran.stdout).contains("1 passed"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
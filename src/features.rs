@@ -0,0 +1,260 @@
+use std::f64::consts::PI;
+
+use crate::{engine::SplitMix64, value::Value};
+
+/// A single input to the network, computed from the (normalized)
+/// coordinates of a point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feature {
+    X,
+    Y,
+    XSquared,
+    YSquared,
+    XTimesY,
+    SinX,
+    CosX,
+    SinY,
+    CosY,
+    /// A random Fourier feature, `cos(x_frequency * x + y_frequency * y + phase)`.
+    /// Enough of these approximate a Gaussian kernel.
+    Fourier {
+        x_frequency: f64,
+        y_frequency: f64,
+        phase: f64,
+    },
+}
+
+impl Feature {
+    pub fn value(&self, x: f64, y: f64) -> f64 {
+        match self {
+            Feature::X => x,
+            Feature::Y => y,
+            Feature::XSquared => x * x,
+            Feature::YSquared => y * y,
+            Feature::XTimesY => x * y,
+            // The coordinates are roughly within (-1, 1), so this
+            // covers about one period.
+            Feature::SinX => (PI * x).sin(),
+            Feature::CosX => (PI * x).cos(),
+            Feature::SinY => (PI * y).sin(),
+            Feature::CosY => (PI * y).cos(),
+            Feature::Fourier {
+                x_frequency,
+                y_frequency,
+                phase,
+            } => (x_frequency * x + y_frequency * y + phase).cos(),
+        }
+    }
+
+    /// Returns the same thing as [Self::value], as part of an expression
+    /// graph, so the gradient with respect to the coordinates can be found.
+    pub fn differentiable_value(&self, x: &Value, y: &Value) -> Value {
+        let scaled = |value: &Value, scale: f64| value.clone() * scale.into();
+        match self {
+            Feature::X => x.clone(),
+            Feature::Y => y.clone(),
+            Feature::XSquared => x.clone() * x.clone(),
+            Feature::YSquared => y.clone() * y.clone(),
+            Feature::XTimesY => x.clone() * y.clone(),
+            Feature::SinX => scaled(x, PI).sin(),
+            Feature::CosX => scaled(x, PI).cos(),
+            Feature::SinY => scaled(y, PI).sin(),
+            Feature::CosY => scaled(y, PI).cos(),
+            Feature::Fourier {
+                x_frequency,
+                y_frequency,
+                phase,
+            } => (scaled(x, *x_frequency) + scaled(y, *y_frequency) + Value::from(*phase)).cos(),
+        }
+    }
+
+    /// Returns the partial derivatives of [Self::value] with respect to
+    /// `x` and `y`.
+    pub fn gradient(&self, x: f64, y: f64) -> (f64, f64) {
+        match self {
+            Feature::X => (1.0, 0.0),
+            Feature::Y => (0.0, 1.0),
+            Feature::XSquared => (2.0 * x, 0.0),
+            Feature::YSquared => (0.0, 2.0 * y),
+            Feature::XTimesY => (y, x),
+            Feature::SinX => (PI * (PI * x).cos(), 0.0),
+            Feature::CosX => (-PI * (PI * x).sin(), 0.0),
+            Feature::SinY => (0.0, PI * (PI * y).cos()),
+            Feature::CosY => (0.0, -PI * (PI * y).sin()),
+            Feature::Fourier {
+                x_frequency,
+                y_frequency,
+                phase,
+            } => {
+                let sin = (x_frequency * x + y_frequency * y + phase).sin();
+                (-x_frequency * sin, -y_frequency * sin)
+            }
+        }
+    }
+}
+
+/// The features that are fed to the network in place of the raw
+/// coordinates, which lets even a network without hidden layers
+/// separate things like circles.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Features(Vec<Feature>);
+
+impl Default for Features {
+    /// Just the coordinates.
+    fn default() -> Self {
+        Features(vec![Feature::X, Feature::Y])
+    }
+}
+
+impl Features {
+    /// Adds x², y² and xy.
+    pub fn with_polynomial(mut self) -> Self {
+        self.0
+            .extend([Feature::XSquared, Feature::YSquared, Feature::XTimesY]);
+        self
+    }
+
+    /// Adds the sine and cosine of each coordinate.
+    pub fn with_trigonometric(mut self) -> Self {
+        self.0
+            .extend([Feature::SinX, Feature::CosX, Feature::SinY, Feature::CosY]);
+        self
+    }
+
+    /// Adds `count` random Fourier features whose frequencies have the
+    /// given standard deviation. The features are always the same for
+    /// the same arguments, so models trained with them can be reloaded.
+    pub fn with_random_fourier(mut self, count: usize, scale: f64) -> Self {
+        let mut rng = SplitMix64::new(count as u64);
+        for _ in 0..count {
+            let x_frequency = rng.next_normal() * scale;
+            let y_frequency = rng.next_normal() * scale;
+            let phase = rng.next_f64() * 2.0 * PI;
+            self.0.push(Feature::Fourier {
+                x_frequency,
+                y_frequency,
+                phase,
+            });
+        }
+        self
+    }
+
+    /// The number of features, i.e. the number of inputs of the network.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the features of the given point.
+    pub fn expand(&self, x: f64, y: f64) -> Vec<f64> {
+        let mut features = Vec::with_capacity(self.len());
+        self.expand_into(x, y, &mut features);
+        features
+    }
+
+    /// Appends the features of the given point to `features`.
+    pub fn expand_into(&self, x: f64, y: f64, features: &mut Vec<f64>) {
+        features.extend(self.0.iter().map(|feature| feature.value(x, y)));
+    }
+
+    /// Returns the features of the given point as part of an expression
+    /// graph. See [Feature::differentiable_value].
+    pub fn expand_values(&self, x: &Value, y: &Value) -> Vec<Value> {
+        self.0
+            .iter()
+            .map(|feature| feature.differentiable_value(x, y))
+            .collect()
+    }
+
+    /// Converts the gradient of something with respect to the features
+    /// of the given point into its gradient with respect to the point
+    /// itself, using the chain rule.
+    pub fn coordinate_gradient(&self, x: f64, y: f64, feature_grads: &[f64]) -> (f64, f64) {
+        self.0
+            .iter()
+            .zip(feature_grads)
+            .fold((0.0, 0.0), |(x_grad, y_grad), (feature, grad)| {
+                let (dx, dy) = feature.gradient(x, y);
+                (x_grad + grad * dx, y_grad + grad * dy)
+            })
+    }
+
+    /// Returns a short description of the features, e.g. `x y x² y² xy`.
+    pub fn description(&self) -> String {
+        let mut names = vec![];
+        let mut num_fourier = 0;
+        for feature in &self.0 {
+            names.push(match feature {
+                Feature::X => "x",
+                Feature::Y => "y",
+                Feature::XSquared => "x²",
+                Feature::YSquared => "y²",
+                Feature::XTimesY => "xy",
+                Feature::SinX => "sin(x)",
+                Feature::CosX => "cos(x)",
+                Feature::SinY => "sin(y)",
+                Feature::CosY => "cos(y)",
+                Feature::Fourier { .. } => {
+                    num_fourier += 1;
+                    continue;
+                }
+            });
+        }
+        let mut description = names.join(" ");
+        if num_fourier > 0 {
+            description.push_str(&format!(" +{num_fourier} RFF"));
+        }
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let features = Features::default().with_polynomial();
+        assert_eq!(features.len(), 5);
+        assert_eq!(features.expand(2.0, 3.0), vec![2.0, 3.0, 4.0, 9.0, 6.0]);
+        assert_eq!(features.description(), "x y x² y² xy");
+    }
+
+    #[test]
+    fn test_gradient_matches_expression_graph() {
+        let features = Features::default()
+            .with_polynomial()
+            .with_trigonometric()
+            .with_random_fourier(4, 2.0);
+        let (x, y) = (0.3, -0.6);
+        let x_value = Value::from(x);
+        let y_value = Value::from(y);
+        let values = features.expand_values(&x_value, &y_value);
+        let mut sum = values
+            .iter()
+            .cloned()
+            .reduce(|sum, value| sum + value)
+            .unwrap();
+        sum.backward();
+        for (value, expected) in values.iter().zip(features.expand(x, y)) {
+            assert!((value.as_f64() - expected).abs() < 1e-12);
+        }
+        let (x_grad, y_grad) = features.coordinate_gradient(x, y, &vec![1.0; features.len()]);
+        assert!((x_value.grad() - x_grad).abs() < 1e-9);
+        assert!((y_value.grad() - y_grad).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_random_fourier_features_are_reproducible() {
+        let features = Features::default().with_random_fourier(8, PI);
+        assert_eq!(features, Features::default().with_random_fourier(8, PI));
+        assert_eq!(features.len(), 10);
+        assert_eq!(features.description(), "x y +8 RFF");
+        for value in features.expand(0.3, -0.7) {
+            assert!((-1.0..=1.0).contains(&value));
+        }
+    }
+}
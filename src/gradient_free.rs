@@ -0,0 +1,266 @@
+use crate::engine::SplitMix64;
+
+/// The smallest and largest step sizes that the adaptive methods will
+/// use, so a run of bad luck can't make them stall or blow up.
+const STEP_SIZE_RANGE: (f64, f64) = (1e-8, 10.0);
+
+/// How slowly the step size of [GradientFreeMethod::OnePlusLambda]
+/// adapts to how often its offspring succeed.
+const SUCCESS_RULE_DAMPING: f64 = 3.0;
+
+/// An optimizer that only needs to evaluate the loss, not its gradient,
+/// which makes it very slow compared to gradient descent on anything but
+/// the smallest networks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GradientFreeMethod {
+    /// Tries a single random change to the parameters, and keeps it if
+    /// it lowers the loss.
+    HillClimbing,
+    /// The (1+λ) evolution strategy: makes `lambda` random changes,
+    /// keeps the best one if it lowers the loss, and adapts the size of
+    /// the changes using the 1/5th success rule.
+    OnePlusLambda { lambda: usize },
+    /// A lightweight CMA-ES, which samples `population` changes from a
+    /// normal distribution and moves towards a weighted average of the
+    /// best half of them. Only the diagonal of the covariance matrix is
+    /// learned, so each parameter gets its own step size, and the
+    /// overall step size is adapted from the path the mean takes.
+    CmaEsLite { population: usize },
+}
+
+impl GradientFreeMethod {
+    pub fn name(&self) -> String {
+        match self {
+            GradientFreeMethod::HillClimbing => "Hill climbing".to_owned(),
+            GradientFreeMethod::OnePlusLambda { lambda } => format!("(1+{lambda})-ES"),
+            GradientFreeMethod::CmaEsLite { population } => format!("CMA-ES-lite ({population})"),
+        }
+    }
+}
+
+/// The state that gradient-free optimizers keep between steps.
+#[derive(Clone, Debug)]
+pub struct GradientFreeSearch {
+    method: GradientFreeMethod,
+    rng: SplitMix64,
+    /// The standard deviation of the random changes.
+    step_size: f64,
+    /// The variance of each parameter's changes, relative to the step
+    /// size. Only used by CMA-ES-lite.
+    variances: Vec<f64>,
+    /// The evolution path used to adapt the step size. Only used by
+    /// CMA-ES-lite.
+    path: Vec<f64>,
+}
+
+impl GradientFreeSearch {
+    pub fn new(method: GradientFreeMethod, step_size: f64, seed: u64) -> Self {
+        GradientFreeSearch {
+            method,
+            rng: SplitMix64::new(seed),
+            step_size,
+            variances: vec![],
+            path: vec![],
+        }
+    }
+
+    pub fn method(&self) -> GradientFreeMethod {
+        self.method
+    }
+
+    /// The current standard deviation of the random changes.
+    pub fn step_size(&self) -> f64 {
+        self.step_size
+    }
+
+    /// Updates the parameters, whose loss is `loss`, by evaluating the
+    /// loss of other parameters with `evaluate`. Returns the number of
+    /// times `evaluate` was called.
+    pub fn step<F: FnMut(&[f64]) -> f64>(
+        &mut self,
+        params: &mut [f64],
+        loss: f64,
+        mut evaluate: F,
+    ) -> usize {
+        match self.method {
+            GradientFreeMethod::HillClimbing => {
+                let candidate = self.perturb(params);
+                if evaluate(&candidate) < loss {
+                    params.copy_from_slice(&candidate);
+                }
+                1
+            }
+            GradientFreeMethod::OnePlusLambda { lambda } => {
+                let mut best: Option<(Vec<f64>, f64)> = None;
+                for _ in 0..lambda {
+                    let candidate = self.perturb(params);
+                    let candidate_loss = evaluate(&candidate);
+                    if best
+                        .as_ref()
+                        .is_none_or(|(_, best_loss)| candidate_loss < *best_loss)
+                    {
+                        best = Some((candidate, candidate_loss));
+                    }
+                }
+                let succeeded = match best {
+                    Some((candidate, candidate_loss)) if candidate_loss < loss => {
+                        params.copy_from_slice(&candidate);
+                        true
+                    }
+                    _ => false,
+                };
+                // Grow the step size if more than a fifth of the steps
+                // succeed, and shrink it otherwise.
+                let success = if succeeded { 1.0 } else { 0.0 };
+                self.adapt_step_size(((success - 0.2) / SUCCESS_RULE_DAMPING).exp());
+                lambda
+            }
+            GradientFreeMethod::CmaEsLite { population } => {
+                self.cma_es_step(params, population, &mut evaluate);
+                population
+            }
+        }
+    }
+
+    /// Returns the parameters plus normally distributed noise with a
+    /// standard deviation of the step size.
+    fn perturb(&mut self, params: &[f64]) -> Vec<f64> {
+        params
+            .iter()
+            .map(|param| param + self.step_size * self.rng.next_normal())
+            .collect()
+    }
+
+    fn adapt_step_size(&mut self, factor: f64) {
+        let (min, max) = STEP_SIZE_RANGE;
+        self.step_size = (self.step_size * factor).clamp(min, max);
+    }
+
+    fn cma_es_step<F: FnMut(&[f64]) -> f64>(
+        &mut self,
+        params: &mut [f64],
+        population: usize,
+        evaluate: &mut F,
+    ) {
+        let n = params.len();
+        if self.variances.len() != n {
+            self.variances = vec![1.0; n];
+            self.path = vec![0.0; n];
+        }
+        let n_f64 = n as f64;
+
+        // The best half of the population is recombined, with weights
+        // that favor the better ones.
+        let num_parents = (population / 2).max(1);
+        let mut weights: Vec<f64> = (0..num_parents)
+            .map(|rank| ((num_parents as f64 + 0.5).ln() - (rank as f64 + 1.0).ln()).max(0.0))
+            .collect();
+        let weight_sum: f64 = weights.iter().sum();
+        for weight in &mut weights {
+            *weight /= weight_sum;
+        }
+        let mu_eff = 1.0 / weights.iter().map(|weight| weight * weight).sum::<f64>();
+        let c_sigma = (mu_eff + 2.0) / (n_f64 + mu_eff + 5.0);
+        let d_sigma =
+            1.0 + 2.0 * (((mu_eff - 1.0) / (n_f64 + 1.0)).sqrt() - 1.0).max(0.0) + c_sigma;
+        // The learning rate of the diagonal covariance, which can be
+        // larger than that of the full matrix since there's less to learn.
+        let c_mu = ((n_f64 + 2.0) / 3.0 * 2.0 * (mu_eff - 2.0 + 1.0 / mu_eff)
+            / ((n_f64 + 2.0).powi(2) + mu_eff))
+            .clamp(0.0, 1.0);
+        // The expected length of a vector of standard normal samples.
+        let expected_norm =
+            n_f64.sqrt() * (1.0 - 1.0 / (4.0 * n_f64) + 1.0 / (21.0 * n_f64 * n_f64));
+
+        let mut samples: Vec<(Vec<f64>, Vec<f64>, f64)> = (0..population)
+            .map(|_| {
+                let z: Vec<f64> = (0..n).map(|_| self.rng.next_normal()).collect();
+                let y: Vec<f64> = z
+                    .iter()
+                    .zip(&self.variances)
+                    .map(|(z, variance)| z * variance.sqrt())
+                    .collect();
+                let candidate: Vec<f64> = params
+                    .iter()
+                    .zip(&y)
+                    .map(|(param, y)| param + self.step_size * y)
+                    .collect();
+                let candidate_loss = evaluate(&candidate);
+                (z, y, candidate_loss)
+            })
+            .collect();
+        samples.sort_by(|a, b| a.2.total_cmp(&b.2));
+
+        let mut z_mean = vec![0.0; n];
+        let mut y_mean = vec![0.0; n];
+        let mut y_squared_mean = vec![0.0; n];
+        for ((z, y, _), weight) in samples.iter().zip(&weights) {
+            for i in 0..n {
+                z_mean[i] += weight * z[i];
+                y_mean[i] += weight * y[i];
+                y_squared_mean[i] += weight * y[i] * y[i];
+            }
+        }
+        for (param, y) in params.iter_mut().zip(&y_mean) {
+            *param += self.step_size * y;
+        }
+        let path_scale = (c_sigma * (2.0 - c_sigma) * mu_eff).sqrt();
+        for (path, z) in self.path.iter_mut().zip(&z_mean) {
+            *path = (1.0 - c_sigma) * *path + path_scale * z;
+        }
+        let path_norm = self.path.iter().map(|p| p * p).sum::<f64>().sqrt();
+        self.adapt_step_size((c_sigma / d_sigma * (path_norm / expected_norm - 1.0)).exp());
+        for (variance, y_squared) in self.variances.iter_mut().zip(y_squared_mean) {
+            *variance = (1.0 - c_mu) * *variance + c_mu * y_squared;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bowl whose minimum is at (1, 2, 3, ...), with some directions
+    /// much steeper than others.
+    fn ellipsoid(params: &[f64]) -> f64 {
+        params
+            .iter()
+            .enumerate()
+            .map(|(i, param)| (i as f64 + 1.0) * (param - (i as f64 + 1.0)).powi(2))
+            .sum()
+    }
+
+    fn minimize(method: GradientFreeMethod, steps: usize) -> (f64, usize) {
+        let mut search = GradientFreeSearch::new(method, 0.5, 1);
+        let mut params = vec![0.0; 5];
+        let mut evaluations = 0;
+        for _ in 0..steps {
+            let loss = ellipsoid(&params);
+            evaluations += search.step(&mut params, loss, ellipsoid);
+        }
+        (ellipsoid(&params), evaluations)
+    }
+
+    #[test]
+    fn test_hill_climbing() {
+        let (loss, evaluations) = minimize(GradientFreeMethod::HillClimbing, 2000);
+        assert!(loss < 0.5, "loss {loss}");
+        assert_eq!(evaluations, 2000);
+    }
+
+    #[test]
+    fn test_one_plus_lambda_adapts_its_step_size() {
+        let method = GradientFreeMethod::OnePlusLambda { lambda: 8 };
+        let (loss, evaluations) = minimize(method, 500);
+        assert!(loss < 1e-6, "loss {loss}");
+        assert_eq!(evaluations, 4000);
+    }
+
+    #[test]
+    fn test_cma_es_lite() {
+        let method = GradientFreeMethod::CmaEsLite { population: 10 };
+        let (loss, evaluations) = minimize(method, 300);
+        assert!(loss < 1e-6, "loss {loss}");
+        assert_eq!(evaluations, 3000);
+    }
+}
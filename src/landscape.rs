@@ -0,0 +1,527 @@
+use std::collections::VecDeque;
+
+use macroquad::prelude::*;
+
+use crate::{
+    classifier_2d::{Classifier2D, colormap_fraction},
+    engine::SplitMix64,
+    zoom::px,
+};
+
+/// The number of power iterations used to find each principal
+/// direction.
+const POWER_ITERATIONS: usize = 100;
+
+/// How far past the furthest point of the trajectory a slice along its
+/// principal directions extends.
+const TRAJECTORY_MARGIN: f64 = 1.25;
+
+/// The smallest half-width of a slice, so it never collapses to a point.
+const MIN_EXTENT: f64 = 0.05;
+
+/// The number of contour lines, evenly spaced in log loss.
+const NUM_CONTOURS: usize = 10;
+
+/// Trajectories with fewer points than this don't have meaningful
+/// principal directions.
+const MIN_TRAJECTORY_POINTS: usize = 3;
+
+/// How the two directions that span a slice of parameter space are
+/// chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SliceDirections {
+    /// Random directions, which show the shape of the loss near the
+    /// current weights.
+    Random,
+    /// The directions along which the recent parameters varied the most,
+    /// which show the path the optimizer took.
+    Principal,
+}
+
+/// The parameters that a network has had during training, most recent
+/// last.
+#[derive(Clone, Debug)]
+pub struct Trajectory {
+    points: VecDeque<Vec<f64>>,
+    capacity: usize,
+    last_step: Option<u64>,
+}
+
+impl Trajectory {
+    /// Creates a trajectory that remembers at most `capacity` points.
+    pub fn new(capacity: usize) -> Self {
+        Trajectory {
+            points: VecDeque::new(),
+            capacity,
+            last_step: None,
+        }
+    }
+
+    pub fn points(&self) -> &VecDeque<Vec<f64>> {
+        &self.points
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+        self.last_step = None;
+    }
+
+    /// Adds the parameters after the given number of training steps.
+    /// Nothing is added if no steps were taken since the last point,
+    /// and the trajectory starts again if a different network started
+    /// training.
+    pub fn record(&mut self, step: u64, params: Vec<f64>) {
+        let is_new_network = self.last_step.is_some_and(|last_step| step < last_step)
+            || self
+                .points
+                .back()
+                .is_some_and(|last| last.len() != params.len());
+        if is_new_network {
+            self.clear();
+        }
+        if self.last_step == Some(step) {
+            return;
+        }
+        self.last_step = Some(step);
+        self.points.push_back(params);
+        while self.points.len() > self.capacity {
+            self.points.pop_front();
+        }
+    }
+}
+
+/// The loss over a square slice of parameter space, centered on the
+/// parameters of a network.
+#[derive(Clone, Debug)]
+pub struct LossLandscape {
+    center: Vec<f64>,
+    /// Orthonormal vectors spanning the slice.
+    directions: [Vec<f64>; 2],
+    kind: SliceDirections,
+    /// The fraction of the trajectory's variance along each principal
+    /// direction.
+    explained_variance: Option<[f64; 2]>,
+    /// The slice covers this far from the center along each direction.
+    extent: f64,
+    resolution: usize,
+    /// The loss, including regularization, at the center of each cell,
+    /// row by row from the bottom.
+    losses: Vec<f64>,
+}
+
+impl LossLandscape {
+    /// Evaluates the loss of the classifier on a `resolution` by
+    /// `resolution` grid around its current parameters. Principal
+    /// directions fall back to random ones if the trajectory is too
+    /// short. Frozen parameters are left alone.
+    pub fn compute(
+        classifier: &mut Classifier2D,
+        kind: SliceDirections,
+        trajectory: &Trajectory,
+        resolution: usize,
+        seed: u64,
+    ) -> Self {
+        let center = classifier.param_values();
+        let trainable = classifier.trainable_params();
+        let principal = match kind {
+            SliceDirections::Principal => principal_directions(&center, &trainable, trajectory),
+            SliceDirections::Random => None,
+        };
+        let (directions, kind, explained_variance, extent) = match principal {
+            Some((directions, explained_variance)) => {
+                // Cover the whole trajectory.
+                let extent = trajectory
+                    .points()
+                    .iter()
+                    .flat_map(|point| {
+                        let offset = subtract(point, &center);
+                        directions
+                            .clone()
+                            .map(|direction| dot(&offset, &direction).abs())
+                    })
+                    .fold(0.0, f64::max);
+                (
+                    directions,
+                    SliceDirections::Principal,
+                    Some(explained_variance),
+                    extent * TRAJECTORY_MARGIN,
+                )
+            }
+            None => {
+                // Go as far as the parameters are from the origin, so the
+                // slice scales with the size of the weights.
+                let norm = dot(&center, &center).sqrt();
+                (
+                    random_directions(&trainable, seed),
+                    SliceDirections::Random,
+                    None,
+                    norm.max(1.0),
+                )
+            }
+        };
+        let extent = extent.max(MIN_EXTENT);
+
+        let mut params = center.clone();
+        let mut losses = Vec::with_capacity(resolution * resolution);
+        for row in 0..resolution {
+            for column in 0..resolution {
+                let (a, b) = (
+                    cell_coordinate(column, resolution, extent),
+                    cell_coordinate(row, resolution, extent),
+                );
+                for (index, param) in params.iter_mut().enumerate() {
+                    *param = center[index] + a * directions[0][index] + b * directions[1][index];
+                }
+                losses.push(classifier.loss_at(&params, None));
+            }
+        }
+        LossLandscape {
+            center,
+            directions,
+            kind,
+            explained_variance,
+            extent,
+            resolution,
+            losses,
+        }
+    }
+
+    /// The kind of directions that were actually used.
+    pub fn kind(&self) -> SliceDirections {
+        self.kind
+    }
+
+    /// The fraction of the trajectory's variance along each of the
+    /// principal directions, if they were used.
+    pub fn explained_variance(&self) -> Option<[f64; 2]> {
+        self.explained_variance
+    }
+
+    /// The number of parameters of the network the slice was taken from.
+    pub fn num_params(&self) -> usize {
+        self.center.len()
+    }
+
+    pub fn losses(&self) -> &[f64] {
+        &self.losses
+    }
+
+    /// Returns the coordinates of the closest point of the slice to the
+    /// given parameters.
+    pub fn project(&self, params: &[f64]) -> (f64, f64) {
+        let offset = subtract(params, &self.center);
+        (
+            dot(&offset, &self.directions[0]),
+            dot(&offset, &self.directions[1]),
+        )
+    }
+
+    /// Returns the line segments, in slice coordinates, that make up the
+    /// contour lines of the log loss.
+    pub fn contours(&self) -> Vec<((f64, f64), (f64, f64))> {
+        let log_losses: Vec<f64> = self.losses.iter().map(|&loss| log_loss(loss)).collect();
+        let (min, max) = range(&log_losses);
+        if max - min < 1e-12 {
+            return vec![];
+        }
+        let n = self.resolution;
+        let point = |column: f64, row: f64| {
+            let scale = 2.0 * self.extent / n as f64;
+            (
+                -self.extent + (column + 0.5) * scale,
+                -self.extent + (row + 0.5) * scale,
+            )
+        };
+        let mut segments = vec![];
+        for level_index in 1..=NUM_CONTOURS {
+            let level = min + (max - min) * level_index as f64 / (NUM_CONTOURS + 1) as f64;
+            for row in 0..n - 1 {
+                for column in 0..n - 1 {
+                    // The corners of the square between four cells, going
+                    // counterclockwise from the bottom left.
+                    let corners = [(0, 0), (1, 0), (1, 1), (0, 1)].map(|(dx, dy)| {
+                        let (column, row) = (column + dx, row + dy);
+                        (column as f64, row as f64, log_losses[row * n + column])
+                    });
+                    // Where the contour crosses each edge of the square.
+                    let crossings: Vec<(f64, f64)> = (0..4)
+                        .filter_map(|edge| {
+                            let (x1, y1, v1) = corners[edge];
+                            let (x2, y2, v2) = corners[(edge + 1) % 4];
+                            if (v1 < level) == (v2 < level) {
+                                return None;
+                            }
+                            let t = (level - v1) / (v2 - v1);
+                            Some(point(x1 + t * (x2 - x1), y1 + t * (y2 - y1)))
+                        })
+                        .collect();
+                    // A square is crossed on either two or four edges. The
+                    // ambiguous case of four is resolved arbitrarily.
+                    for pair in crossings.chunks_exact(2) {
+                        segments.push((pair[0], pair[1]));
+                    }
+                }
+            }
+        }
+        segments
+    }
+
+    /// Draws the slice as a square heatmap of log loss, with contour
+    /// lines, the trajectory projected onto it and the current
+    /// parameters as a dot. `x` and `y` are the screen position of the
+    /// top left corner.
+    pub fn draw(&self, trajectory: &Trajectory, x: f32, y: f32, size: f32) {
+        let log_losses: Vec<f64> = self.losses.iter().map(|&loss| log_loss(loss)).collect();
+        let (min, max) = range(&log_losses);
+        let n = self.resolution;
+        let cell_size = size / n as f32;
+        for (index, &log_loss) in log_losses.iter().enumerate() {
+            let (column, row) = (index % n, index / n);
+            let fraction = if max > min {
+                (log_loss - min) / (max - min)
+            } else {
+                0.0
+            };
+            draw_rectangle(
+                x + column as f32 * cell_size,
+                y + size - (row + 1) as f32 * cell_size,
+                cell_size,
+                cell_size,
+                colormap_fraction(fraction),
+            );
+        }
+
+        let to_screen = |(a, b): (f64, f64)| {
+            let scale = size / (2.0 * self.extent) as f32;
+            vec2(
+                x + (a + self.extent) as f32 * scale,
+                y + size - (b + self.extent) as f32 * scale,
+            )
+        };
+        for (from, to) in self.contours() {
+            let (from, to) = (to_screen(from), to_screen(to));
+            draw_line(from.x, from.y, to.x, to.y, px(1.0), BLACK.with_alpha(0.5));
+        }
+
+        let is_inside = |point: Vec2| {
+            point.x >= x && point.x <= x + size && point.y >= y && point.y <= y + size
+        };
+        let points: Vec<Vec2> = trajectory
+            .points()
+            .iter()
+            .filter(|point| point.len() == self.center.len())
+            .map(|point| to_screen(self.project(point)))
+            .collect();
+        for pair in points.windows(2) {
+            if is_inside(pair[0]) && is_inside(pair[1]) {
+                draw_line(pair[0].x, pair[0].y, pair[1].x, pair[1].y, px(1.5), WHITE);
+            }
+        }
+        if let Some(&last) = points.last().filter(|&&last| is_inside(last)) {
+            draw_circle(last.x, last.y, px(3.0), RED);
+        }
+        draw_rectangle_lines(x, y, size, size, px(1.0), GRAY);
+    }
+}
+
+/// Returns the coordinate of the center of a cell along one direction.
+fn cell_coordinate(index: usize, resolution: usize, extent: f64) -> f64 {
+    -extent + (index as f64 + 0.5) * 2.0 * extent / resolution as f64
+}
+
+/// The loss is shown on a log scale, since it varies over orders of
+/// magnitude.
+fn log_loss(loss: f64) -> f64 {
+    (loss + 1e-12).ln()
+}
+
+fn range(values: &[f64]) -> (f64, f64) {
+    values
+        .iter()
+        .filter(|value| value.is_finite())
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &value| {
+            (min.min(value), max.max(value))
+        })
+}
+
+/// Returns two random orthonormal directions in which only the
+/// trainable parameters change.
+fn random_directions(trainable: &[bool], seed: u64) -> [Vec<f64>; 2] {
+    let mut rng = SplitMix64::new(seed);
+    let mut random = || -> Vec<f64> {
+        trainable
+            .iter()
+            .map(|&trainable| if trainable { rng.next_normal() } else { 0.0 })
+            .collect()
+    };
+    let first = normalize(random());
+    let second = normalize(orthogonalize(random(), &first));
+    [first, second]
+}
+
+/// Returns the two directions along which the trajectory's offsets from
+/// the center vary the most, and the fraction of the variance along
+/// each, or `None` if the trajectory doesn't go anywhere.
+fn principal_directions(
+    center: &[f64],
+    trainable: &[bool],
+    trajectory: &Trajectory,
+) -> Option<([Vec<f64>; 2], [f64; 2])> {
+    if trajectory.len() < MIN_TRAJECTORY_POINTS {
+        return None;
+    }
+    let offsets: Vec<Vec<f64>> = trajectory
+        .points()
+        .iter()
+        .filter(|point| point.len() == center.len())
+        .map(|point| {
+            subtract(point, center)
+                .into_iter()
+                .zip(trainable)
+                .map(|(offset, &trainable)| if trainable { offset } else { 0.0 })
+                .collect()
+        })
+        .collect();
+    let total_variance: f64 = offsets.iter().map(|offset| dot(offset, offset)).sum();
+    if total_variance < 1e-20 {
+        return None;
+    }
+
+    // Power iteration on the covariance matrix, without forming it: each
+    // iteration multiplies by the offsets and then their transpose.
+    let covariance_times = |vector: &[f64]| -> Vec<f64> {
+        let mut result = vec![0.0; vector.len()];
+        for offset in &offsets {
+            let projection = dot(offset, vector);
+            for (result, offset) in result.iter_mut().zip(offset) {
+                *result += projection * offset;
+            }
+        }
+        result
+    };
+    let [mut first, mut second] = random_directions(trainable, 1);
+    for _ in 0..POWER_ITERATIONS {
+        first = normalize(covariance_times(&first));
+    }
+    for _ in 0..POWER_ITERATIONS {
+        second = normalize(orthogonalize(covariance_times(&second), &first));
+    }
+    let variance = |direction: &[f64]| {
+        offsets
+            .iter()
+            .map(|offset| dot(offset, direction).powi(2))
+            .sum::<f64>()
+            / total_variance
+    };
+    let explained_variance = [variance(&first), variance(&second)];
+    Some(([first, second], explained_variance))
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn subtract(a: &[f64], b: &[f64]) -> Vec<f64> {
+    a.iter().zip(b).map(|(a, b)| a - b).collect()
+}
+
+/// Scales the vector to unit length, unless it's zero.
+fn normalize(mut vector: Vec<f64>) -> Vec<f64> {
+    let norm = dot(&vector, &vector).sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|value| *value /= norm);
+    }
+    vector
+}
+
+/// Removes the component along the unit vector `direction`.
+fn orthogonalize(mut vector: Vec<f64>, direction: &[f64]) -> Vec<f64> {
+    let projection = dot(&vector, direction);
+    for (value, direction) in vector.iter_mut().zip(direction) {
+        *value -= projection * direction;
+    }
+    vector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classifier_2d::{Datapoint2D, Label2D, Weights2D};
+
+    fn classifier() -> Classifier2D {
+        let datapoints = vec![
+            Datapoint2D::new((-10, 9), Label2D::Red),
+            Datapoint2D::new((5, -5), Label2D::Blue),
+        ];
+        Classifier2D::new(datapoints, Weights2D::new(2, vec![], false))
+    }
+
+    #[test]
+    fn test_principal_directions_follow_the_trajectory() {
+        let mut classifier = classifier();
+        let center = classifier.param_values();
+        let mut trajectory = Trajectory::new(100);
+        // A straight line towards the center, with a little wobble.
+        for step in 0..10 {
+            let t = 10.0 - step as f64;
+            let wobble = if step % 2 == 0 { 0.01 } else { -0.01 };
+            let point = vec![center[0] + t, center[1] + 2.0 * t + wobble, center[2]];
+            trajectory.record(step, point);
+        }
+        let landscape = LossLandscape::compute(
+            &mut classifier,
+            SliceDirections::Principal,
+            &trajectory,
+            5,
+            0,
+        );
+        assert_eq!(landscape.kind(), SliceDirections::Principal);
+        let along = landscape.directions[0][1] / landscape.directions[0][0];
+        assert!((along - 2.0).abs() < 1e-3, "{:?}", landscape.directions);
+        assert!(landscape.explained_variance().unwrap()[0] > 0.99);
+        // The first point of the trajectory is inside the slice.
+        let (a, b) = landscape.project(&trajectory.points()[0]);
+        assert!(a.abs() < landscape.extent && b.abs() < landscape.extent);
+    }
+
+    #[test]
+    fn test_center_of_slice_has_current_loss() {
+        let mut classifier = classifier();
+        let params = classifier.param_values();
+        let landscape = LossLandscape::compute(
+            &mut classifier,
+            SliceDirections::Principal,
+            &Trajectory::new(10),
+            5,
+            3,
+        );
+        // The trajectory is too short for principal directions.
+        assert_eq!(landscape.kind(), SliceDirections::Random);
+        assert_eq!(classifier.param_values(), params);
+        let loss = classifier.loss() + classifier.regularization_loss();
+        assert!((landscape.losses()[2 * 5 + 2] - loss).abs() < 1e-12);
+        assert!(dot(&landscape.directions[0], &landscape.directions[1]).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_trajectory_restarts_with_new_network() {
+        let mut trajectory = Trajectory::new(3);
+        for step in 1..=5 {
+            trajectory.record(step, vec![step as f64]);
+        }
+        trajectory.record(5, vec![0.0]);
+        assert_eq!(trajectory.len(), 3);
+        trajectory.record(1, vec![1.0]);
+        assert_eq!(trajectory.len(), 1);
+        trajectory.record(2, vec![1.0, 2.0]);
+        assert_eq!(trajectory.len(), 1);
+    }
+}
@@ -0,0 +1,1267 @@
+use std::fmt::Debug;
+
+use crate::{
+    engine::{ActivationType, NeuronValue, rand_f64},
+    model_file::{LayerFile, ModelFileError},
+    value::Value,
+};
+
+/// A layer of a neural net that can be trained, i.e. one whose
+/// parameters are `Value`s.
+pub type TrainableLayer = dyn Layer<Value>;
+
+/// A layer of a read-only neural net, which can be shared between
+/// threads.
+pub type ReadOnlyLayer = dyn Layer<f64> + Send + Sync;
+
+/// A layer in a neural net. Networks can be assembled from any mix
+/// of layers that implement this, including ones defined outside
+/// of this module.
+pub trait Layer<V: NeuronValue>: Debug {
+    /// Returns the outputs of the layer in inference mode, i.e.
+    /// when it's being used rather than trained.
+    fn output(&self, inputs: &[V]) -> Vec<V>;
+
+    /// Returns the outputs of the layer for a whole batch of inputs
+    /// in training mode. Only layers that behave differently during
+    /// training need to implement this.
+    fn training_output(&mut self, batch: &[Vec<V>]) -> Vec<Vec<V>> {
+        batch.iter().map(|inputs| self.output(inputs)).collect()
+    }
+
+    /// Replaces the contents of `outputs` with the outputs of the layer
+    /// in inference mode for a whole batch of inputs, which are laid
+    /// out one after the other in `inputs`, `num_inputs` at a time.
+    /// The outputs are laid out the same way. Layers that are made of
+    /// other layers can borrow buffers for their intermediate outputs
+    /// from `scratch`, as long as they put them back afterwards.
+    /// Layers should implement this if they can do it without
+    /// allocating, since it's how read-only networks are evaluated in
+    /// bulk.
+    fn batch_output(
+        &self,
+        num_inputs: usize,
+        inputs: &[V],
+        outputs: &mut Vec<V>,
+        _scratch: &mut Vec<Vec<V>>,
+    ) {
+        outputs.clear();
+        for inputs in inputs.chunks_exact(num_inputs) {
+            outputs.extend(self.output(inputs));
+        }
+    }
+
+    /// Backpropagates through the layer in inference mode without
+    /// building an expression graph. Given the inputs the layer was
+    /// called with, the outputs it returned and the gradient of the
+    /// loss with respect to those outputs, this adds the gradients of
+    /// its parameters to `param_grads`, in the same order as
+    /// [Layer::params], and returns the gradient with respect to its
+    /// inputs. Returns `None` if the layer doesn't support this.
+    fn backward(
+        &self,
+        _inputs: &[V],
+        _outputs: &[V],
+        _output_grads: &[f64],
+        _param_grads: &mut [f64],
+    ) -> Option<Vec<f64>> {
+        None
+    }
+
+    /// Returns the values of the layer's neurons before their
+    /// activation function is applied, for layers that have neurons.
+    fn pre_activations(&self, _inputs: &[V]) -> Option<Vec<V>> {
+        None
+    }
+
+    /// Returns the activation function the layer applies, if any.
+    fn activation(&self) -> Option<ActivationType> {
+        None
+    }
+
+    /// Returns all the learnable parameters of the layer.
+    fn params(&self) -> Vec<V>;
+
+    fn num_params(&self) -> usize {
+        self.params().len()
+    }
+
+    /// Returns the parameters that regularization should apply to,
+    /// which usually excludes things like biases.
+    fn weights(&self) -> Vec<V> {
+        vec![]
+    }
+
+    /// Returns an `f64`-based copy of the layer, for fast inference.
+    fn read_only(&self) -> Box<ReadOnlyLayer>;
+
+    fn num_outputs(&self) -> usize;
+
+    /// Returns a description that can be used to rebuild the layer
+    /// from scratch, if there is one.
+    fn spec(&self) -> Option<LayerSpec> {
+        None
+    }
+
+    /// Returns the on-disk representation of the layer, if it can
+    /// be saved.
+    fn to_file(&self) -> Option<LayerFile> {
+        None
+    }
+}
+
+/// Describes one of the built-in layers, for building a neural net.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LayerSpec {
+    /// A fully-connected layer with the given number of outputs.
+    Dense(usize),
+    /// Applies the given activation function to each of its inputs.
+    Activation(ActivationType),
+    /// A batch normalization layer with as many outputs as inputs.
+    BatchNorm,
+    /// A layer normalization layer with as many outputs as inputs.
+    LayerNorm,
+    /// A residual block made of the given number of dense layers,
+    /// each with as many outputs as inputs.
+    Residual(usize),
+}
+
+impl LayerSpec {
+    /// Builds a freshly-initialized layer with the given number of
+    /// inputs. If it's a dense layer, its neurons use the given
+    /// activation function.
+    pub fn build(&self, num_inputs: usize, activation: ActivationType) -> Box<TrainableLayer> {
+        match *self {
+            LayerSpec::Dense(num_outputs) => {
+                Box::new(Dense::new(num_inputs, activation, num_outputs))
+            }
+            LayerSpec::Activation(activation) => Box::new(Activation::new(activation, num_inputs)),
+            LayerSpec::BatchNorm => Box::new(BatchNorm::new(num_inputs)),
+            LayerSpec::LayerNorm => Box::new(LayerNorm::new(num_inputs)),
+            LayerSpec::Residual(num_layers) => Box::new(Residual::new(
+                num_inputs,
+                (0..num_layers)
+                    .map(|_| LayerSpec::Dense(num_inputs).build(num_inputs, activation))
+                    .collect(),
+            )),
+        }
+    }
+}
+
+/// A single neuron in a neural net. It can have any number of
+/// inputs and always produces a single output value.
+#[derive(Clone, Debug)]
+struct Neuron<V: NeuronValue> {
+    weights: Vec<V>,
+    bias: V,
+    activation: ActivationType,
+}
+
+impl<V: NeuronValue> Neuron<V> {
+    fn new(num_inputs: usize, activation: ActivationType) -> Self {
+        Neuron {
+            weights: (0..num_inputs).map(|_| rand_f64().into()).collect(),
+            bias: rand_f64().into(),
+            activation,
+        }
+    }
+
+    fn read_only(&self) -> Neuron<f64> {
+        Neuron {
+            weights: self.weights.iter().map(|weight| weight.as_f64()).collect(),
+            bias: self.bias.as_f64(),
+            activation: self.activation,
+        }
+    }
+
+    /// Returns the weighted sum of the inputs, before activation.
+    fn sum(&self, inputs: &[V]) -> V {
+        assert_eq!(self.weights.len(), inputs.len());
+        let mut sum = self.bias.clone();
+        for (weight, input) in self.weights.iter().zip(inputs) {
+            sum = sum + weight.clone() * input.clone();
+        }
+        sum
+    }
+
+    fn output(&self, inputs: &[V]) -> V {
+        self.activation.activate(self.sum(inputs))
+    }
+
+    fn params(&self) -> Vec<V> {
+        let mut params = self.weights.clone();
+        params.push(self.bias.clone());
+        params
+    }
+}
+
+/// A fully-connected layer in a neural net.
+#[derive(Clone, Debug)]
+pub struct Dense<V: NeuronValue> {
+    /// The activation function of every neuron, which is kept here too
+    /// so that it's known even if the layer has no neurons.
+    activation: ActivationType,
+    neurons: Vec<Neuron<V>>,
+}
+
+impl<V: NeuronValue> Dense<V> {
+    pub fn new(num_inputs: usize, activation: ActivationType, num_outputs: usize) -> Self {
+        Dense {
+            activation,
+            neurons: (0..num_outputs)
+                .map(|_| Neuron::new(num_inputs, activation))
+                .collect(),
+        }
+    }
+}
+
+impl<V: NeuronValue> Layer<V> for Dense<V> {
+    fn output(&self, inputs: &[V]) -> Vec<V> {
+        self.neurons
+            .iter()
+            .map(|neuron| neuron.output(inputs))
+            .collect()
+    }
+
+    fn backward(
+        &self,
+        inputs: &[V],
+        outputs: &[V],
+        output_grads: &[f64],
+        param_grads: &mut [f64],
+    ) -> Option<Vec<f64>> {
+        let mut input_grads = vec![0.0; inputs.len()];
+        for ((neuron, param_grads), (output, output_grad)) in self
+            .neurons
+            .iter()
+            .zip(param_grads.chunks_exact_mut(inputs.len() + 1))
+            .zip(outputs.iter().zip(output_grads))
+        {
+            let grad = output_grad * neuron.activation.derivative_from_output(output.as_f64());
+            for (j, (weight, input)) in neuron.weights.iter().zip(inputs).enumerate() {
+                param_grads[j] += grad * input.as_f64();
+                input_grads[j] += grad * weight.as_f64();
+            }
+            param_grads[inputs.len()] += grad;
+        }
+        Some(input_grads)
+    }
+
+    fn pre_activations(&self, inputs: &[V]) -> Option<Vec<V>> {
+        Some(
+            self.neurons
+                .iter()
+                .map(|neuron| neuron.sum(inputs))
+                .collect(),
+        )
+    }
+
+    fn activation(&self) -> Option<ActivationType> {
+        Some(self.activation)
+    }
+
+    fn params(&self) -> Vec<V> {
+        self.neurons
+            .iter()
+            .flat_map(|neuron| neuron.params())
+            .collect()
+    }
+
+    fn num_params(&self) -> usize {
+        self.neurons.len() * (self.neurons.first().map_or(0, |n| n.weights.len()) + 1)
+    }
+
+    fn weights(&self) -> Vec<V> {
+        self.neurons
+            .iter()
+            .flat_map(|neuron| neuron.weights.clone())
+            .collect()
+    }
+
+    fn read_only(&self) -> Box<ReadOnlyLayer> {
+        Box::new(DenseMatrix::new(
+            self.activation,
+            self.neurons
+                .iter()
+                .map(|neuron| neuron.read_only())
+                .collect(),
+        ))
+    }
+
+    fn num_outputs(&self) -> usize {
+        self.neurons.len()
+    }
+
+    fn spec(&self) -> Option<LayerSpec> {
+        Some(LayerSpec::Dense(self.neurons.len()))
+    }
+
+    fn to_file(&self) -> Option<LayerFile> {
+        Some(LayerFile::Dense {
+            activation: self.activation,
+            weights: self
+                .neurons
+                .iter()
+                .map(|neuron| to_f64s(&neuron.weights))
+                .collect(),
+            biases: self
+                .neurons
+                .iter()
+                .map(|neuron| neuron.bias.as_f64())
+                .collect(),
+        })
+    }
+}
+
+/// A read-only fully-connected layer whose weights are stored in a
+/// single contiguous matrix, which is much faster to evaluate than a
+/// list of separate neurons. This is what dense layers turn into when
+/// a network is made read-only.
+#[derive(Clone, Debug)]
+pub struct DenseMatrix {
+    activation: ActivationType,
+    num_inputs: usize,
+    /// The weights of the layer in input-major order, i.e. all the
+    /// weights for the first input come first, one per neuron. This
+    /// means each input can be multiplied into all of the outputs in
+    /// one tight loop, which the compiler can vectorize.
+    weights: Vec<f64>,
+    biases: Vec<f64>,
+}
+
+impl DenseMatrix {
+    fn new(activation: ActivationType, neurons: Vec<Neuron<f64>>) -> Self {
+        let num_inputs = neurons.first().map_or(0, |neuron| neuron.weights.len());
+        let mut weights = Vec::with_capacity(num_inputs * neurons.len());
+        for input in 0..num_inputs {
+            weights.extend(neurons.iter().map(|neuron| neuron.weights[input]));
+        }
+        DenseMatrix {
+            activation,
+            num_inputs,
+            weights,
+            biases: neurons.iter().map(|neuron| neuron.bias).collect(),
+        }
+    }
+
+    /// Returns the weights of the given neuron.
+    fn neuron_weights(&self, neuron: usize) -> impl Iterator<Item = f64> + '_ {
+        self.weights
+            .iter()
+            .skip(neuron)
+            .step_by(self.biases.len())
+            .copied()
+    }
+
+    /// Like [Layer::batch_output], but without the activation function.
+    fn batch_sums(&self, num_inputs: usize, inputs: &[f64], outputs: &mut Vec<f64>) {
+        assert_eq!(self.num_inputs, num_inputs);
+        let num_outputs = self.biases.len();
+        outputs.clear();
+        outputs.resize(inputs.len() / num_inputs * num_outputs, 0.0);
+        for (inputs, sums) in inputs
+            .chunks_exact(num_inputs)
+            .zip(outputs.chunks_exact_mut(num_outputs))
+        {
+            // This adds things up in the same order as `Neuron::sum`,
+            // so the results are exactly the same.
+            sums.copy_from_slice(&self.biases);
+            for (input, weights) in inputs.iter().zip(self.weights.chunks_exact(num_outputs)) {
+                for (sum, weight) in sums.iter_mut().zip(weights) {
+                    *sum += weight * input;
+                }
+            }
+        }
+    }
+}
+
+impl Layer<f64> for DenseMatrix {
+    fn output(&self, inputs: &[f64]) -> Vec<f64> {
+        assert_eq!(self.num_inputs, inputs.len());
+        let mut outputs = Vec::with_capacity(self.biases.len());
+        self.batch_output(self.num_inputs, inputs, &mut outputs, &mut vec![]);
+        outputs
+    }
+
+    fn batch_output(
+        &self,
+        num_inputs: usize,
+        inputs: &[f64],
+        outputs: &mut Vec<f64>,
+        _scratch: &mut Vec<Vec<f64>>,
+    ) {
+        self.batch_sums(num_inputs, inputs, outputs);
+        if self.activation != ActivationType::Linear {
+            for output in outputs.iter_mut() {
+                *output = self.activation.activate(*output);
+            }
+        }
+    }
+
+    fn backward(
+        &self,
+        inputs: &[f64],
+        outputs: &[f64],
+        output_grads: &[f64],
+        param_grads: &mut [f64],
+    ) -> Option<Vec<f64>> {
+        let num_outputs = self.biases.len();
+        let grads: Vec<f64> = outputs
+            .iter()
+            .zip(output_grads)
+            .map(|(&output, output_grad)| {
+                output_grad * self.activation.derivative_from_output(output)
+            })
+            .collect();
+        let mut input_grads = vec![0.0; self.num_inputs];
+        for (j, (weights, input)) in self
+            .weights
+            .chunks_exact(num_outputs)
+            .zip(inputs)
+            .enumerate()
+        {
+            for (k, (weight, grad)) in weights.iter().zip(&grads).enumerate() {
+                param_grads[k * (self.num_inputs + 1) + j] += grad * input;
+                input_grads[j] += grad * weight;
+            }
+        }
+        for (k, grad) in grads.iter().enumerate() {
+            param_grads[k * (self.num_inputs + 1) + self.num_inputs] += grad;
+        }
+        Some(input_grads)
+    }
+
+    fn pre_activations(&self, inputs: &[f64]) -> Option<Vec<f64>> {
+        let mut sums = Vec::with_capacity(self.biases.len());
+        self.batch_sums(self.num_inputs, inputs, &mut sums);
+        Some(sums)
+    }
+
+    fn activation(&self) -> Option<ActivationType> {
+        Some(self.activation)
+    }
+
+    fn params(&self) -> Vec<f64> {
+        (0..self.biases.len())
+            .flat_map(|neuron| self.neuron_weights(neuron).chain([self.biases[neuron]]))
+            .collect()
+    }
+
+    fn num_params(&self) -> usize {
+        self.weights.len() + self.biases.len()
+    }
+
+    fn weights(&self) -> Vec<f64> {
+        (0..self.biases.len())
+            .flat_map(|neuron| self.neuron_weights(neuron))
+            .collect()
+    }
+
+    fn read_only(&self) -> Box<ReadOnlyLayer> {
+        Box::new(self.clone())
+    }
+
+    fn num_outputs(&self) -> usize {
+        self.biases.len()
+    }
+
+    fn spec(&self) -> Option<LayerSpec> {
+        Some(LayerSpec::Dense(self.biases.len()))
+    }
+
+    fn to_file(&self) -> Option<LayerFile> {
+        Some(LayerFile::Dense {
+            activation: self.activation,
+            weights: (0..self.biases.len())
+                .map(|neuron| self.neuron_weights(neuron).collect())
+                .collect(),
+            biases: self.biases.clone(),
+        })
+    }
+}
+
+/// Applies an activation function to each of its inputs, without
+/// any weights of its own.
+#[derive(Clone, Debug)]
+pub struct Activation {
+    activation: ActivationType,
+    width: usize,
+}
+
+impl Activation {
+    pub fn new(activation: ActivationType, width: usize) -> Self {
+        Activation { activation, width }
+    }
+}
+
+impl<V: NeuronValue> Layer<V> for Activation {
+    fn output(&self, inputs: &[V]) -> Vec<V> {
+        assert_eq!(self.width, inputs.len());
+        inputs
+            .iter()
+            .map(|input| self.activation.activate(input.clone()))
+            .collect()
+    }
+
+    fn batch_output(
+        &self,
+        num_inputs: usize,
+        inputs: &[V],
+        outputs: &mut Vec<V>,
+        _scratch: &mut Vec<Vec<V>>,
+    ) {
+        assert_eq!(self.width, num_inputs);
+        outputs.clear();
+        outputs.extend(
+            inputs
+                .iter()
+                .map(|input| self.activation.activate(input.clone())),
+        );
+    }
+
+    fn backward(
+        &self,
+        _inputs: &[V],
+        outputs: &[V],
+        output_grads: &[f64],
+        _param_grads: &mut [f64],
+    ) -> Option<Vec<f64>> {
+        Some(
+            outputs
+                .iter()
+                .zip(output_grads)
+                .map(|(output, output_grad)| {
+                    output_grad * self.activation.derivative_from_output(output.as_f64())
+                })
+                .collect(),
+        )
+    }
+
+    fn pre_activations(&self, inputs: &[V]) -> Option<Vec<V>> {
+        Some(inputs.to_vec())
+    }
+
+    fn activation(&self) -> Option<ActivationType> {
+        Some(self.activation)
+    }
+
+    fn params(&self) -> Vec<V> {
+        vec![]
+    }
+
+    fn read_only(&self) -> Box<ReadOnlyLayer> {
+        Box::new(self.clone())
+    }
+
+    fn num_outputs(&self) -> usize {
+        self.width
+    }
+
+    fn spec(&self) -> Option<LayerSpec> {
+        Some(LayerSpec::Activation(self.activation))
+    }
+
+    fn to_file(&self) -> Option<LayerFile> {
+        Some(LayerFile::Activation {
+            activation: self.activation,
+            width: self.width,
+        })
+    }
+}
+
+/// Adds its inputs to the outputs of the layers inside it, so that
+/// they only need to learn how to change their inputs rather than
+/// how to reproduce them. This lets gradients flow straight through
+/// the block, which makes deep networks much easier to train.
+#[derive(Debug)]
+pub struct Residual<V: NeuronValue> {
+    width: usize,
+    layers: Vec<Box<V::Layer>>,
+}
+
+impl<V: NeuronValue> Residual<V> {
+    /// Creates a residual block around the given layers, whose final
+    /// number of outputs must be the same as the block's number of
+    /// inputs.
+    pub fn new(width: usize, layers: Vec<Box<V::Layer>>) -> Self {
+        if let Some(last) = layers.last() {
+            assert_eq!(last.num_outputs(), width);
+        }
+        Residual { width, layers }
+    }
+}
+
+impl<V: NeuronValue> Layer<V> for Residual<V> {
+    fn output(&self, inputs: &[V]) -> Vec<V> {
+        assert_eq!(self.width, inputs.len());
+        let mut outputs = inputs.to_vec();
+        for layer in &self.layers {
+            outputs = layer.output(&outputs);
+        }
+        inputs
+            .iter()
+            .zip(outputs)
+            .map(|(input, output)| input.clone() + output)
+            .collect()
+    }
+
+    /// The layers inside the block take turns writing to `outputs` and
+    /// a buffer borrowed from `scratch`, in whichever order leaves the
+    /// last layer's outputs in `outputs`, so this doesn't allocate once
+    /// the buffers have grown big enough.
+    fn batch_output(
+        &self,
+        num_inputs: usize,
+        inputs: &[V],
+        outputs: &mut Vec<V>,
+        scratch: &mut Vec<Vec<V>>,
+    ) {
+        assert_eq!(self.width, num_inputs);
+        if self.layers.is_empty() {
+            outputs.clear();
+            outputs.extend_from_slice(inputs);
+        }
+        let mut intermediate = scratch.pop().unwrap_or_default();
+        let mut next_num_inputs = num_inputs;
+        for (index, layer) in self.layers.iter().enumerate() {
+            let into_outputs = (self.layers.len() - index) % 2 == 1;
+            let (layer_inputs, layer_outputs) = match (index, into_outputs) {
+                (0, true) => (inputs, &mut *outputs),
+                (0, false) => (inputs, &mut intermediate),
+                (_, true) => (&intermediate[..], &mut *outputs),
+                (_, false) => (&outputs[..], &mut intermediate),
+            };
+            layer.batch_output(next_num_inputs, layer_inputs, layer_outputs, scratch);
+            next_num_inputs = layer.num_outputs();
+        }
+        scratch.push(intermediate);
+        for (output, input) in outputs.iter_mut().zip(inputs) {
+            *output = input.clone() + output.clone();
+        }
+    }
+
+    fn training_output(&mut self, batch: &[Vec<V>]) -> Vec<Vec<V>> {
+        let mut outputs = batch.to_vec();
+        for layer in &mut self.layers {
+            outputs = layer.training_output(&outputs);
+        }
+        batch
+            .iter()
+            .zip(outputs)
+            .map(|(inputs, outputs)| {
+                inputs
+                    .iter()
+                    .zip(outputs)
+                    .map(|(input, output)| input.clone() + output)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The outputs of the layers inside the block aren't cached, so
+    /// they're recomputed from its inputs.
+    fn backward(
+        &self,
+        inputs: &[V],
+        _outputs: &[V],
+        output_grads: &[f64],
+        param_grads: &mut [f64],
+    ) -> Option<Vec<f64>> {
+        let mut layer_inputs = vec![inputs.to_vec()];
+        for layer in &self.layers {
+            layer_inputs.push(layer.output(layer_inputs.last().unwrap()));
+        }
+        let mut grads = output_grads.to_vec();
+        let mut param_end = param_grads.len();
+        for (index, layer) in self.layers.iter().enumerate().rev() {
+            let param_start = param_end - layer.num_params();
+            grads = layer.backward(
+                &layer_inputs[index],
+                &layer_inputs[index + 1],
+                &grads,
+                &mut param_grads[param_start..param_end],
+            )?;
+            param_end = param_start;
+        }
+        // The inputs are also added straight to the outputs.
+        for (grad, output_grad) in grads.iter_mut().zip(output_grads) {
+            *grad += output_grad;
+        }
+        Some(grads)
+    }
+
+    /// The neurons of a residual block are those of its last layer,
+    /// even though the block's outputs also include its inputs.
+    fn pre_activations(&self, inputs: &[V]) -> Option<Vec<V>> {
+        let (last, rest) = self.layers.split_last()?;
+        let mut last_inputs = inputs.to_vec();
+        for layer in rest {
+            last_inputs = layer.output(&last_inputs);
+        }
+        last.pre_activations(&last_inputs)
+    }
+
+    /// The activation function of the block's layers.
+    fn activation(&self) -> Option<ActivationType> {
+        self.layers.iter().find_map(|layer| layer.activation())
+    }
+
+    fn params(&self) -> Vec<V> {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.params())
+            .collect()
+    }
+
+    fn num_params(&self) -> usize {
+        self.layers.iter().map(|layer| layer.num_params()).sum()
+    }
+
+    fn weights(&self) -> Vec<V> {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.weights())
+            .collect()
+    }
+
+    fn read_only(&self) -> Box<ReadOnlyLayer> {
+        Box::new(Residual::<f64> {
+            width: self.width,
+            layers: self.layers.iter().map(|layer| layer.read_only()).collect(),
+        })
+    }
+
+    fn num_outputs(&self) -> usize {
+        self.width
+    }
+
+    fn spec(&self) -> Option<LayerSpec> {
+        let all_dense = self
+            .layers
+            .iter()
+            .all(|layer| layer.spec() == Some(LayerSpec::Dense(self.width)));
+        if all_dense {
+            Some(LayerSpec::Residual(self.layers.len()))
+        } else {
+            None
+        }
+    }
+
+    fn to_file(&self) -> Option<LayerFile> {
+        Some(LayerFile::Residual {
+            layers: self
+                .layers
+                .iter()
+                .map(|layer| layer.to_file())
+                .collect::<Option<Vec<_>>>()?,
+        })
+    }
+}
+
+/// Small constant added to variances so we never divide by zero
+/// when normalizing.
+pub(crate) const NORM_EPSILON: f64 = 1e-5;
+
+/// How much of each training batch's statistics are mixed into
+/// the running statistics of a batch normalization layer.
+const BATCH_NORM_MOMENTUM: f64 = 0.1;
+
+/// Normalizes each of its inputs across a training batch, so that
+/// it has zero mean and unit variance, and then applies a learned
+/// scale and shift to it. Since there's no batch at inference time,
+/// the running averages of the training statistics are used instead.
+#[derive(Clone, Debug)]
+pub struct BatchNorm<V: NeuronValue> {
+    scale: Vec<V>,
+    shift: Vec<V>,
+    running_mean: Vec<f64>,
+    running_variance: Vec<f64>,
+}
+
+impl<V: NeuronValue> BatchNorm<V> {
+    pub fn new(width: usize) -> Self {
+        BatchNorm {
+            scale: constants(1.0, width),
+            shift: constants(0.0, width),
+            running_mean: vec![0.0; width],
+            running_variance: vec![1.0; width],
+        }
+    }
+}
+
+impl<V: NeuronValue> Layer<V> for BatchNorm<V> {
+    fn output(&self, inputs: &[V]) -> Vec<V> {
+        assert_eq!(self.scale.len(), inputs.len());
+        inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                let inv_std = V::from((self.running_variance[i] + NORM_EPSILON).powf(-0.5));
+                (input.clone() - self.running_mean[i].into()) * inv_std * self.scale[i].clone()
+                    + self.shift[i].clone()
+            })
+            .collect()
+    }
+
+    fn batch_output(
+        &self,
+        num_inputs: usize,
+        inputs: &[V],
+        outputs: &mut Vec<V>,
+        _scratch: &mut Vec<Vec<V>>,
+    ) {
+        assert_eq!(self.scale.len(), num_inputs);
+        outputs.clear();
+        for inputs in inputs.chunks_exact(num_inputs) {
+            outputs.extend(inputs.iter().enumerate().map(|(i, input)| {
+                let inv_std = V::from((self.running_variance[i] + NORM_EPSILON).powf(-0.5));
+                (input.clone() - self.running_mean[i].into()) * inv_std * self.scale[i].clone()
+                    + self.shift[i].clone()
+            }));
+        }
+    }
+
+    fn training_output(&mut self, batch: &[Vec<V>]) -> Vec<Vec<V>> {
+        if batch.is_empty() {
+            return vec![];
+        }
+        let mut outputs: Vec<Vec<V>> = vec![vec![]; batch.len()];
+        for i in 0..self.scale.len() {
+            let column: Vec<V> = batch.iter().map(|inputs| inputs[i].clone()).collect();
+            let (mean, variance) = mean_and_variance(&column);
+            self.running_mean[i] = (1.0 - BATCH_NORM_MOMENTUM) * self.running_mean[i]
+                + BATCH_NORM_MOMENTUM * mean.as_f64();
+            self.running_variance[i] = (1.0 - BATCH_NORM_MOMENTUM) * self.running_variance[i]
+                + BATCH_NORM_MOMENTUM * variance.as_f64();
+            let inv_std = (variance + NORM_EPSILON.into()).pow(-0.5);
+            for (output, input) in outputs.iter_mut().zip(column) {
+                output.push(
+                    (input - mean.clone()) * inv_std.clone() * self.scale[i].clone()
+                        + self.shift[i].clone(),
+                );
+            }
+        }
+        outputs
+    }
+
+    /// Since this is in inference mode, the running statistics are
+    /// treated as constants.
+    fn backward(
+        &self,
+        inputs: &[V],
+        _outputs: &[V],
+        output_grads: &[f64],
+        param_grads: &mut [f64],
+    ) -> Option<Vec<f64>> {
+        let width = self.scale.len();
+        let mut input_grads = vec![0.0; width];
+        for i in 0..width {
+            let inv_std = (self.running_variance[i] + NORM_EPSILON).powf(-0.5);
+            let normalized = (inputs[i].as_f64() - self.running_mean[i]) * inv_std;
+            param_grads[i] += output_grads[i] * normalized;
+            param_grads[width + i] += output_grads[i];
+            input_grads[i] = output_grads[i] * self.scale[i].as_f64() * inv_std;
+        }
+        Some(input_grads)
+    }
+
+    fn params(&self) -> Vec<V> {
+        let mut params = self.scale.clone();
+        params.extend(self.shift.iter().cloned());
+        params
+    }
+
+    fn num_params(&self) -> usize {
+        self.scale.len() * 2
+    }
+
+    fn read_only(&self) -> Box<ReadOnlyLayer> {
+        Box::new(BatchNorm {
+            scale: to_f64s(&self.scale),
+            shift: to_f64s(&self.shift),
+            running_mean: self.running_mean.clone(),
+            running_variance: self.running_variance.clone(),
+        })
+    }
+
+    fn num_outputs(&self) -> usize {
+        self.scale.len()
+    }
+
+    fn spec(&self) -> Option<LayerSpec> {
+        Some(LayerSpec::BatchNorm)
+    }
+
+    fn to_file(&self) -> Option<LayerFile> {
+        Some(LayerFile::BatchNorm {
+            scale: to_f64s(&self.scale),
+            shift: to_f64s(&self.shift),
+            running_mean: self.running_mean.clone(),
+            running_variance: self.running_variance.clone(),
+        })
+    }
+}
+
+/// Normalizes all of its inputs together, so that they have zero
+/// mean and unit variance, and then applies a learned scale and
+/// shift to each one. Unlike batch normalization, this doesn't
+/// depend on the rest of the batch, so it works the same way during
+/// training and inference.
+#[derive(Clone, Debug)]
+pub struct LayerNorm<V: NeuronValue> {
+    scale: Vec<V>,
+    shift: Vec<V>,
+}
+
+impl<V: NeuronValue> LayerNorm<V> {
+    pub fn new(width: usize) -> Self {
+        LayerNorm {
+            scale: constants(1.0, width),
+            shift: constants(0.0, width),
+        }
+    }
+}
+
+impl<V: NeuronValue> Layer<V> for LayerNorm<V> {
+    fn output(&self, inputs: &[V]) -> Vec<V> {
+        assert_eq!(self.scale.len(), inputs.len());
+        let (mean, variance) = mean_and_variance(inputs);
+        let inv_std = (variance + NORM_EPSILON.into()).pow(-0.5);
+        inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                (input.clone() - mean.clone()) * inv_std.clone() * self.scale[i].clone()
+                    + self.shift[i].clone()
+            })
+            .collect()
+    }
+
+    fn batch_output(
+        &self,
+        num_inputs: usize,
+        inputs: &[V],
+        outputs: &mut Vec<V>,
+        _scratch: &mut Vec<Vec<V>>,
+    ) {
+        assert_eq!(self.scale.len(), num_inputs);
+        outputs.clear();
+        for inputs in inputs.chunks_exact(num_inputs) {
+            let (mean, variance) = mean_and_variance(inputs);
+            let inv_std = (variance + NORM_EPSILON.into()).pow(-0.5);
+            outputs.extend(inputs.iter().enumerate().map(|(i, input)| {
+                (input.clone() - mean.clone()) * inv_std.clone() * self.scale[i].clone()
+                    + self.shift[i].clone()
+            }));
+        }
+    }
+
+    fn backward(
+        &self,
+        inputs: &[V],
+        _outputs: &[V],
+        output_grads: &[f64],
+        param_grads: &mut [f64],
+    ) -> Option<Vec<f64>> {
+        let width = self.scale.len();
+        let inputs: Vec<f64> = to_f64s(inputs);
+        let (mean, variance) = mean_and_variance(&inputs);
+        let inv_std = (variance + NORM_EPSILON).powf(-0.5);
+        let normalized: Vec<f64> = inputs.iter().map(|x| (x - mean) * inv_std).collect();
+        let mut normalized_grads = vec![0.0; width];
+        for i in 0..width {
+            param_grads[i] += output_grads[i] * normalized[i];
+            param_grads[width + i] += output_grads[i];
+            normalized_grads[i] = output_grads[i] * self.scale[i].as_f64();
+        }
+        // Every input affects the mean and variance, and therefore every
+        // output.
+        let mean_grad = normalized_grads.iter().sum::<f64>() / width as f64;
+        let mean_normalized_grad = normalized_grads
+            .iter()
+            .zip(&normalized)
+            .map(|(grad, normalized)| grad * normalized)
+            .sum::<f64>()
+            / width as f64;
+        Some(
+            normalized_grads
+                .iter()
+                .zip(&normalized)
+                .map(|(grad, normalized)| {
+                    inv_std * (grad - mean_grad - normalized * mean_normalized_grad)
+                })
+                .collect(),
+        )
+    }
+
+    fn params(&self) -> Vec<V> {
+        let mut params = self.scale.clone();
+        params.extend(self.shift.iter().cloned());
+        params
+    }
+
+    fn num_params(&self) -> usize {
+        self.scale.len() * 2
+    }
+
+    fn read_only(&self) -> Box<ReadOnlyLayer> {
+        Box::new(LayerNorm {
+            scale: to_f64s(&self.scale),
+            shift: to_f64s(&self.shift),
+        })
+    }
+
+    fn num_outputs(&self) -> usize {
+        self.scale.len()
+    }
+
+    fn spec(&self) -> Option<LayerSpec> {
+        Some(LayerSpec::LayerNorm)
+    }
+
+    fn to_file(&self) -> Option<LayerFile> {
+        Some(LayerFile::LayerNorm {
+            scale: to_f64s(&self.scale),
+            shift: to_f64s(&self.shift),
+        })
+    }
+}
+
+/// Returns the mean and (biased) variance of the given values.
+pub(crate) fn mean_and_variance<V: NeuronValue>(values: &[V]) -> (V, V) {
+    let inv_len = V::from(1.0 / values.len() as f64);
+    let mut sum = V::from(0.0);
+    for value in values {
+        sum = sum + value.clone();
+    }
+    let mean = sum * inv_len.clone();
+    let mut squared_sum = V::from(0.0);
+    for value in values {
+        let diff = value.clone() - mean.clone();
+        squared_sum = squared_sum + diff.clone() * diff;
+    }
+    (mean, squared_sum * inv_len)
+}
+
+/// Returns `len` separate copies of the given value. Unlike `vec!`,
+/// this doesn't make `Value`s that all share the same gradient.
+fn constants<V: NeuronValue>(value: f64, len: usize) -> Vec<V> {
+    (0..len).map(|_| V::from(value)).collect()
+}
+
+fn to_f64s<V: NeuronValue>(values: &[V]) -> Vec<f64> {
+    values.iter().map(|value| value.as_f64()).collect()
+}
+
+fn from_f64s(values: &[f64]) -> Vec<Value> {
+    values.iter().map(|&value| value.into()).collect()
+}
+
+/// Creates the trainable layer described by the given file.
+/// `index` is only used for error messages.
+pub(crate) fn layer_from_file(
+    file: &LayerFile,
+    num_inputs: usize,
+    index: usize,
+) -> Result<Box<TrainableLayer>, ModelFileError> {
+    let check_len = |name: &str, len: usize| {
+        if len == num_inputs {
+            Ok(())
+        } else {
+            Err(ModelFileError::ShapeMismatch(format!(
+                "layer {index} has {len} {name} values but {num_inputs} inputs"
+            )))
+        }
+    };
+    match file {
+        LayerFile::Dense {
+            activation,
+            weights,
+            biases,
+        } => {
+            if weights.is_empty() || weights.len() != biases.len() {
+                return Err(ModelFileError::ShapeMismatch(format!(
+                    "layer {index} has {} rows of weights and {} biases",
+                    weights.len(),
+                    biases.len()
+                )));
+            }
+            let mut neurons = vec![];
+            for (neuron_index, (weights, bias)) in weights.iter().zip(biases).enumerate() {
+                if weights.len() != num_inputs {
+                    return Err(ModelFileError::ShapeMismatch(format!(
+                        "layer {index} neuron {neuron_index} has {} weights but the layer has {num_inputs} inputs",
+                        weights.len()
+                    )));
+                }
+                neurons.push(Neuron {
+                    weights: from_f64s(weights),
+                    bias: (*bias).into(),
+                    activation: *activation,
+                });
+            }
+            Ok(Box::new(Dense {
+                activation: *activation,
+                neurons,
+            }))
+        }
+        LayerFile::Activation { activation, width } => {
+            check_len("activation", *width)?;
+            Ok(Box::new(Activation::new(*activation, *width)))
+        }
+        LayerFile::BatchNorm {
+            scale,
+            shift,
+            running_mean,
+            running_variance,
+        } => {
+            check_len("scale", scale.len())?;
+            check_len("shift", shift.len())?;
+            check_len("running mean", running_mean.len())?;
+            check_len("running variance", running_variance.len())?;
+            Ok(Box::new(BatchNorm {
+                scale: from_f64s(scale),
+                shift: from_f64s(shift),
+                running_mean: running_mean.clone(),
+                running_variance: running_variance.clone(),
+            }))
+        }
+        LayerFile::LayerNorm { scale, shift } => {
+            check_len("scale", scale.len())?;
+            check_len("shift", shift.len())?;
+            Ok(Box::new(LayerNorm {
+                scale: from_f64s(scale),
+                shift: from_f64s(shift),
+            }))
+        }
+        LayerFile::Residual { layers: files } => {
+            let mut layers = vec![];
+            let mut next_num_inputs = num_inputs;
+            for file in files {
+                let layer = layer_from_file(file, next_num_inputs, index)?;
+                next_num_inputs = layer.num_outputs();
+                layers.push(layer);
+            }
+            check_len("residual output", next_num_inputs)?;
+            Ok(Box::new(Residual::<Value> {
+                width: num_inputs,
+                layers,
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_norm_normalizes_training_batches() {
+        let mut layer = BatchNorm::<f64>::new(2);
+        let batch = vec![vec![0.1, 0.5], vec![-0.7, 0.2], vec![0.9, -0.4]];
+        let outputs = layer.training_output(&batch);
+        for i in 0..2 {
+            let column: Vec<f64> = outputs.iter().map(|output| output[i]).collect();
+            let (mean, variance) = mean_and_variance(&column);
+            assert!(mean.abs() < 1e-9);
+            assert!((variance - 1.0).abs() < 1e-3);
+        }
+
+        // Inference uses the running statistics, which have only moved a
+        // little bit towards the batch statistics.
+        assert_ne!(layer.output(&batch[0]), outputs[0]);
+    }
+
+    #[test]
+    fn test_residual_adds_inputs_to_outputs() {
+        let inner = Dense::<Value>::new(2, ActivationType::Sigmoid, 2);
+        let inputs = [0.3, -0.8].map(Value::from);
+        let inner_output = inner.output(&inputs);
+        let residual = Residual::<Value>::new(2, vec![Box::new(inner)]);
+        assert_eq!(residual.spec(), Some(LayerSpec::Residual(1)));
+
+        let mut output = residual.output(&inputs);
+        for i in 0..2 {
+            assert_eq!(
+                output[i].as_f64(),
+                inputs[i].as_f64() + inner_output[i].as_f64()
+            );
+        }
+        assert_eq!(
+            residual.read_only().output(&[0.3, -0.8]),
+            vec![output[0].as_f64(), output[1].as_f64()]
+        );
+
+        // The gradient flows through the skip connection as well as
+        // through the inner layer.
+        let params = residual.params();
+        for param in params.iter() {
+            assert_eq!(param.grad(), 0.0);
+        }
+        output[0].backward();
+        assert!(params.iter().any(|param| param.grad() != 0.0));
+    }
+
+    #[test]
+    fn test_dense_matrix_matches_neurons() {
+        let layer = Dense::<Value>::new(3, ActivationType::Tanh, 4);
+        let matrix = layer.read_only();
+        assert_eq!(matrix.spec(), Some(LayerSpec::Dense(4)));
+        assert_eq!(matrix.to_file(), layer.to_file());
+        assert_eq!(matrix.params(), to_f64s(&layer.params()));
+        assert_eq!(matrix.weights(), to_f64s(&layer.weights()));
+
+        let batch = [0.3, -0.8, 0.1, 0.5, 0.2, -0.9];
+        let mut outputs = vec![];
+        matrix.batch_output(3, &batch, &mut outputs, &mut vec![]);
+        for (inputs, outputs) in batch.chunks(3).zip(outputs.chunks(4)) {
+            let inputs: Vec<Value> = from_f64s(inputs);
+            assert_eq!(to_f64s(&layer.output(&inputs)), outputs);
+        }
+    }
+
+    #[test]
+    fn test_empty_dense_layer_keeps_its_activation() {
+        let layer = Dense::<Value>::new(3, ActivationType::Relu, 0);
+        let Some(LayerFile::Dense { activation, .. }) = layer.to_file() else {
+            panic!("expected a dense layer");
+        };
+        assert_eq!(activation, ActivationType::Relu);
+        assert_eq!(layer.read_only().activation(), Some(ActivationType::Relu));
+    }
+
+    #[test]
+    fn test_dense_backward_matches_dense_matrix() {
+        let layer = Dense::<Value>::new(3, ActivationType::Sigmoid, 2);
+        let matrix = layer.read_only();
+        let inputs = [0.3, -0.8, 0.1];
+        let outputs = matrix.output(&inputs);
+        let output_grads = [0.5, -2.0];
+        let mut param_grads = vec![0.0; 8];
+        let input_grads = layer
+            .backward(
+                &from_f64s(&inputs),
+                &from_f64s(&outputs),
+                &output_grads,
+                &mut param_grads,
+            )
+            .unwrap();
+        let mut matrix_param_grads = vec![0.0; 8];
+        let matrix_input_grads = matrix
+            .backward(&inputs, &outputs, &output_grads, &mut matrix_param_grads)
+            .unwrap();
+        assert_eq!(input_grads, matrix_input_grads);
+        assert_eq!(param_grads, matrix_param_grads);
+    }
+
+    #[test]
+    fn test_layer_norm_matches_between_value_and_f64() {
+        let layer = LayerNorm::<Value>::new(3);
+        let inputs = [0.3, -0.8, 0.1];
+        let value_output = layer.output(&inputs.map(Value::from));
+        let f64_output = layer.read_only().output(&inputs);
+        for (value, f64) in value_output.iter().zip(f64_output) {
+            assert!((value.as_f64() - f64).abs() < 1e-12);
+        }
+    }
+}
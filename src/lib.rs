@@ -0,0 +1,20 @@
+pub mod button;
+pub mod classifier_2d;
+pub mod dataset;
+pub mod engine;
+pub mod ensemble;
+pub mod export;
+pub mod features;
+pub mod gradient_free;
+pub mod landscape;
+pub mod layer;
+pub mod model_file;
+pub mod neat;
+pub mod plot;
+pub mod search;
+pub mod second_order;
+pub mod summary;
+pub mod text;
+pub mod trainer;
+pub mod value;
+pub mod zoom;
@@ -1,21 +1,29 @@
-mod button;
-mod classifier_2d;
-mod engine;
-mod plot;
-mod text;
-mod value;
-mod zoom;
-
-use button::Button;
-use text::draw_custom_text;
-use value::Value;
+use neural_net_fun::{
+    button::Button,
+    engine::{Penalty, Regularization},
+    layer::LayerSpec,
+    text::draw_custom_text,
+    value::Value,
+};
 
 use macroquad::{prelude::*, window};
 
-use classifier_2d::{Classifier2D, Datapoint2D, Label2D, Weights2D};
-use plot::Plot;
-
-use crate::zoom::px;
+use neural_net_fun::{
+    classifier_2d::{
+        Classifier2D, Datapoint2D, Label2D, MAX_CLASSES, NeuronValues, Target2D, Task, Weights2D,
+    },
+    dataset::{load_datapoints, save_datapoints, split_validation},
+    ensemble::{Combination, Ensemble},
+    features::Features,
+    gradient_free::GradientFreeMethod,
+    landscape::{LossLandscape, SliceDirections, Trajectory},
+    model_file::{ModelFormat, ModelMetadata},
+    neat::Population,
+    plot::Plot,
+    second_order::SecondOrderMethod,
+    trainer::{EarlyStopping, Metrics, Optimizer, StopReason, Trainer, TrainingObserver},
+    zoom::px,
+};
 
 /// Each point on the plot is scaled by this many screen pixels.
 const PLOT_SCALE: f32 = 8.0;
@@ -33,41 +41,175 @@ const MAX_LEARN_SPEED: i32 = 50;
 /// arrive at the actual learning rate.
 const LEARN_SCALE: f64 = 0.05;
 
+/// Minimum regularization strength, as a power of ten.
+const MIN_REGULARIZATION_EXPONENT: i32 = -5;
+
+/// Maximum regularization strength, as a power of ten.
+const MAX_REGULARIZATION_EXPONENT: i32 = -1;
+
+/// The dropout probabilities that can be cycled through.
+const DROPOUT_RATES: [f64; 4] = [0.0, 0.1, 0.25, 0.5];
+
 /// Maximum number of hidden layers in the multi-layer perceptron.
-const MAX_HIDDEN_LAYERS: usize = 3;
+const MAX_HIDDEN_LAYERS: usize = 5;
 
 /// Number of neuron in each hidden layer of the multi-layer perceptron.
-///
-/// This is mentioned in `get_layer_notation` below, so if
-/// you change it here, change it there too.
 const NEURONS_PER_LAYER: usize = 16;
 
+/// The normalization layers that can follow each hidden layer.
+const NORMALIZATIONS: [Option<LayerSpec>; 3] =
+    [None, Some(LayerSpec::BatchNorm), Some(LayerSpec::LayerNorm)];
+
+/// The number of sets of input features that can be cycled through.
+/// See [Architecture::features].
+const NUM_FEATURE_SETS: usize = 5;
+
+/// The number of random Fourier features in the last set of input
+/// features.
+const NUM_FOURIER_FEATURES: usize = 8;
+
+/// The standard deviation of the frequencies of random Fourier features.
+const FOURIER_FEATURE_SCALE: f64 = 3.0;
+
+/// The fewest classes that multi-class classification is used for.
+/// Fewer classes use a single sigmoid output instead.
+const MIN_MULTI_CLASSES: usize = 3;
+
+/// The fractions of the datapoints that can be randomly held out for
+/// validation.
+const VALIDATION_FRACTIONS: [f64; 3] = [0.0, 0.2, 0.4];
+
+/// The values that can be painted when the task is regression.
+const REGRESSION_BRUSH_VALUES: [f64; 5] = [-1.0, -0.5, 0.0, 0.5, 1.0];
+
+/// Where the model is saved to and loaded from in JSON format.
+const MODEL_JSON_PATH: &str = "model.json";
+
+/// Where the datapoints are saved to and loaded from, in CSV format.
+const DATAPOINTS_PATH: &str = "datapoints.csv";
+
+/// Where the model is exported to as Rust source code.
+const MODEL_RUST_PATH: &str = "model.rs";
+
+/// Where the model is saved to and loaded from in binary format.
+const MODEL_BINARY_PATH: &str = "model.nnfm";
+
+/// Number of epochs without the loss improving by `PLATEAU_MIN_IMPROVEMENT`
+/// after which training stops, when early stopping is enabled.
+const PLATEAU_PATIENCE: u64 = 200;
+
+const PLATEAU_MIN_IMPROVEMENT: f64 = 1e-5;
+
 /// Maximum number of times we'll make the neural net learn per frame.
 const MAX_UPDATES_PER_FRAME: i32 = 10;
 
 // Length of the fade-out of the intro help message, in seconds.
 const HELP_ALPHA_FADE_SECS: f32 = 1.0;
 
+/// The width of each hidden neuron's heatmap, in pixels.
+const NEURON_HEATMAP_SIZE: f32 = 32.0;
+
+/// The optimizers that can be used instead of gradient descent, which
+/// only evaluate the loss.
+const GRADIENT_FREE_METHODS: [GradientFreeMethod; 3] = [
+    GradientFreeMethod::HillClimbing,
+    GradientFreeMethod::OnePlusLambda { lambda: 8 },
+    GradientFreeMethod::CmaEsLite { population: 12 },
+];
+
+/// The initial step size of gradient-free optimizers, as a multiple of
+/// the learning rate.
+const GRADIENT_FREE_STEP_SCALE: f64 = 0.5;
+
+/// The optimizers that use the curvature of the loss, which come after
+/// the gradient-free ones.
+const SECOND_ORDER_METHODS: [SecondOrderMethod; 2] = [
+    SecondOrderMethod::Lbfgs { memory: 10 },
+    SecondOrderMethod::DampedNewton,
+];
+
+/// The number of genomes in the population of the neuroevolution lab.
+const NEAT_POPULATION_SIZE: usize = 150;
+
+/// The width and height of the drawing of the best genome, in pixels.
+const NEAT_GENOME_SIZE: (f32, f32) = (240.0, 160.0);
+
+/// The number of cells along each side of the loss landscape.
+const LANDSCAPE_RESOLUTION: usize = 31;
+
+/// The width and height of the loss landscape, in pixels.
+const LANDSCAPE_SIZE: f32 = 240.0;
+
+/// The number of frames after which the loss landscape is recalculated
+/// around the latest weights.
+const LANDSCAPE_REFRESH_FRAMES: u32 = 30;
+
+/// The number of past parameters that are remembered, to draw the path
+/// the optimizer took and find its principal directions.
+const TRAJECTORY_CAPACITY: usize = 300;
+
+/// The numbers of independently initialized networks that can be
+/// trained at once.
+const ENSEMBLE_SIZES: [usize; 3] = [1, 3, 5];
+
+/// The spacing between the lines of the model summary, in pixels.
+const SUMMARY_LINE_HEIGHT: f32 = 22.0;
+
+/// Roughly the width of each character of the model summary, which is
+/// in a monospace font, in pixels.
+const SUMMARY_CHAR_WIDTH: f32 = 11.0;
+
 const BUTTON_FONT_SIZE: u16 = 14;
 
 const HELP_FONT_SIZE: u16 = 20;
 
 const STATUS_FONT_SIZE: u16 = 18;
 
-const HELP_TEXT: &'static str = r#"Help
+const HELP_TEXT: &str = r#"Help
 
 H - Toggle help
 [ - Decrease updates per frame
 ] - Increase updates per frame
 , - Decrease learning rate
-. - Increase learning rate
-1 - Paint blue datapoint (at mouse cursor)
-2 - Paint red datapoint (at mouse cursor)
+. - Increase learning rate (or initial step size of gradient-free optimizers)
+P - Cycle optimizer (gradient descent, hill climbing, (1+8)-ES, CMA-ES-lite, L-BFGS, damped Newton)
+1 - Paint first label (blue), or lowest value (at mouse cursor)
+2 - Paint last label, or highest value (at mouse cursor)
 X - Delete datapoint (at mouse cursor)
+Shift - Hold while painting to paint validation datapoints (drawn as rings)
+Z - Cycle random validation split (none, 20%, 40% of datapoints)
+Tab - Cycle brush (labels or values, then eraser)
 L - Cycle number of hidden layers
+N - Cycle normalization of hidden layers (none, batch, layer)
+J - Toggle residual connections between hidden layers
+O - Toggle skip connections from hidden layers to output
+T - Toggle task (classification, regression)
+Q - Cycle number of classes (2 to 8)
+I - Cycle input features (x y, polynomial, sin/cos, both, random Fourier)
 C - Clear all datapoints
 W - Reset weights
+E - Toggle early stopping (on loss plateau or 100% accuracy)
 S - Toggle point mesh shading
+U - Toggle model summary (also printed to the terminal)
+A - Cycle hidden neuron heatmaps (off, activations, pre-activations)
+G - Cycle input gradient (off, arrow at mouse cursor, arrow and magnitude heatmap)
+M - Cycle number of networks in the ensemble (1, 3, 5)
+V - Toggle combining the ensemble by averaging or voting
+Y - Cycle loss landscape (off, random directions, principal directions of the trajectory)
+K - Toggle neuroevolution lab (evolves network structure instead of training)
+F4 - Export model (Rust source)
+F5 - Save model (JSON)
+F6 - Save model (binary)
+F7 - Save datapoints (CSV)
+F8 - Load datapoints (CSV)
+F9 - Load model (JSON)
+F10 - Load model (binary)
+R - Cycle regularization (none, L1, L2)
+- - Decrease regularization strength
+= - Increase regularization strength
+B - Toggle regularization of biases
+D - Cycle dropout rate of hidden layers
+F - Cycle number of frozen layers (shown in [brackets])
 "#;
 
 fn window_conf() -> window::Conf {
@@ -88,15 +230,43 @@ async fn main() {
         Datapoint2D::new((-5, -5), Label2D::Blue),
         Datapoint2D::new((9, -10), Label2D::Blue),
     ];
-    let mut num_hidden_layers = 0;
-    let mut perceptron = make_perceptron(&datapoints, num_hidden_layers);
+    let mut architecture = Architecture::default();
+    let mut ensemble = Ensemble::new(
+        vec![
+            Trainer::new(
+                architecture.make_perceptron(&datapoints),
+                Optimizer::Sgd { learning_rate: 0.0 },
+            )
+            .with_observer(StopLogger),
+        ],
+        Combination::Average,
+    );
+    let mut ensemble_size_index = 0;
 
     let plot = Plot::new(PLOT_SCALE);
     let mut updates_per_frame = 1;
     let mut enable_shading = false;
+    let mut neuron_heatmaps: Option<NeuronValues> = None;
+    let mut input_gradient = InputGradient::Off;
+    let mut show_summary = false;
+    let mut evolution: Option<Population> = None;
+    let mut trajectory = Trajectory::new(TRAJECTORY_CAPACITY);
+    let mut landscape_directions: Option<SliceDirections> = None;
+    let mut landscape: Option<LossLandscape> = None;
+    let mut landscape_seed = 0;
+    let mut frames_since_landscape = 0;
     let mut show_help = false;
     let mut learning_speed = 2;
-    let mut current_brush: Option<Label2D> = Some(Label2D::Blue);
+    // Zero is gradient descent, followed by the gradient-free and then
+    // second-order methods.
+    let mut optimizer_index = 0;
+    let mut current_brush: Option<Target2D> = brushes(architecture.task).first().copied();
+    let mut validation_fraction_index = 0;
+    let mut penalty: Option<Penalty> = None;
+    let mut regularization_exponent = -3;
+    let mut regularize_biases = false;
+    let mut frozen_blocks = 0;
+    let mut dropout_index = 0;
     let help_lines: Vec<&'static str> = HELP_TEXT.split('\n').collect();
 
     let mut did_click_clear_button = false;
@@ -116,7 +286,7 @@ async fn main() {
         let label_arch_rect = Rect {
             x: label_button_rect.right() + px(LEFT_PADDING),
             y: y_ui,
-            w: px(96.0),
+            w: px(192.0),
             h: px(32.0),
         };
         let updates_per_frame_rect = Rect {
@@ -155,14 +325,39 @@ async fn main() {
 
         let is_mouse_outside_ui = !whole_ui_bounds.contains(raw_mouse_pos.into());
 
+        let brush_targets = brushes(architecture.task);
+        let validation = is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift);
         let did_modify_datapoints = if is_mouse_outside_ui && is_key_down(KeyCode::Key1) {
-            modify_datapoint(&mut datapoints, mouse, Some(Label2D::Blue))
+            let target = brush_targets.first().copied();
+            modify_datapoint(&mut datapoints, mouse, target, validation)
         } else if is_mouse_outside_ui && is_key_down(KeyCode::Key2) {
-            modify_datapoint(&mut datapoints, mouse, Some(Label2D::Red))
+            let target = brush_targets.last().copied();
+            modify_datapoint(&mut datapoints, mouse, target, validation)
         } else if is_mouse_outside_ui && is_key_down(KeyCode::X) {
-            modify_datapoint(&mut datapoints, mouse, None)
+            modify_datapoint(&mut datapoints, mouse, None, false)
         } else if is_mouse_outside_ui && is_mouse_button_down(MouseButton::Left) {
-            modify_datapoint(&mut datapoints, mouse, current_brush)
+            modify_datapoint(&mut datapoints, mouse, current_brush, validation)
+        } else if is_key_pressed(KeyCode::Z) {
+            validation_fraction_index =
+                (validation_fraction_index + 1) % VALIDATION_FRACTIONS.len();
+            let fraction = VALIDATION_FRACTIONS[validation_fraction_index];
+            split_validation(&mut datapoints, fraction, rand::rand() as u64);
+            true
+        } else if is_key_pressed(KeyCode::F8) {
+            match load_datapoints(DATAPOINTS_PATH) {
+                Ok(loaded) => {
+                    info!("Loaded datapoints from {}.", DATAPOINTS_PATH);
+                    datapoints = loaded;
+                    true
+                }
+                Err(err) => {
+                    error!(
+                        "Unable to load datapoints from {}: {}",
+                        DATAPOINTS_PATH, err
+                    );
+                    false
+                }
+            }
         } else if is_key_pressed(KeyCode::C) || did_click_clear_button {
             datapoints = vec![];
             did_click_clear_button = false;
@@ -172,9 +367,85 @@ async fn main() {
         };
 
         if did_modify_datapoints {
-            perceptron = Classifier2D::new(datapoints.clone(), perceptron.weights());
+            ensemble.set_datapoints(datapoints.clone());
         } else if is_key_pressed(KeyCode::W) {
-            perceptron = make_perceptron(&datapoints, num_hidden_layers);
+            ensemble.replace_classifiers(|| architecture.make_perceptron(&datapoints));
+        }
+
+        if is_key_pressed(KeyCode::M) {
+            ensemble_size_index = (ensemble_size_index + 1) % ENSEMBLE_SIZES.len();
+            ensemble.resize(ENSEMBLE_SIZES[ensemble_size_index], || {
+                architecture.make_perceptron(&datapoints)
+            });
+        }
+
+        if is_key_pressed(KeyCode::V) {
+            ensemble.set_combination(match ensemble.combination() {
+                Combination::Average => Combination::Vote,
+                Combination::Vote => Combination::Average,
+            });
+        }
+
+        if is_key_pressed(KeyCode::F7) {
+            match save_datapoints(DATAPOINTS_PATH, &datapoints) {
+                Ok(()) => info!("Saved datapoints to {}.", DATAPOINTS_PATH),
+                Err(err) => error!("Unable to save datapoints to {}: {}", DATAPOINTS_PATH, err),
+            }
+        }
+
+        for (key, path, format) in [
+            (KeyCode::F5, MODEL_JSON_PATH, ModelFormat::Json),
+            (KeyCode::F6, MODEL_BINARY_PATH, ModelFormat::Binary),
+        ] {
+            if is_key_pressed(key) {
+                let metadata = ModelMetadata {
+                    training_steps: Some(ensemble.primary().metrics().step),
+                    seed: None,
+                };
+                // Only the primary network of an ensemble is saved.
+                match ensemble
+                    .primary()
+                    .classifier()
+                    .weights()
+                    .save(path, format, metadata)
+                {
+                    Ok(()) => info!("Saved model to {}.", path),
+                    Err(err) => error!("Unable to save model to {}: {}", path, err),
+                }
+            }
+        }
+
+        if is_key_pressed(KeyCode::F4) {
+            // Only the primary network of an ensemble is exported.
+            let source = ensemble.primary().classifier().to_rust_source();
+            match source.map(|source| std::fs::write(MODEL_RUST_PATH, source)) {
+                Ok(Ok(())) => info!("Exported model to {}.", MODEL_RUST_PATH),
+                Ok(Err(err)) => error!("Unable to export model to {}: {}", MODEL_RUST_PATH, err),
+                Err(err) => error!("Unable to export model: {}", err),
+            }
+        }
+
+        for (key, path) in [
+            (KeyCode::F9, MODEL_JSON_PATH),
+            (KeyCode::F10, MODEL_BINARY_PATH),
+        ] {
+            if is_key_pressed(key) {
+                let num_inputs = architecture.features().len();
+                match Weights2D::load(path, num_inputs, architecture.task.num_outputs()) {
+                    Ok((weights, metadata)) => {
+                        info!("Loaded model from {}.", path);
+                        ensemble_size_index = 0;
+                        ensemble.resize(1, || architecture.make_perceptron(&datapoints));
+                        ensemble.primary_mut().replace_classifier(
+                            Classifier2D::new(datapoints.clone(), weights)
+                                .with_features(architecture.features())
+                                .with_task(architecture.task),
+                            metadata.training_steps.unwrap_or(0),
+                        );
+                    }
+                    Err(err) => error!("Unable to load model from {}: {}", path, err),
+                }
+            }
         }
 
         if is_key_pressed(KeyCode::H) {
@@ -185,6 +456,29 @@ async fn main() {
             enable_shading = !enable_shading;
         }
 
+        if is_key_pressed(KeyCode::A) {
+            neuron_heatmaps = match neuron_heatmaps {
+                None => Some(NeuronValues::Activations),
+                Some(NeuronValues::Activations) => Some(NeuronValues::PreActivations),
+                Some(NeuronValues::PreActivations) => None,
+            };
+        }
+
+        if is_key_pressed(KeyCode::U) {
+            show_summary = !show_summary;
+            if show_summary {
+                println!("{}", ensemble.primary().classifier().weights().summary());
+            }
+        }
+
+        if is_key_pressed(KeyCode::G) {
+            input_gradient = match input_gradient {
+                InputGradient::Off => InputGradient::Arrow,
+                InputGradient::Arrow => InputGradient::ArrowAndHeatmap,
+                InputGradient::ArrowAndHeatmap => InputGradient::Off,
+            };
+        }
+
         if is_key_pressed(KeyCode::LeftBracket) {
             updates_per_frame = std::cmp::max(updates_per_frame - 1, 0);
         } else if is_key_pressed(KeyCode::RightBracket) {
@@ -199,16 +493,222 @@ async fn main() {
             learning_speed = std::cmp::min(learning_speed + 1, MAX_LEARN_SPEED);
         }
 
+        if is_key_pressed(KeyCode::P) {
+            optimizer_index = (optimizer_index + 1)
+                % (1 + GRADIENT_FREE_METHODS.len() + SECOND_ORDER_METHODS.len());
+        }
+
         let learning_rate = learning_speed as f64 * LEARN_SCALE;
+        ensemble.set_optimizer(match optimizer_index {
+            0 => Optimizer::Sgd { learning_rate },
+            index if index <= GRADIENT_FREE_METHODS.len() => Optimizer::GradientFree {
+                method: GRADIENT_FREE_METHODS[index - 1],
+                step_size: learning_rate * GRADIENT_FREE_STEP_SCALE,
+            },
+            index => Optimizer::SecondOrder {
+                method: SECOND_ORDER_METHODS[index - 1 - GRADIENT_FREE_METHODS.len()],
+            },
+        });
+
+        if is_key_pressed(KeyCode::E) {
+            ensemble.set_early_stopping(if ensemble.primary().early_stopping().is_enabled() {
+                EarlyStopping::default()
+            } else {
+                EarlyStopping::default()
+                    .with_plateau(PLATEAU_PATIENCE, PLATEAU_MIN_IMPROVEMENT)
+                    .with_target_accuracy(1.0)
+            });
+        }
+
+        if is_key_pressed(KeyCode::R) {
+            penalty = match penalty {
+                None => Some(Penalty::L1),
+                Some(Penalty::L1) => Some(Penalty::L2),
+                Some(Penalty::L2) => None,
+            };
+        }
+
+        if is_key_pressed(KeyCode::Minus) {
+            regularization_exponent =
+                std::cmp::max(regularization_exponent - 1, MIN_REGULARIZATION_EXPONENT);
+        } else if is_key_pressed(KeyCode::Equal) {
+            regularization_exponent =
+                std::cmp::min(regularization_exponent + 1, MAX_REGULARIZATION_EXPONENT);
+        }
+
+        if is_key_pressed(KeyCode::B) {
+            regularize_biases = !regularize_biases;
+        }
+
+        let regularization = penalty.map(|penalty| {
+            let regularization =
+                Regularization::new(penalty, 10.0_f64.powi(regularization_exponent));
+            if regularize_biases {
+                regularization.with_biases()
+            } else {
+                regularization
+            }
+        });
+        if regularization != ensemble.primary().classifier().regularization() {
+            ensemble.update_classifiers(|classifier| classifier.set_regularization(regularization));
+        }
+
+        if is_key_pressed(KeyCode::D) {
+            dropout_index = (dropout_index + 1) % DROPOUT_RATES.len();
+        }
+        for member in ensemble.members_mut() {
+            member
+                .classifier_mut()
+                .set_dropout(DROPOUT_RATES[dropout_index]);
+        }
+
+        if is_key_pressed(KeyCode::F) {
+            frozen_blocks += 1;
+        }
+        // Freezing every layer would stop training altogether, so the
+        // output layer is never frozen.
+        frozen_blocks %= ensemble.primary().classifier().weights().num_blocks();
+        // New classifiers start out with nothing frozen, so this also
+        // catches changes to the architecture.
+        let needs_freezing = ensemble
+            .members()
+            .iter()
+            .any(|member| member.classifier().weights().num_frozen_blocks() != frozen_blocks);
+        if needs_freezing {
+            for member in ensemble.members_mut() {
+                member.classifier_mut().freeze_first_blocks(frozen_blocks);
+            }
+        }
+
+        if is_key_pressed(KeyCode::K) {
+            evolution = match evolution {
+                Some(_) => None,
+                None if architecture.task.num_outputs() > 1 => {
+                    info!("The neuroevolution lab only evolves networks with a single output.");
+                    None
+                }
+                None => Some(new_population(ensemble.primary().classifier())),
+            };
+        }
+
+        if let Some(population) = &mut evolution {
+            let classifier = ensemble.primary().classifier();
+            // Start again if the inputs or output of the genomes changed.
+            if population.num_inputs() != classifier.features().len()
+                || population.output_activation() != classifier.task().output_activation()
+            {
+                *population = new_population(classifier);
+            }
+            for _ in 0..updates_per_frame {
+                population.evolve(classifier);
+            }
+        } else {
+            for _ in 0..updates_per_frame {
+                if ensemble.step().is_some() {
+                    break;
+                }
+            }
+            // Training runs the network with dropout and batch statistics,
+            // which would make the loss and accuracy in the status line
+            // jump around, so they're recalculated in inference mode.
+            let classifier = ensemble.primary_mut().classifier_mut();
+            if !classifier.training_matches_inference() {
+                classifier.evaluate();
+            }
+        }
+        let trainer = ensemble.primary();
+        trajectory.record(trainer.metrics().step, trainer.classifier().param_values());
 
-        for _ in 0..updates_per_frame {
-            perceptron.update(learning_rate);
+        if is_key_pressed(KeyCode::Y) {
+            landscape_directions = match landscape_directions {
+                None => Some(SliceDirections::Random),
+                Some(SliceDirections::Random) => Some(SliceDirections::Principal),
+                Some(SliceDirections::Principal) => None,
+            };
+            landscape = None;
+            landscape_seed = rand::rand() as u64;
         }
+        if let Some(directions) = landscape_directions {
+            frames_since_landscape += 1;
+            let classifier = ensemble.primary_mut().classifier_mut();
+            let is_stale = landscape.as_ref().is_none_or(|landscape| {
+                frames_since_landscape >= LANDSCAPE_REFRESH_FRAMES
+                    || landscape.num_params() != classifier.num_params()
+            });
+            if is_stale {
+                landscape = Some(LossLandscape::compute(
+                    classifier,
+                    directions,
+                    &trajectory,
+                    LANDSCAPE_RESOLUTION,
+                    landscape_seed,
+                ));
+                frames_since_landscape = 0;
+            }
+        }
+
+        let trainer = ensemble.primary();
+        let perceptron = trainer.classifier();
+        let best_genome = evolution.as_ref().and_then(|population| population.best());
 
         plot.draw_axes();
         plot.draw_circle(mouse.0 as f32, mouse.1 as f32, 0.75, DARKGRAY);
 
-        perceptron.draw(&plot, enable_shading);
+        if let Some((genome, _)) = best_genome {
+            let network = genome.network();
+            perceptron.draw_model(&plot, enable_shading, |inputs| network.output(inputs));
+            let (width, height) = (px(NEAT_GENOME_SIZE.0), px(NEAT_GENOME_SIZE.1));
+            let x = screen_width() - width - px(LEFT_PADDING) * 2.0;
+            draw_custom_text("Best genome", x, px(30.0), STATUS_FONT_SIZE, WHITE);
+            genome.draw(x, px(40.0), width, height);
+        } else if input_gradient == InputGradient::ArrowAndHeatmap {
+            perceptron.draw_input_gradient_heatmap(&plot);
+        } else if ensemble.len() > 1 {
+            ensemble.draw(&plot, enable_shading);
+        } else {
+            perceptron.draw(&plot, enable_shading);
+        }
+
+        if input_gradient != InputGradient::Off && is_mouse_outside_ui {
+            perceptron.draw_input_gradient(&plot, mouse_f32.0, mouse_f32.1);
+        }
+
+        if let Some(landscape) = landscape
+            .as_ref()
+            .filter(|_| landscape_directions.is_some())
+        {
+            let size = px(LANDSCAPE_SIZE);
+            let x = screen_width() - size - px(LEFT_PADDING) * 2.0;
+            let y = y_ui - size - px(LEFT_PADDING) * 2.0;
+            let title = match landscape.explained_variance() {
+                Some([first, second]) => format!(
+                    "Loss landscape (PCA {:.0}% + {:.0}%)",
+                    first * 100.0,
+                    second * 100.0
+                ),
+                None => "Loss landscape (random)".to_owned(),
+            };
+            draw_custom_text(&title, x, y - px(8.0), STATUS_FONT_SIZE, WHITE);
+            landscape.draw(&trajectory, x, y, size);
+        }
+
+        if let Some(values) = neuron_heatmaps.filter(|_| evolution.is_none()) {
+            let size = px(NEURON_HEATMAP_SIZE);
+            // Each heatmap is followed by a gap of an eighth of its size.
+            let width = NEURONS_PER_LAYER as f32 * size * 1.125;
+            let x = screen_width() - width - px(LEFT_PADDING);
+            draw_custom_text(
+                match values {
+                    NeuronValues::Activations => "Neuron activations",
+                    NeuronValues::PreActivations => "Neuron pre-activations",
+                },
+                x,
+                px(30.0),
+                STATUS_FONT_SIZE,
+                WHITE,
+            );
+            perceptron.draw_neuron_heatmaps(x, px(40.0), size, values);
+        }
 
         let updates_per_frame_text = format!("Speed: {updates_per_frame}");
         updates_per_frame = Button::at(updates_per_frame_rect)
@@ -222,7 +722,12 @@ async fn main() {
                 GRAY,
             ) as i32;
 
-        let learning_speed_text = format!("LR: {learning_rate:0.2}");
+        let learning_speed_text = match trainer.optimizer() {
+            Optimizer::GradientFree { step_size, .. } => format!("Step: {step_size:0.3}"),
+            // The line search chooses the step length.
+            Optimizer::SecondOrder { .. } => "LR: auto".to_owned(),
+            Optimizer::Sgd { .. } => format!("LR: {learning_rate:0.2}"),
+        };
         learning_speed = Button::at(learning_speed_rect)
             .with_background(BLACK)
             .with_text(&learning_speed_text, BUTTON_FONT_SIZE, WHITE)
@@ -234,13 +739,153 @@ async fn main() {
                 GRAY,
             ) as i32;
 
-        draw_custom_text(
-            &format!(
-                "Loss: {:0.4?} Acc: {}% Params: {}",
+        let regularization_text = if let Some(regularization) = perceptron.regularization() {
+            format!(
+                " Reg ({:?} {:e}{}): {:0.4?}",
+                regularization.penalty,
+                regularization.strength,
+                if regularization.include_biases {
+                    " +B"
+                } else {
+                    ""
+                },
+                perceptron.regularization_loss()
+            )
+        } else {
+            String::new()
+        };
+        let stop_text = match trainer.stop_reason() {
+            Some(reason) => format!(" Stopped: {reason}"),
+            None if trainer.early_stopping().is_enabled() => " Early stopping".to_owned(),
+            None => String::new(),
+        };
+        let optimizer_text = match trainer.optimizer() {
+            // Gradient descent gets much slower when it can't use every
+            // core, so it's worth pointing out.
+            Optimizer::Sgd { .. } => match perceptron.serial_gradients_reason() {
+                Some(reason) => format!(" Single-threaded ({reason})"),
+                None => String::new(),
+            },
+            optimizer => {
+                let search_text = if let Some(search) = trainer.gradient_free_search() {
+                    format!(" Step: {:0.4}", search.step_size())
+                } else if let Some(search) = trainer.second_order_search() {
+                    let line_search_text = match search.line_search() {
+                        Some(line_search) if line_search.accepted => format!(
+                            " Line search: t={:0.4} ({} evals, -{:0.2e})",
+                            line_search.step_length, line_search.evaluations, line_search.decrease
+                        ),
+                        Some(_) => " Line search: failed".to_owned(),
+                        None => String::new(),
+                    };
+                    let damping_text = search
+                        .damping()
+                        .map(|damping| format!(" Damping: {damping:0.1e}"))
+                        .unwrap_or_default();
+                    line_search_text + &damping_text
+                } else {
+                    String::new()
+                };
+                format!(
+                    " {} Evals/step: {}{}",
+                    optimizer.name(),
+                    trainer.metrics().evaluations,
+                    search_text
+                )
+            }
+        };
+        let features_text = if architecture.features_index > 0 {
+            format!(" Inputs: {}", perceptron.features().description())
+        } else {
+            String::new()
+        };
+        let dropout_text = if architecture.num_hidden_layers > 0 && perceptron.dropout() > 0.0 {
+            format!(" Dropout: {}", perceptron.dropout())
+        } else {
+            String::new()
+        };
+        let ensemble_text = if ensemble.len() > 1 {
+            let combined = match (ensemble.loss_and_accuracy(), perceptron.task()) {
+                (Some((_, accuracy)), Task::Classification | Task::MultiClass { .. }) => {
+                    format!(" Acc: {}%", (accuracy * 100.0).floor())
+                }
+                (Some((loss, _)), Task::Regression) => format!(" MSE: {loss:0.4?}"),
+                (None, _) => String::new(),
+            };
+            format!(
+                " Ensemble ({} {:?}){}",
+                ensemble.len(),
+                ensemble.combination(),
+                combined
+            )
+        } else {
+            String::new()
+        };
+        let error_text = match perceptron.task() {
+            Task::Classification | Task::MultiClass { .. } => format!(
+                "Loss: {:0.4?}{}{} Acc: {}%",
+                perceptron.loss(),
+                regularization_text,
+                dropout_text,
+                (perceptron.accuracy() * 100.0).floor()
+            ),
+            Task::Regression => format!(
+                "MSE: {:0.4?}{}{} MAE: {:0.4?}",
                 perceptron.loss(),
-                (perceptron.accuracy() * 100.0).floor(),
-                perceptron.num_params()
+                regularization_text,
+                dropout_text,
+                perceptron.mean_absolute_error()
             ),
+        };
+        let metrics = trainer.metrics();
+        let validation_text = match (metrics.validation_loss, metrics.validation_accuracy) {
+            (Some(loss), Some(accuracy)) => match perceptron.task() {
+                Task::Classification | Task::MultiClass { .. } => format!(
+                    " Val loss: {:0.4?} Val acc: {}%",
+                    loss,
+                    (accuracy * 100.0).floor()
+                ),
+                Task::Regression => format!(" Val MSE: {loss:0.4?}"),
+            },
+            _ => String::new(),
+        };
+        let status_text = match &evolution {
+            Some(population) => {
+                let best_text = match best_genome {
+                    Some((genome, score)) => format!(
+                        " Best: {} Hidden: {} Connections: {}",
+                        match perceptron.task() {
+                            Task::Classification | Task::MultiClass { .. } => {
+                                format!("Acc: {}%", (score.accuracy * 100.0).floor())
+                            }
+                            Task::Regression => format!("MSE: {:0.4?}", score.loss),
+                        },
+                        genome.num_hidden(),
+                        genome.num_enabled_connections()
+                    ),
+                    None => String::new(),
+                };
+                format!(
+                    "NEAT Gen: {} Species: {}{}{}",
+                    population.generation(),
+                    population.num_species(),
+                    best_text,
+                    features_text
+                )
+            }
+            None => format!(
+                "{}{} Params: {}{}{}{}{}",
+                error_text,
+                validation_text,
+                perceptron.num_params(),
+                optimizer_text,
+                features_text,
+                ensemble_text,
+                stop_text
+            ),
+        };
+        draw_custom_text(
+            &status_text,
             px(LEFT_PADDING),
             y_stats,
             STATUS_FONT_SIZE,
@@ -248,32 +893,75 @@ async fn main() {
         );
 
         if Button::at(label_button_rect)
-            .with_background(if let Some(label) = current_brush {
-                label.color()
+            .with_background(if let Some(target) = current_brush {
+                target.color()
             } else {
                 BLACK
             })
             .clicked()
+            || is_key_pressed(KeyCode::Tab)
         {
-            current_brush = match current_brush {
-                None => Some(Label2D::Blue),
-                Some(Label2D::Blue) => Some(Label2D::Red),
-                Some(Label2D::Red) => None,
-            };
+            current_brush = next_brush(current_brush, &brush_targets);
         }
 
         if Button::at(label_arch_rect)
-            .with_text(
-                get_layer_notation(num_hidden_layers),
-                BUTTON_FONT_SIZE,
-                WHITE,
-            )
+            .with_text(&perceptron.weights().notation(), BUTTON_FONT_SIZE, WHITE)
             .with_background(BLACK)
             .clicked()
             || is_key_pressed(KeyCode::L)
         {
-            num_hidden_layers = (num_hidden_layers + 1) % MAX_HIDDEN_LAYERS;
-            perceptron = make_perceptron(&datapoints, num_hidden_layers);
+            architecture.num_hidden_layers =
+                (architecture.num_hidden_layers + 1) % (MAX_HIDDEN_LAYERS + 1);
+            ensemble.replace_classifiers(|| architecture.make_perceptron(&datapoints));
+        }
+
+        if is_key_pressed(KeyCode::N) {
+            architecture.normalization_index =
+                (architecture.normalization_index + 1) % NORMALIZATIONS.len();
+            ensemble.replace_classifiers(|| architecture.make_perceptron(&datapoints));
+        }
+
+        if is_key_pressed(KeyCode::J) {
+            architecture.residual = !architecture.residual;
+            ensemble.replace_classifiers(|| architecture.make_perceptron(&datapoints));
+        }
+
+        let next_task = if is_key_pressed(KeyCode::T) {
+            Some(match architecture.task {
+                Task::Classification | Task::MultiClass { .. } => Task::Regression,
+                Task::Regression => Task::Classification,
+            })
+        } else if is_key_pressed(KeyCode::Q) {
+            Some(match architecture.task {
+                Task::Classification => Task::MultiClass {
+                    num_classes: MIN_MULTI_CLASSES,
+                },
+                Task::MultiClass { num_classes } if num_classes < MAX_CLASSES => Task::MultiClass {
+                    num_classes: num_classes + 1,
+                },
+                Task::MultiClass { .. } | Task::Regression => Task::Classification,
+            })
+        } else {
+            None
+        };
+        if let Some(task) = next_task {
+            architecture.task = task;
+            current_brush = brushes(task).first().copied();
+            if task.num_outputs() > 1 {
+                evolution = None;
+            }
+            relabel_datapoints(task, &mut datapoints);
+            ensemble.replace_classifiers(|| architecture.make_perceptron(&datapoints));
+        }
+
+        if is_key_pressed(KeyCode::I) {
+            architecture.features_index = (architecture.features_index + 1) % NUM_FEATURE_SETS;
+            ensemble.replace_classifiers(|| architecture.make_perceptron(&datapoints));
+        }
+
+        if is_key_pressed(KeyCode::O) {
+            architecture.output_skips = !architecture.output_skips;
+            ensemble.replace_classifiers(|| architecture.make_perceptron(&datapoints));
         }
 
         if Button::at(clear_rect)
@@ -284,6 +972,36 @@ async fn main() {
             did_click_clear_button = true;
         }
 
+        if show_summary && !show_help {
+            let summary = ensemble.primary().classifier().weights().summary();
+            let summary = summary.to_string();
+            let lines: Vec<&str> = summary.lines().collect();
+            let line_height = px(SUMMARY_LINE_HEIGHT);
+            let width = lines
+                .iter()
+                .map(|line| line.chars().count())
+                .max()
+                .unwrap_or(0) as f32
+                * px(SUMMARY_CHAR_WIDTH);
+            let top = px(45.0);
+            draw_rectangle(
+                px(LEFT_PADDING),
+                top,
+                width + px(LEFT_PADDING) * 2.0,
+                (lines.len() as f32 + 0.5) * line_height,
+                BLACK.with_alpha(0.8),
+            );
+            for (index, line) in lines.iter().enumerate() {
+                draw_custom_text(
+                    line,
+                    px(LEFT_PADDING) * 2.0,
+                    top + (index as f32 + 1.0) * line_height,
+                    STATUS_FONT_SIZE,
+                    WHITE,
+                );
+            }
+        }
+
         if show_help {
             for (index, line) in help_lines.iter().enumerate() {
                 draw_custom_text(
@@ -325,27 +1043,35 @@ async fn main() {
 
 /// Modifies the datapoint with the given point.
 ///
-/// If the label is none, the datapoint is removed (if it exists).
+/// If the target is none, the datapoint is removed (if it exists).
 ///
-/// Otherwise, the datapoint is modified to have the given label (or if it's not in
-/// datapoints, it's added).
+/// Otherwise, the datapoint is modified to have the given target and to
+/// be a validation point or not (or if it's not in datapoints, it's added).
 ///
 /// Returns whether the datapoints were changed.
 fn modify_datapoint(
     datapoints: &mut Vec<Datapoint2D>,
     point: (i32, i32),
-    label: Option<Label2D>,
+    target: Option<Target2D>,
+    validation: bool,
 ) -> bool {
-    if let Some(label) = label {
+    if let Some(target) = target {
         if let Some(dp) = datapoints.iter_mut().find(|dp| dp.pos == point) {
-            if dp.label != label {
-                info!("Changing label of {:?} to {:?}.", point, label);
-                dp.label = label;
+            if dp.target != target || dp.validation != validation {
+                info!(
+                    "Changing target of {:?} to {:?} (validation: {}).",
+                    point, target, validation
+                );
+                dp.target = target;
+                dp.validation = validation;
                 return true;
             }
         } else {
-            info!("Adding datapoint at {:?} with label {:?}.", point, label);
-            datapoints.push(Datapoint2D::new(point, label));
+            info!(
+                "Adding datapoint at {:?} with target {:?} (validation: {}).",
+                point, target, validation
+            );
+            datapoints.push(Datapoint2D::new(point, target).with_validation(validation));
             return true;
         }
     } else {
@@ -358,20 +1084,142 @@ fn modify_datapoint(
     false
 }
 
-fn get_layer_notation(num_hidden_layers: usize) -> &'static str {
-    if num_hidden_layers == 0 {
-        "2-1"
-    } else if num_hidden_layers == 1 {
-        "2-16-1"
-    } else {
-        "2-16-16-1"
+/// Returns the targets that can be painted for the given task.
+fn brushes(task: Task) -> Vec<Target2D> {
+    match task {
+        Task::Classification => vec![Label2D::Blue.into(), Label2D::Red.into()],
+        Task::MultiClass { num_classes } => Label2D::ALL[..num_classes]
+            .iter()
+            .map(|&label| label.into())
+            .collect(),
+        Task::Regression => REGRESSION_BRUSH_VALUES
+            .iter()
+            .map(|&value| value.into())
+            .collect(),
+    }
+}
+
+/// Gives datapoints whose label the task doesn't have the task's last
+/// label instead, since otherwise they would silently count as red.
+fn relabel_datapoints(task: Task, datapoints: &mut [Datapoint2D]) {
+    let num_labels = match task {
+        Task::MultiClass { num_classes } => num_classes,
+        Task::Classification | Task::Regression => 2,
+    };
+    let last_label = Label2D::from_index(num_labels - 1);
+    let mut num_relabeled = 0;
+    for datapoint in datapoints {
+        if let Target2D::Label(label) = datapoint.target
+            && label.index() >= num_labels
+        {
+            datapoint.target = last_label.into();
+            num_relabeled += 1;
+        }
+    }
+    if num_relabeled > 0 {
+        info!(
+            "Relabeled {} datapoints as {}.",
+            num_relabeled,
+            last_label.name()
+        );
+    }
+}
+
+/// Returns the brush after the current one, cycling through the brushes
+/// and then the eraser, which is `None`.
+fn next_brush(current: Option<Target2D>, brushes: &[Target2D]) -> Option<Target2D> {
+    match current {
+        None => brushes.first().copied(),
+        Some(target) => brushes
+            .iter()
+            .skip_while(|&&brush| brush != target)
+            .nth(1)
+            .copied(),
+    }
+}
+
+/// Logs why training stopped early.
+struct StopLogger;
+
+impl TrainingObserver for StopLogger {
+    fn on_stop(&mut self, reason: StopReason, metrics: &Metrics) {
+        info!(
+            "Training stopped after {} steps ({} epochs): {}.",
+            metrics.step, metrics.epoch, reason
+        );
+    }
+}
+
+/// How the gradient of the output with respect to the inputs is shown.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum InputGradient {
+    Off,
+    Arrow,
+    ArrowAndHeatmap,
+}
+
+/// The shape of the neural net being trained.
+#[derive(Default)]
+struct Architecture {
+    num_hidden_layers: usize,
+    /// Index into `NORMALIZATIONS`.
+    normalization_index: usize,
+    /// Whether every hidden layer after the first is a residual block.
+    residual: bool,
+    /// Whether every hidden layer is connected to the output.
+    output_skips: bool,
+    task: Task,
+    /// Which set of input features to use, from 0 to `NUM_FEATURE_SETS`.
+    features_index: usize,
+}
+
+impl Architecture {
+    fn normalization(&self) -> Option<LayerSpec> {
+        NORMALIZATIONS[self.normalization_index]
+    }
+
+    fn features(&self) -> Features {
+        match self.features_index {
+            0 => Features::default(),
+            1 => Features::default().with_polynomial(),
+            2 => Features::default().with_trigonometric(),
+            3 => Features::default().with_polynomial().with_trigonometric(),
+            _ => {
+                Features::default().with_random_fourier(NUM_FOURIER_FEATURES, FOURIER_FEATURE_SCALE)
+            }
+        }
+    }
+
+    fn make_perceptron(&self, datapoints: &[Datapoint2D]) -> Classifier2D {
+        let mut hidden_layers = vec![];
+        for index in 0..self.num_hidden_layers {
+            // The first hidden layer changes the width of the network,
+            // so it can't be a residual block.
+            hidden_layers.push(if self.residual && index > 0 {
+                LayerSpec::Residual(1)
+            } else {
+                LayerSpec::Dense(NEURONS_PER_LAYER)
+            });
+            if let Some(normalization) = self.normalization() {
+                hidden_layers.push(normalization);
+            }
+        }
+        let features = self.features();
+        let weights =
+            Weights2D::new(features.len(), hidden_layers, self.output_skips).for_task(self.task);
+        Classifier2D::new(datapoints.to_vec(), weights)
+            .with_features(features)
+            .with_task(self.task)
     }
 }
 
-fn make_perceptron(datapoints: &Vec<Datapoint2D>, num_hidden_layers: usize) -> Classifier2D {
-    Classifier2D::new(
-        datapoints.clone(),
-        Weights2D::new((0..num_hidden_layers).map(|_| NEURONS_PER_LAYER).collect()),
+/// Starts evolving minimal genomes that fit the classifier's datapoints.
+fn new_population(classifier: &Classifier2D) -> Population {
+    Population::new(
+        classifier.features().len(),
+        classifier.task().output_activation(),
+        NEAT_POPULATION_SIZE,
+        rand::rand() as u64,
     )
 }
 
@@ -0,0 +1,609 @@
+use std::{fmt::Display, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::engine::ActivationType;
+
+/// The version of the model file format written by this code. It
+/// should be bumped whenever the format changes in a way that older
+/// versions can't read.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// The oldest version of the model file format we can still read.
+/// Version 1 files have no residual blocks or output skips.
+const MIN_FORMAT_VERSION: u32 = 1;
+
+/// The first bytes of every binary model file, which is also how we
+/// tell binary files apart from JSON ones.
+const BINARY_MAGIC: &[u8; 4] = b"NNFM";
+
+/// How deeply residual blocks can be nested in a binary file, so that
+/// corrupt files can't make reading them overflow the stack.
+const MAX_RESIDUAL_DEPTH: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModelFormat {
+    /// Human-readable JSON.
+    Json,
+    /// A compact little-endian binary format.
+    Binary,
+}
+
+/// Optional information about how a model was made.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub training_steps: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+/// The on-disk representation of a trained neural net.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModelFile {
+    pub version: u32,
+    pub num_inputs: usize,
+    pub dropout: f64,
+    /// Whether the final layer sees the outputs of all the hidden
+    /// dense layers rather than just the last hidden layer.
+    #[serde(default)]
+    pub output_skips: bool,
+    pub layers: Vec<LayerFile>,
+    #[serde(default)]
+    pub metadata: ModelMetadata,
+}
+
+/// The on-disk representation of a single layer of a neural net.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LayerFile {
+    Dense {
+        activation: ActivationType,
+        /// One row of weights per neuron.
+        weights: Vec<Vec<f64>>,
+        /// One bias per neuron.
+        biases: Vec<f64>,
+    },
+    Activation {
+        activation: ActivationType,
+        width: usize,
+    },
+    BatchNorm {
+        scale: Vec<f64>,
+        shift: Vec<f64>,
+        running_mean: Vec<f64>,
+        running_variance: Vec<f64>,
+    },
+    LayerNorm {
+        scale: Vec<f64>,
+        shift: Vec<f64>,
+    },
+    Residual {
+        /// The layers inside the residual block.
+        layers: Vec<LayerFile>,
+    },
+}
+
+#[derive(Debug)]
+pub enum ModelFileError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// The binary file is truncated or otherwise malformed.
+    Binary(String),
+    UnsupportedVersion(u32),
+    /// The layers in the file don't fit together, or don't fit the
+    /// network they're being loaded into.
+    ShapeMismatch(String),
+}
+
+impl Display for ModelFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModelFileError::Io(err) => write!(f, "I/O error: {err}"),
+            ModelFileError::Json(err) => write!(f, "invalid JSON model file: {err}"),
+            ModelFileError::Binary(message) => write!(f, "invalid binary model file: {message}"),
+            ModelFileError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported model file version {version} (expected {MIN_FORMAT_VERSION} to {FORMAT_VERSION})"
+            ),
+            ModelFileError::ShapeMismatch(message) => write!(f, "shape mismatch: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ModelFileError {}
+
+impl From<std::io::Error> for ModelFileError {
+    fn from(value: std::io::Error) -> Self {
+        ModelFileError::Io(value)
+    }
+}
+
+impl From<serde_json::Error> for ModelFileError {
+    fn from(value: serde_json::Error) -> Self {
+        ModelFileError::Json(value)
+    }
+}
+
+impl ModelFile {
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: ModelFormat) -> Result<(), ModelFileError> {
+        let bytes = match format {
+            ModelFormat::Json => self.to_json().into_bytes(),
+            ModelFormat::Binary => self.to_binary(),
+        };
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// Loads a model file in either format.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ModelFileError> {
+        let bytes = std::fs::read(path)?;
+        if bytes.starts_with(BINARY_MAGIC) {
+            Self::from_binary(&bytes)
+        } else {
+            Self::from_json(&String::from_utf8_lossy(&bytes))
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ModelFileError> {
+        // Check the version before anything else, so that files from
+        // newer versions get a helpful error even if their structure
+        // has changed.
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: u32,
+        }
+        let versioned: Versioned = serde_json::from_str(json)?;
+        check_version(versioned.version)?;
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut writer = BinaryWriter::default();
+        writer.bytes.extend_from_slice(BINARY_MAGIC);
+        writer.u32(self.version);
+        writer.u32(self.num_inputs as u32);
+        writer.f64(self.dropout);
+        if self.version >= 2 {
+            writer.u8(self.output_skips as u8);
+        }
+        writer.optional_u64(self.metadata.training_steps);
+        writer.optional_u64(self.metadata.seed);
+        writer.u32(self.layers.len() as u32);
+        for layer in &self.layers {
+            writer.layer(layer);
+        }
+        writer.bytes
+    }
+
+    pub fn from_binary(bytes: &[u8]) -> Result<Self, ModelFileError> {
+        let mut reader = BinaryReader { bytes, offset: 0 };
+        if reader.take(BINARY_MAGIC.len())? != BINARY_MAGIC {
+            return Err(ModelFileError::Binary("missing magic bytes".to_owned()));
+        }
+        let version = reader.u32()?;
+        check_version(version)?;
+        let num_inputs = reader.u32()? as usize;
+        let dropout = reader.f64()?;
+        let output_skips = version >= 2 && reader.u8()? != 0;
+        let metadata = ModelMetadata {
+            training_steps: reader.optional_u64()?,
+            seed: reader.optional_u64()?,
+        };
+        let num_layers = reader.u32()?;
+        let mut layers = vec![];
+        for _ in 0..num_layers {
+            layers.push(reader.layer(0)?);
+        }
+        if reader.offset != bytes.len() {
+            return Err(ModelFileError::Binary(
+                "unexpected trailing data".to_owned(),
+            ));
+        }
+        Ok(ModelFile {
+            version,
+            num_inputs,
+            dropout,
+            output_skips,
+            layers,
+            metadata,
+        })
+    }
+}
+
+fn check_version(version: u32) -> Result<(), ModelFileError> {
+    if (MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(ModelFileError::UnsupportedVersion(version))
+    }
+}
+
+fn activation_code(activation: ActivationType) -> u8 {
+    match activation {
+        ActivationType::Sigmoid => 0,
+        ActivationType::Tanh => 1,
+        ActivationType::Relu => 2,
+        ActivationType::Linear => 3,
+    }
+}
+
+fn activation_from_code(code: u8) -> Result<ActivationType, ModelFileError> {
+    match code {
+        0 => Ok(ActivationType::Sigmoid),
+        1 => Ok(ActivationType::Tanh),
+        2 => Ok(ActivationType::Relu),
+        3 => Ok(ActivationType::Linear),
+        _ => Err(ModelFileError::Binary(format!(
+            "unknown activation type {code}"
+        ))),
+    }
+}
+
+#[derive(Default)]
+struct BinaryWriter {
+    bytes: Vec<u8>,
+}
+
+impl BinaryWriter {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f64s(&mut self, values: &[f64]) {
+        for &value in values {
+            self.f64(value);
+        }
+    }
+
+    fn optional_u64(&mut self, value: Option<u64>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn layer(&mut self, layer: &LayerFile) {
+        match layer {
+            LayerFile::Dense {
+                activation,
+                weights,
+                biases,
+            } => {
+                self.u8(0);
+                self.u8(activation_code(*activation));
+                self.u32(weights.len() as u32);
+                self.u32(weights.first().map(|row| row.len()).unwrap_or(0) as u32);
+                for row in weights {
+                    self.f64s(row);
+                }
+                self.f64s(biases);
+            }
+            LayerFile::Activation { activation, width } => {
+                self.u8(3);
+                self.u8(activation_code(*activation));
+                self.u32(*width as u32);
+            }
+            LayerFile::BatchNorm {
+                scale,
+                shift,
+                running_mean,
+                running_variance,
+            } => {
+                self.u8(1);
+                self.u32(scale.len() as u32);
+                self.f64s(scale);
+                self.f64s(shift);
+                self.f64s(running_mean);
+                self.f64s(running_variance);
+            }
+            LayerFile::LayerNorm { scale, shift } => {
+                self.u8(2);
+                self.u32(scale.len() as u32);
+                self.f64s(scale);
+                self.f64s(shift);
+            }
+            LayerFile::Residual { layers } => {
+                self.u8(4);
+                self.u32(layers.len() as u32);
+                for layer in layers {
+                    self.layer(layer);
+                }
+            }
+        }
+    }
+}
+
+struct BinaryReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> BinaryReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ModelFileError> {
+        let end = self.offset + len;
+        if end > self.bytes.len() {
+            return Err(ModelFileError::Binary("unexpected end of file".to_owned()));
+        }
+        let slice = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, ModelFileError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ModelFileError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, ModelFileError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, ModelFileError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f64s(&mut self, len: usize) -> Result<Vec<f64>, ModelFileError> {
+        self.expect_f64s(len)?;
+        (0..len).map(|_| self.f64()).collect()
+    }
+
+    /// Fails unless there are at least `len` more `f64`s to read, so
+    /// that corrupt sizes are caught before anything is allocated.
+    fn expect_f64s(&self, len: usize) -> Result<(), ModelFileError> {
+        match len.checked_mul(8) {
+            Some(num_bytes) if num_bytes <= self.bytes.len() - self.offset => Ok(()),
+            _ => Err(ModelFileError::Binary("unexpected end of file".to_owned())),
+        }
+    }
+
+    fn optional_u64(&mut self) -> Result<Option<u64>, ModelFileError> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.u64()?)),
+        }
+    }
+
+    /// Reads a layer that's nested inside `depth` residual blocks.
+    fn layer(&mut self, depth: usize) -> Result<LayerFile, ModelFileError> {
+        Ok(match self.u8()? {
+            0 => {
+                let activation = activation_from_code(self.u8()?)?;
+                let num_outputs = self.u32()? as usize;
+                let num_weights = self.u32()? as usize;
+                if num_outputs == 0 {
+                    return Err(ModelFileError::Binary(
+                        "dense layer with no outputs".to_owned(),
+                    ));
+                }
+                self.expect_f64s(num_outputs.saturating_mul(num_weights + 1))?;
+                let mut weights = vec![];
+                for _ in 0..num_outputs {
+                    weights.push(self.f64s(num_weights)?);
+                }
+                LayerFile::Dense {
+                    activation,
+                    weights,
+                    biases: self.f64s(num_outputs)?,
+                }
+            }
+            1 => {
+                let width = self.u32()? as usize;
+                self.expect_f64s(width.saturating_mul(4))?;
+                LayerFile::BatchNorm {
+                    scale: self.f64s(width)?,
+                    shift: self.f64s(width)?,
+                    running_mean: self.f64s(width)?,
+                    running_variance: self.f64s(width)?,
+                }
+            }
+            2 => {
+                let width = self.u32()? as usize;
+                self.expect_f64s(width.saturating_mul(2))?;
+                LayerFile::LayerNorm {
+                    scale: self.f64s(width)?,
+                    shift: self.f64s(width)?,
+                }
+            }
+            3 => LayerFile::Activation {
+                activation: activation_from_code(self.u8()?)?,
+                width: self.u32()? as usize,
+            },
+            4 => {
+                if depth >= MAX_RESIDUAL_DEPTH {
+                    return Err(ModelFileError::Binary(
+                        "residual blocks nested too deeply".to_owned(),
+                    ));
+                }
+                let num_layers = self.u32()?;
+                let mut layers = vec![];
+                for _ in 0..num_layers {
+                    layers.push(self.layer(depth + 1)?);
+                }
+                LayerFile::Residual { layers }
+            }
+            tag => return Err(ModelFileError::Binary(format!("unknown layer type {tag}"))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::MultiLayerPerceptron, layer::LayerSpec, value::Value};
+
+    fn make_mlp() -> MultiLayerPerceptron<Value> {
+        MultiLayerPerceptron::from_specs(
+            2,
+            ActivationType::Sigmoid,
+            vec![
+                LayerSpec::Dense(4),
+                LayerSpec::BatchNorm,
+                LayerSpec::Dense(3),
+                LayerSpec::LayerNorm,
+                LayerSpec::Activation(ActivationType::Relu),
+                LayerSpec::Dense(1),
+            ],
+        )
+    }
+
+    fn assert_same_outputs(a: &MultiLayerPerceptron<f64>, b: &MultiLayerPerceptron<f64>) {
+        for inputs in [vec![0.1, 0.2], vec![-0.5, 0.9], vec![1.0, -1.0]] {
+            assert_eq!(a.output(&inputs), b.output(&inputs));
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mlp = make_mlp();
+        let metadata = ModelMetadata {
+            training_steps: Some(123),
+            seed: None,
+        };
+        let json = mlp.to_model_file(metadata.clone()).unwrap().to_json();
+        let file = ModelFile::from_json(&json).unwrap();
+        assert_eq!(file.metadata, metadata);
+        let loaded = MultiLayerPerceptron::<f64>::from_model_file(&file).unwrap();
+        assert_same_outputs(&mlp.read_only(), &loaded);
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let mlp = make_mlp();
+        let file = mlp
+            .to_model_file(ModelMetadata {
+                training_steps: Some(5),
+                seed: Some(42),
+            })
+            .unwrap();
+        let loaded_file = ModelFile::from_binary(&file.to_binary()).unwrap();
+        assert_eq!(loaded_file, file);
+        let loaded = MultiLayerPerceptron::<Value>::from_model_file(&loaded_file).unwrap();
+        assert_same_outputs(&mlp.read_only(), &loaded.read_only());
+    }
+
+    #[test]
+    fn test_residual_and_output_skips_round_trip() {
+        let mlp = MultiLayerPerceptron::from_specs_with_output_skips(
+            2,
+            ActivationType::Tanh,
+            vec![
+                LayerSpec::Dense(4),
+                LayerSpec::Residual(2),
+                LayerSpec::LayerNorm,
+                LayerSpec::Dense(1),
+            ],
+        );
+        let file = mlp.to_model_file(Default::default()).unwrap();
+        assert!(file.output_skips);
+        let from_json = ModelFile::from_json(&file.to_json()).unwrap();
+        let from_binary = ModelFile::from_binary(&file.to_binary()).unwrap();
+        assert_eq!(from_json, file);
+        assert_eq!(from_binary, file);
+        let loaded = MultiLayerPerceptron::<f64>::from_model_file(&from_binary).unwrap();
+        assert!(loaded.output_skips());
+        assert_same_outputs(&mlp.read_only(), &loaded);
+    }
+
+    #[test]
+    fn test_version_1_files_still_load() {
+        let mut file = make_mlp().to_model_file(Default::default()).unwrap();
+        file.version = 1;
+        let json = file.to_json().replace("  \"output_skips\": false,\n", "");
+        assert!(!json.contains("output_skips"));
+        assert_eq!(ModelFile::from_json(&json).unwrap(), file);
+    }
+
+    #[test]
+    fn test_truncated_binary_fails() {
+        let bytes = make_mlp()
+            .to_model_file(Default::default())
+            .unwrap()
+            .to_binary();
+        let err = ModelFile::from_binary(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid binary model file: unexpected end of file"
+        );
+    }
+
+    /// Returns a binary file whose only layer is made of the given bytes.
+    fn binary_with_layer(layer: &[u8]) -> Vec<u8> {
+        let mut file = make_mlp().to_model_file(Default::default()).unwrap();
+        file.layers.clear();
+        let mut bytes = file.to_binary();
+        bytes.truncate(bytes.len() - 4);
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(layer);
+        bytes
+    }
+
+    #[test]
+    fn test_corrupt_sizes_fail() {
+        let mut huge_layer = vec![0, 0];
+        huge_layer.extend_from_slice(&u32::MAX.to_le_bytes());
+        huge_layer.extend_from_slice(&0u32.to_le_bytes());
+        let err = ModelFile::from_binary(&binary_with_layer(&huge_layer)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid binary model file: unexpected end of file"
+        );
+
+        let empty_layer = [0; 10];
+        let err = ModelFile::from_binary(&binary_with_layer(&empty_layer)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid binary model file: dense layer with no outputs"
+        );
+    }
+
+    #[test]
+    fn test_deeply_nested_residual_blocks_fail() {
+        let nested: Vec<u8> = (0..10_000).flat_map(|_| [4, 1, 0, 0, 0]).collect();
+        let err = ModelFile::from_binary(&binary_with_layer(&nested)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid binary model file: residual blocks nested too deeply"
+        );
+    }
+
+    #[test]
+    fn test_unsupported_version_fails() {
+        let mut file = make_mlp().to_model_file(Default::default()).unwrap();
+        file.version = FORMAT_VERSION + 1;
+        let err = ModelFile::from_json(&file.to_json()).unwrap_err();
+        assert!(matches!(err, ModelFileError::UnsupportedVersion(_)));
+    }
+
+    #[test]
+    fn test_shape_mismatch_fails() {
+        let mut file = make_mlp().to_model_file(Default::default()).unwrap();
+        let LayerFile::Dense { weights, .. } = &mut file.layers[2] else {
+            panic!("expected a dense layer");
+        };
+        weights[0].pop();
+        let err = MultiLayerPerceptron::<f64>::from_model_file(&file).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "shape mismatch: layer 2 neuron 0 has 3 weights but the layer has 4 inputs"
+        );
+    }
+}
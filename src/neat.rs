@@ -0,0 +1,889 @@
+use std::collections::{HashMap, HashSet};
+
+use macroquad::prelude::*;
+
+use crate::{
+    classifier_2d::{Classifier2D, Task},
+    engine::{ActivationType, SplitMix64},
+    zoom::px,
+};
+
+/// The activation functions that new hidden nodes can be given.
+const HIDDEN_ACTIVATIONS: [ActivationType; 3] = [
+    ActivationType::Tanh,
+    ActivationType::Relu,
+    ActivationType::Sigmoid,
+];
+
+/// The probability that a child's weights are changed at all.
+const WEIGHT_MUTATION_RATE: f64 = 0.8;
+
+/// The standard deviation of the changes to a child's weights.
+const WEIGHT_PERTURBATION_SCALE: f64 = 0.5;
+
+/// The probability that a weight being changed is replaced with a new
+/// random one instead of being nudged.
+const WEIGHT_REPLACEMENT_RATE: f64 = 0.1;
+
+const ADD_CONNECTION_RATE: f64 = 0.15;
+
+const ADD_NODE_RATE: f64 = 0.05;
+
+/// The number of random pairs of nodes that are tried when adding a
+/// connection, before giving up.
+const ADD_CONNECTION_ATTEMPTS: usize = 20;
+
+/// The probability that a child has two parents, rather than being a
+/// mutated copy of one.
+const CROSSOVER_RATE: f64 = 0.75;
+
+/// The probability that a connection which is disabled in either parent
+/// is disabled in the child.
+const DISABLED_GENE_RATE: f64 = 0.75;
+
+/// Genomes further apart than this, as measured by
+/// [Genome::distance], belong to different species.
+const COMPATIBILITY_THRESHOLD: f64 = 3.0;
+
+/// How much the average difference in the weights of shared connections
+/// contributes to [Genome::distance].
+const WEIGHT_DIFFERENCE_COEFFICIENT: f64 = 0.4;
+
+/// Species whose best fitness hasn't improved for this many
+/// generations stop having children, unless they contain the best
+/// genome.
+const MAX_STAGNATION: usize = 20;
+
+/// How much the loss lowers the fitness of a classifier, which lets
+/// genomes with the same accuracy be told apart.
+const LOSS_PENALTY: f64 = 0.1;
+
+/// What a node of a genome does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    Input,
+    /// Always outputs 1.
+    Bias,
+    Hidden,
+    Output,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeGene {
+    /// Identifies the node across all the genomes of a population.
+    pub id: usize,
+    pub kind: NodeKind,
+    pub activation: ActivationType,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionGene {
+    pub from: usize,
+    pub to: usize,
+    pub weight: f64,
+    pub enabled: bool,
+    /// Identifies the connection across all the genomes of a
+    /// population, so genomes can be lined up during crossover.
+    pub innovation: usize,
+}
+
+/// Hands out the ids of new connections and nodes, so the same
+/// structural change gets the same id in every genome.
+#[derive(Clone, Debug)]
+struct Innovations {
+    connections: HashMap<(usize, usize), usize>,
+    /// The id of the node added by splitting each connection.
+    split_nodes: HashMap<usize, usize>,
+    next_node_id: usize,
+}
+
+impl Innovations {
+    fn new(next_node_id: usize) -> Self {
+        Innovations {
+            connections: HashMap::new(),
+            split_nodes: HashMap::new(),
+            next_node_id,
+        }
+    }
+
+    fn connection(&mut self, from: usize, to: usize) -> usize {
+        let next = self.connections.len();
+        *self.connections.entry((from, to)).or_insert(next)
+    }
+
+    fn split_node(&mut self, innovation: usize) -> usize {
+        *self.split_nodes.entry(innovation).or_insert_with(|| {
+            self.next_node_id += 1;
+            self.next_node_id - 1
+        })
+    }
+}
+
+/// The description of a network whose neurons can be connected in any
+/// way that doesn't form a cycle, which evolves by gaining neurons and
+/// connections.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Genome {
+    /// The inputs come first, followed by the bias, the output and then
+    /// any hidden nodes.
+    nodes: Vec<NodeGene>,
+    connections: Vec<ConnectionGene>,
+}
+
+impl Genome {
+    /// Returns a genome with no hidden nodes, whose inputs and bias are
+    /// connected straight to the output.
+    fn minimal(
+        num_inputs: usize,
+        output_activation: ActivationType,
+        innovations: &mut Innovations,
+        rng: &mut SplitMix64,
+    ) -> Self {
+        let mut nodes: Vec<NodeGene> = (0..num_inputs)
+            .map(|id| NodeGene {
+                id,
+                kind: NodeKind::Input,
+                activation: ActivationType::Linear,
+            })
+            .collect();
+        nodes.push(NodeGene {
+            id: num_inputs,
+            kind: NodeKind::Bias,
+            activation: ActivationType::Linear,
+        });
+        let output = num_inputs + 1;
+        nodes.push(NodeGene {
+            id: output,
+            kind: NodeKind::Output,
+            activation: output_activation,
+        });
+        let connections = (0..=num_inputs)
+            .map(|from| ConnectionGene {
+                from,
+                to: output,
+                weight: rng.next_normal(),
+                enabled: true,
+                innovation: innovations.connection(from, output),
+            })
+            .collect();
+        Genome { nodes, connections }
+    }
+
+    pub fn nodes(&self) -> &[NodeGene] {
+        &self.nodes
+    }
+
+    pub fn connections(&self) -> &[ConnectionGene] {
+        &self.connections
+    }
+
+    pub fn num_hidden(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|node| node.kind == NodeKind::Hidden)
+            .count()
+    }
+
+    pub fn num_enabled_connections(&self) -> usize {
+        self.connections.iter().filter(|gene| gene.enabled).count()
+    }
+
+    /// Builds the network that the genome describes.
+    pub fn network(&self) -> Network {
+        let index_of: HashMap<usize, usize> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (node.id, index))
+            .collect();
+        let mut incoming: Vec<Vec<(usize, f64)>> = vec![vec![]; self.nodes.len()];
+        for gene in self.connections.iter().filter(|gene| gene.enabled) {
+            incoming[index_of[&gene.to]].push((index_of[&gene.from], gene.weight));
+        }
+        let num_inputs = self.num_inputs();
+        let order = self
+            .evaluation_order()
+            .into_iter()
+            .map(|id| index_of[&id])
+            .filter(|&index| index > num_inputs)
+            .collect();
+        Network {
+            num_inputs,
+            activations: self.nodes.iter().map(|node| node.activation).collect(),
+            incoming,
+            order,
+            output: num_inputs + 1,
+        }
+    }
+
+    fn num_inputs(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|node| node.kind == NodeKind::Input)
+            .count()
+    }
+
+    /// Returns the ids of all the nodes, ordered so that every node comes
+    /// after the nodes it has enabled connections from.
+    fn evaluation_order(&self) -> Vec<usize> {
+        let mut num_incoming: HashMap<usize, usize> =
+            self.nodes.iter().map(|node| (node.id, 0)).collect();
+        for gene in self.connections.iter().filter(|gene| gene.enabled) {
+            *num_incoming.get_mut(&gene.to).unwrap() += 1;
+        }
+        let mut ready: Vec<usize> = self
+            .nodes
+            .iter()
+            .rev()
+            .filter(|node| num_incoming[&node.id] == 0)
+            .map(|node| node.id)
+            .collect();
+        let mut order = Vec::with_capacity(self.nodes.len());
+        while let Some(id) = ready.pop() {
+            order.push(id);
+            for gene in self.connections.iter().filter(|gene| gene.enabled) {
+                if gene.from == id {
+                    let count = num_incoming.get_mut(&gene.to).unwrap();
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.push(gene.to);
+                    }
+                }
+            }
+        }
+        debug_assert_eq!(order.len(), self.nodes.len(), "genomes can't have cycles");
+        order
+    }
+
+    /// Returns whether there is a path of connections, enabled or not,
+    /// from `from` to `to`.
+    fn has_path(&self, from: usize, to: usize) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![from];
+        while let Some(id) = stack.pop() {
+            if id == to {
+                return true;
+            }
+            if visited.insert(id) {
+                stack.extend(
+                    self.connections
+                        .iter()
+                        .filter(|gene| gene.from == id)
+                        .map(|gene| gene.to),
+                );
+            }
+        }
+        false
+    }
+
+    fn mutate(&mut self, innovations: &mut Innovations, rng: &mut SplitMix64) {
+        if rng.next_f64() < WEIGHT_MUTATION_RATE {
+            for gene in &mut self.connections {
+                if rng.next_f64() < WEIGHT_REPLACEMENT_RATE {
+                    gene.weight = rng.next_normal();
+                } else {
+                    gene.weight += WEIGHT_PERTURBATION_SCALE * rng.next_normal();
+                }
+            }
+        }
+        if rng.next_f64() < ADD_CONNECTION_RATE {
+            self.add_connection(innovations, rng);
+        }
+        if rng.next_f64() < ADD_NODE_RATE {
+            self.add_node(innovations, rng);
+        }
+    }
+
+    /// Connects two nodes that aren't connected yet, as long as that
+    /// doesn't create a cycle.
+    fn add_connection(&mut self, innovations: &mut Innovations, rng: &mut SplitMix64) {
+        for _ in 0..ADD_CONNECTION_ATTEMPTS {
+            let from = self.nodes[rng.next_index(self.nodes.len())];
+            let to = self.nodes[rng.next_index(self.nodes.len())];
+            if from.kind == NodeKind::Output
+                || matches!(to.kind, NodeKind::Input | NodeKind::Bias)
+                || from.id == to.id
+                || self
+                    .connections
+                    .iter()
+                    .any(|gene| gene.from == from.id && gene.to == to.id)
+                || self.has_path(to.id, from.id)
+            {
+                continue;
+            }
+            self.connections.push(ConnectionGene {
+                from: from.id,
+                to: to.id,
+                weight: rng.next_normal(),
+                enabled: true,
+                innovation: innovations.connection(from.id, to.id),
+            });
+            return;
+        }
+    }
+
+    /// Splits an enabled connection in two by adding a hidden node in
+    /// the middle of it.
+    fn add_node(&mut self, innovations: &mut Innovations, rng: &mut SplitMix64) {
+        let enabled: Vec<usize> = (0..self.connections.len())
+            .filter(|&index| self.connections[index].enabled)
+            .collect();
+        if enabled.is_empty() {
+            return;
+        }
+        let index = enabled[rng.next_index(enabled.len())];
+        let gene = self.connections[index];
+        let id = innovations.split_node(gene.innovation);
+        // The connection may have been split before, and re-enabled by
+        // crossover since.
+        if self.nodes.iter().any(|node| node.id == id) {
+            return;
+        }
+        self.connections[index].enabled = false;
+        self.nodes.push(NodeGene {
+            id,
+            kind: NodeKind::Hidden,
+            activation: HIDDEN_ACTIVATIONS[rng.next_index(HIDDEN_ACTIVATIONS.len())],
+        });
+        self.connections.push(ConnectionGene {
+            from: gene.from,
+            to: id,
+            weight: 1.0,
+            enabled: true,
+            innovation: innovations.connection(gene.from, id),
+        });
+        self.connections.push(ConnectionGene {
+            from: id,
+            to: gene.to,
+            weight: gene.weight,
+            enabled: true,
+            innovation: innovations.connection(id, gene.to),
+        });
+    }
+
+    /// Returns a child with the structure of the fitter parent, whose
+    /// shared connections are taken from either parent at random.
+    fn crossover(fitter: &Genome, other: &Genome, rng: &mut SplitMix64) -> Genome {
+        let other_genes: HashMap<usize, &ConnectionGene> = other
+            .connections
+            .iter()
+            .map(|gene| (gene.innovation, gene))
+            .collect();
+        let connections = fitter
+            .connections
+            .iter()
+            .map(|gene| match other_genes.get(&gene.innovation) {
+                Some(other_gene) => {
+                    let mut child = if rng.next_f64() < 0.5 {
+                        *gene
+                    } else {
+                        **other_gene
+                    };
+                    if !gene.enabled || !other_gene.enabled {
+                        child.enabled = rng.next_f64() >= DISABLED_GENE_RATE;
+                    }
+                    child
+                }
+                None => *gene,
+            })
+            .collect();
+        Genome {
+            nodes: fitter.nodes.clone(),
+            connections,
+        }
+    }
+
+    /// Measures how different two genomes are, from the number of
+    /// connections that only one of them has and the differences in the
+    /// weights of the rest.
+    fn distance(&self, other: &Genome) -> f64 {
+        let other_weights: HashMap<usize, f64> = other
+            .connections
+            .iter()
+            .map(|gene| (gene.innovation, gene.weight))
+            .collect();
+        let mut num_shared = 0;
+        let mut weight_difference = 0.0;
+        for gene in &self.connections {
+            if let Some(weight) = other_weights.get(&gene.innovation) {
+                num_shared += 1;
+                weight_difference += (gene.weight - weight).abs();
+            }
+        }
+        let num_unshared = self.connections.len() + other.connections.len() - 2 * num_shared;
+        // Small genomes aren't normalized by their size, as in the
+        // original NEAT paper.
+        let size = self.connections.len().max(other.connections.len());
+        let size = if size < 20 { 1.0 } else { size as f64 };
+        num_unshared as f64 / size
+            + WEIGHT_DIFFERENCE_COEFFICIENT * weight_difference / num_shared.max(1) as f64
+    }
+
+    /// Returns the ids of the nodes in each of the columns that
+    /// [Self::draw] puts them in, from the inputs in the first column to
+    /// the output in the last.
+    fn columns(&self) -> Vec<Vec<usize>> {
+        // Each node's column is the length of the longest path to it.
+        let mut depths: HashMap<usize, usize> = HashMap::new();
+        for id in self.evaluation_order() {
+            let depth = self
+                .connections
+                .iter()
+                .filter(|gene| gene.enabled && gene.to == id)
+                .map(|gene| depths[&gene.from] + 1)
+                .max()
+                .unwrap_or(0);
+            depths.insert(id, depth);
+        }
+        let output = self.num_inputs() + 1;
+        let mut max_depth = depths
+            .iter()
+            .filter(|&(&id, _)| id != output)
+            .map(|(_, &depth)| depth)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        if self.num_hidden() > 0 {
+            // Leave room for hidden nodes between the inputs and the
+            // output, even if none of them are connected to anything.
+            max_depth = max_depth.max(2);
+        }
+        depths.insert(output, max_depth);
+        for node in &self.nodes {
+            if node.kind == NodeKind::Hidden {
+                // Unconnected hidden nodes go in the middle.
+                let depth = depths.get_mut(&node.id).unwrap();
+                *depth = (*depth).clamp(1, max_depth - 1);
+            }
+        }
+
+        let mut columns: Vec<Vec<usize>> = vec![vec![]; max_depth + 1];
+        for node in &self.nodes {
+            columns[depths[&node.id]].push(node.id);
+        }
+        columns
+    }
+
+    /// Draws the genome's nodes in columns, from the inputs on the left
+    /// to the output on the right, within the given screen rectangle.
+    /// Positive connections are red and negative ones blue, and thicker
+    /// the larger their weights.
+    pub fn draw(&self, x: f32, y: f32, width: f32, height: f32) {
+        let columns = self.columns();
+        let max_depth = columns.len() - 1;
+        let mut positions: HashMap<usize, Vec2> = HashMap::new();
+        for (depth, column) in columns.iter().enumerate() {
+            for (row, &id) in column.iter().enumerate() {
+                let position = vec2(
+                    x + width * depth as f32 / max_depth as f32,
+                    y + height * (row as f32 + 0.5) / column.len() as f32,
+                );
+                positions.insert(id, position);
+            }
+        }
+
+        for gene in self.connections.iter().filter(|gene| gene.enabled) {
+            let (from, to) = (positions[&gene.from], positions[&gene.to]);
+            let color = if gene.weight > 0.0 { RED } else { BLUE };
+            let thickness = px(0.5 + gene.weight.abs().min(3.0) as f32);
+            draw_line(from.x, from.y, to.x, to.y, thickness, color.with_alpha(0.8));
+        }
+        for node in &self.nodes {
+            let position = positions[&node.id];
+            let color = match node.kind {
+                NodeKind::Input | NodeKind::Bias => GRAY,
+                NodeKind::Hidden | NodeKind::Output => WHITE,
+            };
+            draw_circle(position.x, position.y, px(4.0), color);
+        }
+    }
+}
+
+/// A network built from a [Genome], which can be evaluated quickly and
+/// from several threads at once.
+#[derive(Clone, Debug)]
+pub struct Network {
+    num_inputs: usize,
+    activations: Vec<ActivationType>,
+    /// The nodes and weights of the connections into each node.
+    incoming: Vec<Vec<(usize, f64)>>,
+    /// The indices of the hidden and output nodes, in the order they
+    /// need to be evaluated in.
+    order: Vec<usize>,
+    output: usize,
+}
+
+impl Network {
+    pub fn output(&self, inputs: &[f64]) -> f64 {
+        let mut values = vec![0.0; self.activations.len()];
+        values[..self.num_inputs].copy_from_slice(&inputs[..self.num_inputs]);
+        values[self.num_inputs] = 1.0;
+        for &index in &self.order {
+            let sum: f64 = self.incoming[index]
+                .iter()
+                .map(|&(from, weight)| values[from] * weight)
+                .sum();
+            values[index] = self.activations[index].activate(sum);
+        }
+        values[self.output]
+    }
+}
+
+/// How well a genome does on the datapoints of a classifier.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Score {
+    /// The mean squared error.
+    pub loss: f64,
+    /// The fraction of correctly classified datapoints, which is always
+    /// zero for regression.
+    pub accuracy: f64,
+    /// What evolution maximizes: mostly the accuracy for classification,
+    /// and the negative loss for regression.
+    pub fitness: f64,
+}
+
+impl Score {
+    /// Returns `None` for [Task::MultiClass], since genomes only have a
+    /// single output.
+    fn new(task: Task, loss: f64, accuracy: f64) -> Option<Self> {
+        let fitness = match task {
+            Task::Classification => accuracy - LOSS_PENALTY * loss,
+            Task::Regression => -loss,
+            Task::MultiClass { .. } => return None,
+        };
+        Some(Score {
+            loss,
+            accuracy,
+            fitness,
+        })
+    }
+}
+
+/// A group of similar genomes, which mostly compete with each other, so
+/// that new structures have time to be tuned before they have to compete
+/// with the whole population.
+#[derive(Clone, Debug)]
+struct Species {
+    representative: Genome,
+    /// Indices of the genomes in the current generation.
+    members: Vec<usize>,
+    best_fitness: f64,
+    /// The number of generations since the best fitness improved.
+    stagnation: usize,
+}
+
+/// A population of genomes evolved to fit the datapoints of a
+/// [Classifier2D], in the style of NEAT (NeuroEvolution of Augmenting
+/// Topologies).
+pub struct Population {
+    num_inputs: usize,
+    output_activation: ActivationType,
+    genomes: Vec<Genome>,
+    species: Vec<Species>,
+    innovations: Innovations,
+    rng: SplitMix64,
+    generation: u64,
+    best: Option<(Genome, Score)>,
+}
+
+impl Population {
+    /// Creates `size` minimal genomes with random weights.
+    pub fn new(
+        num_inputs: usize,
+        output_activation: ActivationType,
+        size: usize,
+        seed: u64,
+    ) -> Self {
+        assert!(size > 0, "a population needs at least one genome");
+        let mut rng = SplitMix64::new(seed);
+        let mut innovations = Innovations::new(num_inputs + 2);
+        let genomes = (0..size)
+            .map(|_| Genome::minimal(num_inputs, output_activation, &mut innovations, &mut rng))
+            .collect();
+        Population {
+            num_inputs,
+            output_activation,
+            genomes,
+            species: vec![],
+            innovations,
+            rng,
+            generation: 0,
+            best: None,
+        }
+    }
+
+    pub fn num_inputs(&self) -> usize {
+        self.num_inputs
+    }
+
+    pub fn output_activation(&self) -> ActivationType {
+        self.output_activation
+    }
+
+    /// The number of generations evolved so far.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn num_species(&self) -> usize {
+        self.species.len()
+    }
+
+    /// Returns the fittest genome of the latest generation, and its score.
+    pub fn best(&self) -> Option<(&Genome, Score)> {
+        self.best.as_ref().map(|(genome, score)| (genome, *score))
+    }
+
+    /// Scores every genome on the classifier's datapoints, and replaces
+    /// them with the next generation. Returns the score of the fittest
+    /// genome, or `None` without changing anything if the classifier has
+    /// several classes, which genomes with a single output can't tell
+    /// apart.
+    pub fn evolve(&mut self, classifier: &Classifier2D) -> Option<Score> {
+        assert_eq!(
+            self.num_inputs,
+            classifier.features().len(),
+            "the genomes need as many inputs as the classifier has features"
+        );
+        let task = classifier.task();
+        // The classifier can't be shared between threads, but it
+        // evaluates each genome on several threads.
+        let scores: Vec<Score> = self
+            .genomes
+            .iter()
+            .map(|genome| {
+                let network = genome.network();
+                let (loss, accuracy) = classifier.score(|inputs| network.output(inputs))?;
+                Score::new(task, loss, accuracy)
+            })
+            .collect::<Option<_>>()?;
+        let champion = (0..scores.len())
+            .max_by(|&a, &b| scores[a].fitness.total_cmp(&scores[b].fitness))
+            .unwrap();
+        self.best = Some((self.genomes[champion].clone(), scores[champion]));
+
+        self.speciate();
+        for species in &mut self.species {
+            let best_fitness = species
+                .members
+                .iter()
+                .map(|&index| scores[index].fitness)
+                .fold(f64::NEG_INFINITY, f64::max);
+            if best_fitness > species.best_fitness {
+                species.best_fitness = best_fitness;
+                species.stagnation = 0;
+            } else {
+                species.stagnation += 1;
+            }
+        }
+        self.species.retain(|species| {
+            species.stagnation < MAX_STAGNATION || species.members.contains(&champion)
+        });
+
+        let num_children = self.num_children(&scores, champion);
+        let mut children = Vec::with_capacity(self.genomes.len());
+        for (species, &count) in self.species.iter().zip(&num_children) {
+            let mut members = species.members.clone();
+            members.sort_by(|&a, &b| scores[b].fitness.total_cmp(&scores[a].fitness));
+            // Only the best half of each species gets to be a parent.
+            members.truncate(members.len().div_ceil(2));
+            for child_index in 0..count {
+                // The best genome of each species survives unchanged.
+                if child_index == 0 {
+                    children.push(self.genomes[members[0]].clone());
+                    continue;
+                }
+                let a = members[self.rng.next_index(members.len())];
+                let mut child = if members.len() > 1 && self.rng.next_f64() < CROSSOVER_RATE {
+                    let b = members[self.rng.next_index(members.len())];
+                    let (fitter, other) = if scores[a].fitness >= scores[b].fitness {
+                        (a, b)
+                    } else {
+                        (b, a)
+                    };
+                    Genome::crossover(&self.genomes[fitter], &self.genomes[other], &mut self.rng)
+                } else {
+                    self.genomes[a].clone()
+                };
+                child.mutate(&mut self.innovations, &mut self.rng);
+                children.push(child);
+            }
+        }
+        for species in &mut self.species {
+            let member = species.members[self.rng.next_index(species.members.len())];
+            species.representative = self.genomes[member].clone();
+        }
+        self.genomes = children;
+        self.generation += 1;
+        Some(scores[champion])
+    }
+
+    /// Puts every genome into the first species it's compatible with,
+    /// starting new species as needed.
+    fn speciate(&mut self) {
+        for species in &mut self.species {
+            species.members.clear();
+        }
+        for (index, genome) in self.genomes.iter().enumerate() {
+            match self
+                .species
+                .iter_mut()
+                .find(|species| species.representative.distance(genome) < COMPATIBILITY_THRESHOLD)
+            {
+                Some(species) => species.members.push(index),
+                None => self.species.push(Species {
+                    representative: genome.clone(),
+                    members: vec![index],
+                    best_fitness: f64::NEG_INFINITY,
+                    stagnation: 0,
+                }),
+            }
+        }
+        self.species.retain(|species| !species.members.is_empty());
+    }
+
+    /// Shares out the next generation between the species, in proportion
+    /// to their members' average fitness, so no one species can take
+    /// over just by being large.
+    fn num_children(&self, scores: &[Score], champion: usize) -> Vec<usize> {
+        let min_fitness = scores
+            .iter()
+            .map(|score| score.fitness)
+            .fold(f64::INFINITY, f64::min);
+        let shares: Vec<f64> = self
+            .species
+            .iter()
+            .map(|species| {
+                let total: f64 = species
+                    .members
+                    .iter()
+                    .map(|&index| scores[index].fitness - min_fitness + 1e-3)
+                    .sum();
+                total / species.members.len() as f64
+            })
+            .collect();
+        let total_share: f64 = shares.iter().sum();
+        let size = self.genomes.len();
+        let mut counts: Vec<usize> = shares
+            .iter()
+            .map(|share| (share / total_share * size as f64).floor() as usize)
+            .collect();
+        // Whatever is left over after rounding down goes to the species
+        // of the best genome.
+        let champion_species = self
+            .species
+            .iter()
+            .position(|species| species.members.contains(&champion))
+            .unwrap();
+        counts[champion_species] += size - counts.iter().sum::<usize>();
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classifier_2d::{Datapoint2D, Label2D, Weights2D};
+
+    fn population(size: usize) -> Population {
+        Population::new(2, ActivationType::Sigmoid, size, 7)
+    }
+
+    #[test]
+    fn test_minimal_network() {
+        let mut population = population(1);
+        let genome = &mut population.genomes[0];
+        for (gene, weight) in genome.connections.iter_mut().zip([0.5, -2.0, 0.25]) {
+            gene.weight = weight;
+        }
+        let output = genome.network().output(&[2.0, 1.0]);
+        let expected = ActivationType::Sigmoid.activate(0.5 * 2.0 - 2.0 * 1.0 + 0.25);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_columns_with_isolated_hidden_node() {
+        let mut population = population(1);
+        let mut genome = population.genomes[0].clone();
+        genome.add_node(&mut population.innovations, &mut population.rng);
+        let hidden = genome.nodes.last().unwrap().id;
+        // Crossover can disable the only connection into a hidden node.
+        for gene in &mut genome.connections {
+            if gene.to == hidden {
+                gene.enabled = false;
+            }
+        }
+        let output = genome.num_inputs() + 1;
+        assert_eq!(
+            genome.columns(),
+            vec![vec![0, 1, 2], vec![hidden], vec![output]]
+        );
+    }
+
+    #[test]
+    fn test_mutations_never_create_cycles() {
+        let mut population = population(1);
+        let mut genome = population.genomes[0].clone();
+        for _ in 0..200 {
+            genome.add_connection(&mut population.innovations, &mut population.rng);
+            genome.add_node(&mut population.innovations, &mut population.rng);
+        }
+        assert!(genome.num_hidden() > 50);
+        assert_eq!(genome.evaluation_order().len(), genome.nodes.len());
+        for gene in &genome.connections {
+            assert!(!genome.has_path(gene.to, gene.from));
+        }
+        assert!(genome.network().output(&[0.3, -0.2]).is_finite());
+
+        // The same split in different genomes adds the same node.
+        let mut a = population.genomes[0].clone();
+        a.connections.truncate(1);
+        let mut b = a.clone();
+        a.add_node(&mut population.innovations, &mut population.rng);
+        b.add_node(&mut population.innovations, &mut population.rng);
+        assert_eq!(a.connections, b.connections);
+        assert_eq!(a.nodes.last().unwrap().id, b.nodes.last().unwrap().id);
+    }
+
+    #[test]
+    fn test_evolution_solves_xor() {
+        let datapoints: Vec<Datapoint2D> = [(-1, -1), (1, 1), (-1, 1), (1, -1)]
+            .into_iter()
+            .flat_map(|(x, y)| {
+                let label = if x == y { Label2D::Blue } else { Label2D::Red };
+                (0..4).map(move |offset| Datapoint2D::new((x * (10 + offset), y * 12), label))
+            })
+            .collect();
+        let classifier = Classifier2D::new(datapoints, Weights2D::new(2, vec![], false));
+        let mut population = population(150);
+        for _ in 0..100 {
+            population.evolve(&classifier);
+            if population.best().unwrap().1.accuracy == 1.0 {
+                break;
+            }
+        }
+        let (genome, score) = population.best().unwrap();
+        assert_eq!(score.accuracy, 1.0);
+        // A network without hidden nodes can't separate XOR.
+        assert!(genome.num_hidden() > 0);
+        assert_eq!(population.genomes.len(), 150);
+    }
+
+    #[test]
+    fn test_several_classes_are_not_evolved() {
+        let datapoints = vec![
+            Datapoint2D::new((-10, 0), Label2D::Blue),
+            Datapoint2D::new((0, 10), Label2D::Red),
+            Datapoint2D::new((10, 0), Label2D::Green),
+        ];
+        let task = Task::MultiClass { num_classes: 3 };
+        let weights = Weights2D::new(2, vec![], false).for_task(task);
+        let classifier = Classifier2D::new(datapoints, weights).with_task(task);
+        assert_eq!(classifier.score(|_| 0.5), None);
+
+        let mut population = population(10);
+        assert_eq!(population.evolve(&classifier), None);
+        assert_eq!(population.generation(), 0);
+        assert!(population.best().is_none());
+    }
+}
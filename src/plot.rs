@@ -53,17 +53,32 @@ impl Plot {
         );
     }
 
-    pub fn _draw_line(&self, x1: f32, y1: f32, x2: f32, y2: f32, color: Color) {
+    pub fn draw_line(&self, x1: f32, y1: f32, x2: f32, y2: f32, color: Color) {
         draw_line(
             self.screen_x(x1),
             self.screen_y(y1),
             self.screen_x(x2),
             self.screen_y(y2),
-            1.0,
+            px(1.0),
             color,
         );
     }
 
+    /// Draws an arrow from the first point to the second, with a head
+    /// that's `head_size` plot units long.
+    pub fn draw_arrow(&self, x1: f32, y1: f32, x2: f32, y2: f32, head_size: f32, color: Color) {
+        let direction = vec2(x2 - x1, y2 - y1).normalize_or_zero();
+        if direction == Vec2::ZERO {
+            return;
+        }
+        self.draw_line(x1, y1, x2, y2, color);
+        let tip = vec2(x2, y2);
+        let base = tip - direction * head_size;
+        let side = direction.perp() * head_size / 2.0;
+        let screen = |point: Vec2| vec2(self.screen_x(point.x), self.screen_y(point.y));
+        draw_triangle(screen(tip), screen(base + side), screen(base - side), color);
+    }
+
     pub fn draw_circle(&self, x: f32, y: f32, r: f32, color: Color) {
         draw_circle(
             self.screen_x(x),
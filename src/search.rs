@@ -0,0 +1,380 @@
+use std::{cmp::Ordering, time::Instant};
+
+use macroquad::rand::srand;
+use serde::Serialize;
+
+use crate::{
+    classifier_2d::{Classifier2D, Datapoint2D, Target2D, Task, Weights2D},
+    engine::{ActivationType, SplitMix64},
+    layer::LayerSpec,
+    trainer::{Optimizer, Trainer},
+};
+
+/// The values of each hyperparameter to try.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchSpace {
+    /// The widths of the hidden layers of each architecture.
+    pub architectures: Vec<Vec<usize>>,
+    pub activations: Vec<ActivationType>,
+    pub learning_rates: Vec<f64>,
+    pub seeds: Vec<u64>,
+}
+
+impl Default for SearchSpace {
+    fn default() -> Self {
+        SearchSpace {
+            architectures: vec![vec![], vec![16], vec![16, 16]],
+            activations: vec![
+                ActivationType::Sigmoid,
+                ActivationType::Tanh,
+                ActivationType::Relu,
+            ],
+            learning_rates: vec![0.1, 0.5, 1.0],
+            seeds: vec![1],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchStrategy {
+    /// Tries every combination of the values in the search space.
+    Grid,
+    /// Tries the given number of random combinations. Learning rates
+    /// are sampled log-uniformly between the smallest and largest ones
+    /// in the search space, and seeds are sampled from all of them.
+    Random { num_trials: usize, seed: u64 },
+}
+
+/// The hyperparameters of a single training run.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrialConfig {
+    pub hidden_layers: Vec<usize>,
+    pub activation: ActivationType,
+    pub learning_rate: f64,
+    pub seed: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrialResult {
+    #[serde(flatten)]
+    pub config: TrialConfig,
+    /// The architecture in the notation used by the app, e.g. `2-16-1`.
+    pub architecture: String,
+    pub steps: u64,
+    pub loss: f64,
+    pub accuracy: f64,
+    pub mean_absolute_error: f64,
+    /// The loss on the validation points, if there are any.
+    pub validation_loss: Option<f64>,
+    /// The accuracy on the validation points, if there are any.
+    pub validation_accuracy: Option<f64>,
+    pub train_millis: u128,
+}
+
+/// Returns the task that suits the given datapoints: regression if any
+/// of them have continuous values, multi-class classification if any
+/// have labels other than blue and red, and classification otherwise.
+pub fn infer_task(datapoints: &[Datapoint2D]) -> Task {
+    let mut num_classes = 2;
+    for point in datapoints {
+        match point.target {
+            Target2D::Value(_) => return Task::Regression,
+            Target2D::Label(label) => num_classes = num_classes.max(label.index() + 1),
+        }
+    }
+    if num_classes > 2 {
+        Task::MultiClass { num_classes }
+    } else {
+        Task::Classification
+    }
+}
+
+/// Returns the configurations that the given strategy tries.
+pub fn trial_configs(space: &SearchSpace, strategy: SearchStrategy) -> Vec<TrialConfig> {
+    match strategy {
+        SearchStrategy::Grid => {
+            let mut configs = vec![];
+            for hidden_layers in &space.architectures {
+                for &activation in &space.activations {
+                    for &learning_rate in &space.learning_rates {
+                        for &seed in &space.seeds {
+                            configs.push(TrialConfig {
+                                hidden_layers: hidden_layers.clone(),
+                                activation,
+                                learning_rate,
+                                seed,
+                            });
+                        }
+                    }
+                }
+            }
+            configs
+        }
+        SearchStrategy::Random { num_trials, seed } => {
+            let mut rng = SplitMix64::new(seed);
+            let min_rate = space
+                .learning_rates
+                .iter()
+                .copied()
+                .fold(f64::INFINITY, f64::min);
+            let max_rate = space.learning_rates.iter().copied().fold(0.0, f64::max);
+            (0..num_trials)
+                .map(|_| {
+                    let hidden_layers =
+                        space.architectures[rng.next_index(space.architectures.len())].clone();
+                    let activation = space.activations[rng.next_index(space.activations.len())];
+                    let seed = space.seeds[rng.next_index(space.seeds.len())];
+                    let fraction = rng.next_f64();
+                    TrialConfig {
+                        hidden_layers,
+                        activation,
+                        learning_rate: min_rate * (max_rate / min_rate).powf(fraction),
+                        seed,
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+/// Trains a network with the given configuration for `steps` steps of
+/// full-batch gradient descent.
+pub fn run_trial(
+    datapoints: &[Datapoint2D],
+    task: Task,
+    config: &TrialConfig,
+    steps: u64,
+) -> TrialResult {
+    // The weights are initialized from macroquad's global random state.
+    srand(config.seed);
+    let hidden_layers = config
+        .hidden_layers
+        .iter()
+        .map(|&width| LayerSpec::Dense(width))
+        .collect();
+    let weights = Weights2D::from_specs(2, config.activation, hidden_layers, false).for_task(task);
+    let architecture = weights.notation();
+    let classifier = Classifier2D::new(datapoints.to_vec(), weights).with_task(task);
+    let mut trainer = Trainer::new(
+        classifier,
+        Optimizer::Sgd {
+            learning_rate: config.learning_rate,
+        },
+    );
+    let start = Instant::now();
+    trainer.run_until(|metrics| metrics.step >= steps);
+    let metrics = trainer.metrics();
+    TrialResult {
+        config: config.clone(),
+        architecture,
+        steps: metrics.step,
+        loss: metrics.loss,
+        accuracy: metrics.accuracy,
+        mean_absolute_error: metrics.mean_absolute_error,
+        validation_loss: metrics.validation_loss,
+        validation_accuracy: metrics.validation_accuracy,
+        train_millis: start.elapsed().as_millis(),
+    }
+}
+
+/// Sorts the results from best to worst: by accuracy and then loss for
+/// classification, and by loss for regression. The validation points
+/// are used when there are any, and the training points otherwise.
+/// Diverged runs come last.
+pub fn rank(results: &mut [TrialResult], task: Task) {
+    let loss = |result: &TrialResult| result.validation_loss.unwrap_or(result.loss);
+    let accuracy = |result: &TrialResult| result.validation_accuracy.unwrap_or(result.accuracy);
+    let by_loss = |a: &TrialResult, b: &TrialResult| {
+        let (a, b) = (loss(a), loss(b));
+        match (a.is_nan(), b.is_nan()) {
+            (false, false) => a.total_cmp(&b),
+            (a_is_nan, b_is_nan) => a_is_nan.cmp(&b_is_nan),
+        }
+    };
+    results.sort_by(|a, b| match task {
+        Task::Classification | Task::MultiClass { .. } => accuracy(b)
+            .partial_cmp(&accuracy(a))
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| by_loss(a, b)),
+        Task::Regression => by_loss(a, b),
+    });
+}
+
+pub fn parse_activation(name: &str) -> Option<ActivationType> {
+    [
+        ActivationType::Sigmoid,
+        ActivationType::Tanh,
+        ActivationType::Relu,
+        ActivationType::Linear,
+    ]
+    .into_iter()
+    .find(|&activation| activation.name() == name)
+}
+
+/// Parses the widths of hidden layers separated by dashes, e.g.
+/// `16-16`, or `none` for no hidden layers.
+pub fn parse_architecture(text: &str) -> Option<Vec<usize>> {
+    if text == "none" {
+        return Some(vec![]);
+    }
+    text.split('-')
+        .map(|width| width.parse().ok().filter(|&width| width > 0))
+        .collect()
+}
+
+/// Formats the results as CSV, with a header line.
+pub fn results_to_csv(results: &[TrialResult]) -> String {
+    let mut csv = "rank,architecture,hidden_layers,activation,learning_rate,seed,steps,loss,accuracy,mean_absolute_error,validation_loss,validation_accuracy,train_millis\n".to_owned();
+    for (index, result) in results.iter().enumerate() {
+        let config = &result.config;
+        let hidden_layers = if config.hidden_layers.is_empty() {
+            "none".to_owned()
+        } else {
+            config
+                .hidden_layers
+                .iter()
+                .map(|width| width.to_string())
+                .collect::<Vec<_>>()
+                .join("-")
+        };
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            index + 1,
+            result.architecture,
+            hidden_layers,
+            config.activation.name(),
+            config.learning_rate,
+            config.seed,
+            result.steps,
+            result.loss,
+            result.accuracy,
+            result.mean_absolute_error,
+            optional_to_csv(result.validation_loss),
+            optional_to_csv(result.validation_accuracy),
+            result.train_millis
+        ));
+    }
+    csv
+}
+
+/// Leaves missing values empty.
+fn optional_to_csv(value: Option<f64>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// Formats the results as a JSON array.
+pub fn results_to_json(results: &[TrialResult]) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classifier_2d::Label2D;
+
+    fn xor() -> Vec<Datapoint2D> {
+        vec![
+            Datapoint2D::new((-10, 9), Label2D::Red),
+            Datapoint2D::new((8, -8), Label2D::Red),
+            Datapoint2D::new((-5, -5), Label2D::Blue),
+            Datapoint2D::new((9, 10), Label2D::Blue),
+        ]
+    }
+
+    #[test]
+    fn test_grid_search_tries_every_combination() {
+        let space = SearchSpace {
+            seeds: vec![1, 2],
+            ..SearchSpace::default()
+        };
+        assert_eq!(
+            trial_configs(&space, SearchStrategy::Grid).len(),
+            3 * 3 * 3 * 2
+        );
+    }
+
+    #[test]
+    fn test_random_search_stays_in_the_search_space() {
+        let space = SearchSpace::default();
+        let strategy = SearchStrategy::Random {
+            num_trials: 20,
+            seed: 7,
+        };
+        let configs = trial_configs(&space, strategy);
+        assert_eq!(configs.len(), 20);
+        assert_eq!(configs, trial_configs(&space, strategy));
+        for config in configs {
+            assert!(space.architectures.contains(&config.hidden_layers));
+            assert!((0.1..=1.0).contains(&config.learning_rate));
+        }
+    }
+
+    #[test]
+    fn test_trials_are_ranked() {
+        let datapoints = xor();
+        let task = infer_task(&datapoints);
+        assert_eq!(task, Task::Classification);
+        let space = SearchSpace {
+            architectures: vec![vec![], vec![8]],
+            activations: vec![ActivationType::Tanh],
+            learning_rates: vec![1.0],
+            seeds: vec![3],
+        };
+        let mut results: Vec<TrialResult> = trial_configs(&space, SearchStrategy::Grid)
+            .iter()
+            .map(|config| run_trial(&datapoints, task, config, 300))
+            .collect();
+        assert_eq!(results[0].steps, 300);
+
+        rank(&mut results, task);
+        // XOR can't be solved without a hidden layer.
+        assert_eq!(results[0].architecture, "2-8-1");
+        assert!(results[0].loss < results[1].loss);
+        assert!(
+            results_to_csv(&results)
+                .lines()
+                .nth(1)
+                .unwrap()
+                .starts_with("1,2-8-1,8,tanh,")
+        );
+    }
+
+    #[test]
+    fn test_trials_are_ranked_by_validation() {
+        let mut datapoints = xor();
+        datapoints.push(Datapoint2D::new((-9, 10), Label2D::Red).with_validation(true));
+        let config = TrialConfig {
+            hidden_layers: vec![8],
+            activation: ActivationType::Tanh,
+            learning_rate: 1.0,
+            seed: 3,
+        };
+        let overfit = run_trial(&datapoints, Task::Classification, &config, 300);
+        assert_eq!(overfit.validation_accuracy, Some(1.0));
+        let csv = results_to_csv(std::slice::from_ref(&overfit));
+        let (header, row) = csv.trim_end().split_once('\n').unwrap();
+        let column = header
+            .split(',')
+            .position(|name| name == "validation_accuracy")
+            .unwrap();
+        assert_eq!(row.split(',').nth(column), Some("1"));
+
+        // Worse on the training points, but better on the validation ones.
+        let generalizes = TrialResult {
+            loss: overfit.loss + 1.0,
+            validation_loss: Some(overfit.validation_loss.unwrap() / 2.0),
+            ..overfit.clone()
+        };
+        let mut results = vec![overfit, generalizes.clone()];
+        rank(&mut results, Task::Classification);
+        assert_eq!(results[0], generalizes);
+    }
+
+    #[test]
+    fn test_parse_architecture() {
+        assert_eq!(parse_architecture("none"), Some(vec![]));
+        assert_eq!(parse_architecture("16-8"), Some(vec![16, 8]));
+        assert_eq!(parse_architecture("16-0"), None);
+        assert_eq!(parse_activation("relu"), Some(ActivationType::Relu));
+    }
+}
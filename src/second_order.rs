@@ -0,0 +1,405 @@
+use std::collections::VecDeque;
+
+/// The smallest damping that [SecondOrderMethod::DampedNewton] uses, so
+/// the Hessian can always be made positive definite.
+const MIN_DAMPING: f64 = 1e-6;
+
+/// The largest damping, beyond which damped Newton steps are just tiny
+/// gradient descent steps anyway.
+const MAX_DAMPING: f64 = 1e10;
+
+/// The change in each parameter used to estimate the Hessian from
+/// differences of the gradient.
+const HESSIAN_EPSILON: f64 = 1e-5;
+
+/// How much of the decrease in the loss predicted by the gradient a step
+/// has to achieve to be accepted by the line search (the Armijo
+/// condition).
+const SUFFICIENT_DECREASE: f64 = 1e-4;
+
+/// The number of times the line search halves the step length before
+/// giving up.
+const MAX_LINE_SEARCH_STEPS: usize = 30;
+
+/// The smallest value of `s·y` for which L-BFGS remembers a step, so
+/// its approximation of the inverse Hessian stays positive definite.
+const MIN_CURVATURE: f64 = 1e-10;
+
+/// An optimizer that uses the curvature of the loss, not just its
+/// gradient, to choose the direction and size of each step. These are
+/// meant for networks with few parameters, and work best when every
+/// step uses all of the datapoints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecondOrderMethod {
+    /// Limited-memory BFGS, which approximates the inverse Hessian from
+    /// the changes in the parameters and gradient over the last `memory`
+    /// steps.
+    Lbfgs { memory: usize },
+    /// Newton's method, with the Hessian estimated from differences of
+    /// the gradient, and a multiple of the identity added to it when it
+    /// isn't positive definite or its steps don't work out. This needs
+    /// twice as many gradient evaluations as there are parameters.
+    DampedNewton,
+}
+
+impl SecondOrderMethod {
+    pub fn name(&self) -> String {
+        match self {
+            SecondOrderMethod::Lbfgs { memory } => format!("L-BFGS ({memory})"),
+            SecondOrderMethod::DampedNewton => "Damped Newton".to_owned(),
+        }
+    }
+}
+
+/// The loss that a second-order optimizer minimizes.
+pub trait Objective {
+    fn loss(&mut self, params: &[f64]) -> f64;
+
+    fn gradient(&mut self, params: &[f64]) -> Vec<f64>;
+}
+
+/// What happened during the line search of a step.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineSearch {
+    /// The multiple of the search direction that was taken, or the last
+    /// one tried if none were accepted.
+    pub step_length: f64,
+    /// The number of times the loss was evaluated.
+    pub evaluations: usize,
+    /// Whether a step that decreased the loss enough was found. If not,
+    /// the parameters are left alone.
+    pub accepted: bool,
+    /// How much the loss decreased.
+    pub decrease: f64,
+}
+
+/// The state that second-order optimizers keep between steps.
+#[derive(Clone, Debug)]
+pub struct SecondOrderSearch {
+    method: SecondOrderMethod,
+    /// The latest changes in the parameters and gradient, oldest first.
+    /// Only used by L-BFGS.
+    history: VecDeque<(Vec<f64>, Vec<f64>)>,
+    /// The parameters and gradient at the start of the previous step.
+    /// Only used by L-BFGS.
+    previous: Option<(Vec<f64>, Vec<f64>)>,
+    /// Only used by damped Newton.
+    damping: f64,
+    line_search: Option<LineSearch>,
+}
+
+impl SecondOrderSearch {
+    pub fn new(method: SecondOrderMethod) -> Self {
+        SecondOrderSearch {
+            method,
+            history: VecDeque::new(),
+            previous: None,
+            damping: MIN_DAMPING,
+            line_search: None,
+        }
+    }
+
+    pub fn method(&self) -> SecondOrderMethod {
+        self.method
+    }
+
+    /// The line search of the latest step.
+    pub fn line_search(&self) -> Option<LineSearch> {
+        self.line_search
+    }
+
+    /// The multiple of the identity that damped Newton adds to the
+    /// Hessian, or `None` for other methods.
+    pub fn damping(&self) -> Option<f64> {
+        match self.method {
+            SecondOrderMethod::DampedNewton => Some(self.damping),
+            SecondOrderMethod::Lbfgs { .. } => None,
+        }
+    }
+
+    /// Updates the parameters, whose loss and gradient are `loss` and
+    /// `grads`. Returns the number of other times the loss or its
+    /// gradient was evaluated.
+    pub fn step<O: Objective>(
+        &mut self,
+        params: &mut [f64],
+        loss: f64,
+        grads: &[f64],
+        objective: &mut O,
+    ) -> usize {
+        let (direction, initial_step, mut evaluations) = match self.method {
+            SecondOrderMethod::Lbfgs { memory } => {
+                let (direction, initial_step) = self.lbfgs_direction(params, grads, memory);
+                (direction, initial_step, 0)
+            }
+            SecondOrderMethod::DampedNewton => {
+                let hessian = estimate_hessian(params, objective);
+                (self.newton_direction(hessian, grads), 1.0, 2 * params.len())
+            }
+        };
+        let line_search = backtrack(params, loss, grads, &direction, initial_step, objective);
+        evaluations += line_search.evaluations;
+        if self.method == SecondOrderMethod::DampedNewton {
+            // Trust the quadratic model more when full steps work.
+            self.damping = if line_search.accepted && line_search.step_length == 1.0 {
+                self.damping / 3.0
+            } else {
+                self.damping * 4.0
+            }
+            .clamp(MIN_DAMPING, MAX_DAMPING);
+        }
+        self.line_search = Some(line_search);
+        evaluations
+    }
+
+    /// Returns the L-BFGS search direction and the step length to try
+    /// first.
+    fn lbfgs_direction(&mut self, params: &[f64], grads: &[f64], memory: usize) -> (Vec<f64>, f64) {
+        if let Some((previous_params, previous_grads)) = self.previous.take() {
+            let s = subtract(params, &previous_params);
+            let y = subtract(grads, &previous_grads);
+            if dot(&s, &y) > MIN_CURVATURE {
+                self.history.push_back((s, y));
+            }
+        }
+        while self.history.len() > memory {
+            self.history.pop_front();
+        }
+        self.previous = Some((params.to_vec(), grads.to_vec()));
+
+        // The two-loop recursion multiplies the gradient by the
+        // approximate inverse Hessian.
+        let mut q = grads.to_vec();
+        let mut alphas = Vec::with_capacity(self.history.len());
+        for (s, y) in self.history.iter().rev() {
+            let alpha = dot(s, &q) / dot(s, y);
+            axpy(-alpha, y, &mut q);
+            alphas.push(alpha);
+        }
+        if let Some((s, y)) = self.history.back() {
+            let scale = dot(s, y) / dot(y, y);
+            q.iter_mut().for_each(|value| *value *= scale);
+        }
+        for ((s, y), alpha) in self.history.iter().zip(alphas.into_iter().rev()) {
+            let beta = dot(y, &q) / dot(s, y);
+            axpy(alpha - beta, s, &mut q);
+        }
+        let direction: Vec<f64> = q.iter().map(|value| -value).collect();
+
+        if self.history.is_empty() || dot(&direction, grads) >= 0.0 {
+            // Without any curvature information, take a gradient descent
+            // step that's small enough not to overshoot too wildly.
+            self.history.clear();
+            let norm = dot(grads, grads).sqrt();
+            let initial_step = if norm > 1.0 { 1.0 / norm } else { 1.0 };
+            (grads.iter().map(|grad| -grad).collect(), initial_step)
+        } else {
+            (direction, 1.0)
+        }
+    }
+
+    /// Solves `(H + damping * I) d = -g`, increasing the damping until
+    /// the matrix is positive definite, so `d` is a descent direction.
+    fn newton_direction(&mut self, hessian: Vec<Vec<f64>>, grads: &[f64]) -> Vec<f64> {
+        loop {
+            let mut matrix = hessian.clone();
+            for (index, row) in matrix.iter_mut().enumerate() {
+                row[index] += self.damping;
+            }
+            if let Some(factor) = cholesky(matrix) {
+                let negative_grads: Vec<f64> = grads.iter().map(|grad| -grad).collect();
+                return cholesky_solve(&factor, &negative_grads);
+            }
+            if self.damping >= MAX_DAMPING {
+                return grads.iter().map(|grad| -grad / self.damping).collect();
+            }
+            self.damping = (self.damping * 10.0).min(MAX_DAMPING);
+        }
+    }
+}
+
+/// Estimates the Hessian from central differences of the gradient,
+/// averaged with its transpose so it's symmetric.
+fn estimate_hessian<O: Objective>(params: &[f64], objective: &mut O) -> Vec<Vec<f64>> {
+    let n = params.len();
+    let mut shifted = params.to_vec();
+    let differences: Vec<Vec<f64>> = (0..n)
+        .map(|index| {
+            shifted[index] = params[index] + HESSIAN_EPSILON;
+            let above = objective.gradient(&shifted);
+            shifted[index] = params[index] - HESSIAN_EPSILON;
+            let below = objective.gradient(&shifted);
+            shifted[index] = params[index];
+            above
+                .iter()
+                .zip(below)
+                .map(|(above, below)| (above - below) / (2.0 * HESSIAN_EPSILON))
+                .collect()
+        })
+        .collect();
+    (0..n)
+        .map(|row| {
+            (0..n)
+                .map(|column| (differences[row][column] + differences[column][row]) / 2.0)
+                .collect()
+        })
+        .collect()
+}
+
+/// Halves the step length until the loss decreases enough. The
+/// parameters are moved to the accepted point, if any.
+fn backtrack<O: Objective>(
+    params: &mut [f64],
+    loss: f64,
+    grads: &[f64],
+    direction: &[f64],
+    initial_step: f64,
+    objective: &mut O,
+) -> LineSearch {
+    let slope = dot(grads, direction);
+    let mut step_length = initial_step;
+    let mut candidate = params.to_vec();
+    for evaluations in 1..=MAX_LINE_SEARCH_STEPS {
+        for ((candidate, param), direction) in candidate.iter_mut().zip(&*params).zip(direction) {
+            *candidate = param + step_length * direction;
+        }
+        let candidate_loss = objective.loss(&candidate);
+        if candidate_loss <= loss + SUFFICIENT_DECREASE * step_length * slope {
+            params.copy_from_slice(&candidate);
+            return LineSearch {
+                step_length,
+                evaluations,
+                accepted: true,
+                decrease: loss - candidate_loss,
+            };
+        }
+        step_length /= 2.0;
+    }
+    LineSearch {
+        step_length: step_length * 2.0,
+        evaluations: MAX_LINE_SEARCH_STEPS,
+        accepted: false,
+        decrease: 0.0,
+    }
+}
+
+/// Returns the lower triangular `L` for which `L Lᵀ` is the given
+/// matrix, or `None` if it isn't positive definite.
+fn cholesky(mut matrix: Vec<Vec<f64>>) -> Option<Vec<Vec<f64>>> {
+    let n = matrix.len();
+    for column in 0..n {
+        let diagonal =
+            matrix[column][column] - (0..column).map(|k| matrix[column][k].powi(2)).sum::<f64>();
+        if diagonal.is_nan() || diagonal <= 0.0 {
+            return None;
+        }
+        let diagonal = diagonal.sqrt();
+        matrix[column][column] = diagonal;
+        for row in column + 1..n {
+            let sum: f64 = (0..column)
+                .map(|k| matrix[row][k] * matrix[column][k])
+                .sum();
+            matrix[row][column] = (matrix[row][column] - sum) / diagonal;
+        }
+        for value in &mut matrix[column][column + 1..] {
+            *value = 0.0;
+        }
+    }
+    Some(matrix)
+}
+
+/// Solves `L Lᵀ x = b`, given the Cholesky factor `L`.
+fn cholesky_solve(factor: &[Vec<f64>], b: &[f64]) -> Vec<f64> {
+    let n = b.len();
+    let mut x = b.to_vec();
+    for row in 0..n {
+        let sum: f64 = (0..row).map(|k| factor[row][k] * x[k]).sum();
+        x[row] = (x[row] - sum) / factor[row][row];
+    }
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| factor[k][row] * x[k]).sum();
+        x[row] = (x[row] - sum) / factor[row][row];
+    }
+    x
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn subtract(a: &[f64], b: &[f64]) -> Vec<f64> {
+    a.iter().zip(b).map(|(a, b)| a - b).collect()
+}
+
+/// Adds `scale * x` to `y`.
+fn axpy(scale: f64, x: &[f64], y: &mut [f64]) {
+    for (y, x) in y.iter_mut().zip(x) {
+        *y += scale * x;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The Rosenbrock function, whose minimum at (1, 1) is at the end of
+    /// a long curved valley that gradient descent crawls along.
+    struct Rosenbrock {
+        evaluations: usize,
+    }
+
+    impl Objective for Rosenbrock {
+        fn loss(&mut self, params: &[f64]) -> f64 {
+            self.evaluations += 1;
+            let (x, y) = (params[0], params[1]);
+            (1.0 - x).powi(2) + 100.0 * (y - x * x).powi(2)
+        }
+
+        fn gradient(&mut self, params: &[f64]) -> Vec<f64> {
+            self.evaluations += 1;
+            let (x, y) = (params[0], params[1]);
+            vec![
+                -2.0 * (1.0 - x) - 400.0 * x * (y - x * x),
+                200.0 * (y - x * x),
+            ]
+        }
+    }
+
+    fn minimize(method: SecondOrderMethod, steps: usize) -> Vec<f64> {
+        let mut objective = Rosenbrock { evaluations: 0 };
+        let mut search = SecondOrderSearch::new(method);
+        let mut params = vec![-1.2, 1.0];
+        for _ in 0..steps {
+            let loss = objective.loss(&params);
+            let grads = objective.gradient(&params);
+            objective.evaluations = 0;
+            let evaluations = search.step(&mut params, loss, &grads, &mut objective);
+            assert_eq!(evaluations, objective.evaluations);
+        }
+        params
+    }
+
+    #[test]
+    fn test_lbfgs() {
+        let params = minimize(SecondOrderMethod::Lbfgs { memory: 5 }, 100);
+        assert!((params[0] - 1.0).abs() < 1e-6, "{params:?}");
+        assert!((params[1] - 1.0).abs() < 1e-6, "{params:?}");
+    }
+
+    #[test]
+    fn test_damped_newton() {
+        let params = minimize(SecondOrderMethod::DampedNewton, 50);
+        assert!((params[0] - 1.0).abs() < 1e-6, "{params:?}");
+        assert!((params[1] - 1.0).abs() < 1e-6, "{params:?}");
+    }
+
+    #[test]
+    fn test_cholesky_solve() {
+        let matrix = vec![vec![4.0, 2.0], vec![2.0, 3.0]];
+        let factor = cholesky(matrix).unwrap();
+        let x = cholesky_solve(&factor, &[2.0, 1.0]);
+        assert!((4.0 * x[0] + 2.0 * x[1] - 2.0).abs() < 1e-12);
+        assert!((2.0 * x[0] + 3.0 * x[1] - 1.0).abs() < 1e-12);
+        assert_eq!(cholesky(vec![vec![1.0, 2.0], vec![2.0, 1.0]]), None);
+    }
+}
@@ -0,0 +1,250 @@
+use std::fmt::Display;
+
+use crate::{engine::ActivationType, layer::LayerSpec};
+
+/// Statistics of the weights of a layer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WeightStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// The standard deviation.
+    pub std: f64,
+}
+
+impl WeightStats {
+    /// Returns the statistics of the given weights, or `None` if there
+    /// aren't any.
+    pub fn new(weights: &[f64]) -> Option<Self> {
+        if weights.is_empty() {
+            return None;
+        }
+        let len = weights.len() as f64;
+        let mean = weights.iter().sum::<f64>() / len;
+        let variance = weights
+            .iter()
+            .map(|weight| (weight - mean).powi(2))
+            .sum::<f64>()
+            / len;
+        Some(WeightStats {
+            min: weights.iter().copied().fold(f64::INFINITY, f64::min),
+            max: weights.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            mean,
+            std: variance.sqrt(),
+        })
+    }
+}
+
+/// A description of a single layer of a network, as returned by
+/// [crate::engine::MultiLayerPerceptron::summary].
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerSummary {
+    /// The layer's description, or `None` if it isn't a built-in layer.
+    pub spec: Option<LayerSpec>,
+    pub num_inputs: usize,
+    pub num_outputs: usize,
+    pub activation: Option<ActivationType>,
+    /// The number of parameters that are weights, i.e. the ones that
+    /// regularization applies to.
+    pub num_weights: usize,
+    /// The number of other parameters: biases, and the scales and
+    /// shifts of normalization layers.
+    pub num_biases: usize,
+    pub frozen: bool,
+    pub weight_stats: Option<WeightStats>,
+}
+
+impl LayerSummary {
+    pub fn num_params(&self) -> usize {
+        self.num_weights + self.num_biases
+    }
+
+    /// Returns the name of the kind of layer this is.
+    pub fn kind(&self) -> &'static str {
+        match self.spec {
+            Some(LayerSpec::Dense(_)) => "Dense",
+            Some(LayerSpec::Activation(_)) => "Activation",
+            Some(LayerSpec::BatchNorm) => "BatchNorm",
+            Some(LayerSpec::LayerNorm) => "LayerNorm",
+            Some(LayerSpec::Residual(_)) => "Residual",
+            None => "Custom",
+        }
+    }
+}
+
+/// A description of a network's layers and parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelSummary {
+    pub layers: Vec<LayerSummary>,
+    /// Whether the final layer sees the outputs of all the hidden dense
+    /// and residual layers.
+    pub output_skips: bool,
+}
+
+impl ModelSummary {
+    pub fn num_weights(&self) -> usize {
+        self.layers.iter().map(|layer| layer.num_weights).sum()
+    }
+
+    pub fn num_biases(&self) -> usize {
+        self.layers.iter().map(|layer| layer.num_biases).sum()
+    }
+
+    pub fn num_params(&self) -> usize {
+        self.layers.iter().map(|layer| layer.num_params()).sum()
+    }
+
+    /// The number of parameters that aren't in frozen layers.
+    pub fn num_trainable_params(&self) -> usize {
+        self.layers
+            .iter()
+            .filter(|layer| !layer.frozen)
+            .map(|layer| layer.num_params())
+            .sum()
+    }
+
+    /// Returns the cells of the table, starting with the header row.
+    fn rows(&self) -> Vec<Vec<String>> {
+        let mut rows = vec![
+            [
+                "#",
+                "Layer",
+                "In",
+                "Out",
+                "Activation",
+                "Weights",
+                "Biases",
+                "Params",
+                "Min",
+                "Max",
+                "Mean",
+                "Std",
+            ]
+            .map(str::to_owned)
+            .to_vec(),
+        ];
+        for (index, layer) in self.layers.iter().enumerate() {
+            let mut row = vec![
+                index.to_string(),
+                format!("{}{}", layer.kind(), if layer.frozen { " *" } else { "" }),
+                layer.num_inputs.to_string(),
+                layer.num_outputs.to_string(),
+                layer
+                    .activation
+                    .map_or("-", |activation| activation.name())
+                    .to_owned(),
+                layer.num_weights.to_string(),
+                layer.num_biases.to_string(),
+                layer.num_params().to_string(),
+            ];
+            match layer.weight_stats {
+                Some(stats) => row.extend(
+                    [stats.min, stats.max, stats.mean, stats.std]
+                        .map(|value| format!("{value:.3}")),
+                ),
+                None => row.extend(["-"; 4].map(str::to_owned)),
+            }
+            rows.push(row);
+        }
+        rows
+    }
+}
+
+impl Display for ModelSummary {
+    /// Formats the summary as a table with a row for each layer,
+    /// followed by the totals.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rows = self.rows();
+        let mut widths = vec![0; rows[0].len()];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        for row in &rows {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .enumerate()
+                .map(|(column, (cell, &width))| {
+                    // The layer's kind and activation are left-aligned,
+                    // and the numbers right-aligned.
+                    if column == 1 || column == 4 {
+                        format!("{cell:<width$}")
+                    } else {
+                        format!("{cell:>width$}")
+                    }
+                })
+                .collect();
+            writeln!(f, "{}", cells.join("  ").trim_end())?;
+        }
+        write!(
+            f,
+            "Weights: {}  Biases: {}  Params: {}  Trainable: {}",
+            self.num_weights(),
+            self.num_biases(),
+            self.num_params(),
+            self.num_trainable_params()
+        )?;
+        if self.layers.iter().any(|layer| layer.frozen) {
+            write!(f, " (* frozen)")?;
+        }
+        if self.output_skips {
+            write!(
+                f,
+                "\nThe output layer sees every hidden dense and residual layer."
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::MultiLayerPerceptron;
+
+    #[test]
+    fn test_weight_stats() {
+        let stats = WeightStats::new(&[1.0, -1.0, 3.0, 1.0]).unwrap();
+        assert_eq!(stats.min, -1.0);
+        assert_eq!(stats.max, 3.0);
+        assert_eq!(stats.mean, 1.0);
+        assert_eq!(stats.std, 2.0_f64.sqrt());
+        assert_eq!(WeightStats::new(&[]), None);
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut mlp = MultiLayerPerceptron::from_specs_with_output_skips(
+            2,
+            ActivationType::Tanh,
+            vec![
+                LayerSpec::Dense(4),
+                LayerSpec::LayerNorm,
+                LayerSpec::Dense(3),
+                LayerSpec::Dense(1),
+            ],
+        );
+        mlp.set_frozen(0, true);
+        let summary = mlp.summary();
+        let widths: Vec<(usize, usize)> = summary
+            .layers
+            .iter()
+            .map(|layer| (layer.num_inputs, layer.num_outputs))
+            .collect();
+        // The output layer sees both hidden dense layers.
+        assert_eq!(widths, vec![(2, 4), (4, 4), (4, 3), (7, 1)]);
+        assert_eq!(summary.layers[0].num_weights, 8);
+        assert_eq!(summary.layers[0].num_biases, 4);
+        assert_eq!(summary.layers[1].activation, None);
+        assert_eq!(summary.layers[1].weight_stats, None);
+        assert_eq!(summary.num_params(), mlp.num_params());
+        assert_eq!(summary.num_trainable_params(), mlp.num_params() - 12);
+
+        let table = summary.to_string();
+        assert!(table.starts_with("#  Layer"));
+        assert!(table.contains("Dense *"));
+        assert_eq!(table.lines().count(), 1 + 4 + 2);
+    }
+}
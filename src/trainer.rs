@@ -0,0 +1,641 @@
+use std::fmt::Display;
+
+use macroquad::rand::ChooseRandom;
+
+use crate::{
+    classifier_2d::{Classifier2D, Datapoint2D},
+    gradient_free::{GradientFreeMethod, GradientFreeSearch},
+    second_order::{Objective, SecondOrderMethod, SecondOrderSearch},
+};
+
+/// How a [Trainer] updates the parameters to reduce the loss.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Optimizer {
+    /// Plain stochastic gradient descent.
+    Sgd { learning_rate: f64 },
+    /// Ignores the gradient, and only looks at the loss of other
+    /// parameters, starting with random changes whose standard
+    /// deviation is `step_size`.
+    GradientFree {
+        method: GradientFreeMethod,
+        step_size: f64,
+    },
+    /// Uses the curvature of the loss as well as its gradient, choosing
+    /// how far to go with a line search.
+    SecondOrder { method: SecondOrderMethod },
+}
+
+impl Optimizer {
+    pub fn uses_gradients(&self) -> bool {
+        !matches!(self, Optimizer::GradientFree { .. })
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Optimizer::Sgd { .. } => "Gradient descent".to_owned(),
+            Optimizer::GradientFree { method, .. } => method.name(),
+            Optimizer::SecondOrder { method } => method.name(),
+        }
+    }
+}
+
+/// A snapshot of how training is going.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Metrics {
+    /// The number of optimizer steps taken so far.
+    pub step: u64,
+    /// The number of complete passes over the training points so far.
+    pub epoch: u64,
+    pub loss: f64,
+    pub regularization_loss: f64,
+    pub accuracy: f64,
+    pub mean_absolute_error: f64,
+    /// The loss on the validation points, not including regularization,
+    /// or `None` if there aren't any.
+    pub validation_loss: Option<f64>,
+    /// The accuracy on the validation points, or `None` if there aren't
+    /// any.
+    pub validation_accuracy: Option<f64>,
+    /// The number of times the loss was evaluated by the last step,
+    /// not counting the evaluation of the current parameters. Gradient
+    /// descent evaluates the loss and its gradient once.
+    pub evaluations: usize,
+}
+
+impl Metrics {
+    /// The loss that is actually being minimized.
+    pub fn total_loss(&self) -> f64 {
+        self.loss + self.regularization_loss
+    }
+}
+
+/// Why training stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The loss stopped improving for too many epochs.
+    LossPlateau,
+    /// The accuracy reached its target.
+    TargetAccuracy,
+    /// The condition given to [Trainer::run_until] was met.
+    ConditionMet,
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            StopReason::LossPlateau => "loss plateau",
+            StopReason::TargetAccuracy => "target accuracy reached",
+            StopReason::ConditionMet => "condition met",
+        })
+    }
+}
+
+/// When to stop training early. These are checked at the end of every
+/// epoch. By default, training never stops early.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EarlyStopping {
+    /// Number of epochs without improvement, and the minimum decrease
+    /// in total loss that counts as an improvement.
+    plateau: Option<(u64, f64)>,
+    target_accuracy: Option<f64>,
+}
+
+impl EarlyStopping {
+    /// Stops once the total loss hasn't decreased by at least
+    /// `min_improvement` for `patience` epochs in a row.
+    pub fn with_plateau(mut self, patience: u64, min_improvement: f64) -> Self {
+        self.plateau = Some((patience, min_improvement));
+        self
+    }
+
+    /// Stops once the accuracy is at least `accuracy`.
+    pub fn with_target_accuracy(mut self, accuracy: f64) -> Self {
+        self.target_accuracy = Some(accuracy);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.plateau.is_some() || self.target_accuracy.is_some()
+    }
+}
+
+/// Gets told about the progress of a [Trainer]. All methods do nothing
+/// by default.
+pub trait TrainingObserver {
+    /// Called after every optimizer step.
+    fn on_step(&mut self, _metrics: &Metrics) {}
+
+    /// Called after every complete pass over the datapoints.
+    fn on_epoch(&mut self, _metrics: &Metrics) {}
+
+    /// Called when training stops.
+    fn on_stop(&mut self, _reason: StopReason, _metrics: &Metrics) {}
+}
+
+/// Trains a classifier, without any dependence on a user interface.
+pub struct Trainer {
+    classifier: Classifier2D,
+    optimizer: Optimizer,
+    /// The state of a gradient-free optimizer, once it has taken a step.
+    search: Option<GradientFreeSearch>,
+    /// The state of a second-order optimizer, once it has taken a step.
+    second_order: Option<SecondOrderSearch>,
+    /// The number of datapoints per step, or `None` to use all of them.
+    batch_size: Option<usize>,
+    early_stopping: EarlyStopping,
+    observers: Vec<Box<dyn TrainingObserver>>,
+    metrics: Metrics,
+    /// Indices of the datapoints not yet visited in this epoch, in the
+    /// (shuffled) order they'll be visited.
+    remaining: Vec<usize>,
+    best_loss: f64,
+    epochs_without_improvement: u64,
+    stop_reason: Option<StopReason>,
+}
+
+impl Trainer {
+    pub fn new(classifier: Classifier2D, optimizer: Optimizer) -> Self {
+        let mut trainer = Trainer {
+            classifier,
+            optimizer,
+            search: None,
+            second_order: None,
+            batch_size: None,
+            early_stopping: EarlyStopping::default(),
+            observers: vec![],
+            metrics: Metrics::default(),
+            remaining: vec![],
+            best_loss: f64::INFINITY,
+            epochs_without_improvement: 0,
+            stop_reason: None,
+        };
+        trainer.update_metrics();
+        trainer
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        assert!(batch_size > 0, "batch size must be positive");
+        self.batch_size = Some(batch_size);
+        self
+    }
+
+    pub fn with_early_stopping(mut self, early_stopping: EarlyStopping) -> Self {
+        self.early_stopping = early_stopping;
+        self
+    }
+
+    pub fn with_observer<O: TrainingObserver + 'static>(mut self, observer: O) -> Self {
+        self.add_observer(observer);
+        self
+    }
+
+    pub fn add_observer<O: TrainingObserver + 'static>(&mut self, observer: O) {
+        self.observers.push(Box::new(observer));
+    }
+
+    pub fn classifier(&self) -> &Classifier2D {
+        &self.classifier
+    }
+
+    /// Gives access to the classifier. Call [Self::refresh] afterwards
+    /// if anything that affects the loss was changed.
+    pub fn classifier_mut(&mut self) -> &mut Classifier2D {
+        &mut self.classifier
+    }
+
+    pub fn optimizer(&self) -> Optimizer {
+        self.optimizer
+    }
+
+    /// Changes the optimizer. Setting the same one again keeps the
+    /// state of a gradient-free or second-order optimizer.
+    pub fn set_optimizer(&mut self, optimizer: Optimizer) {
+        if optimizer != self.optimizer {
+            self.optimizer = optimizer;
+            self.reset_optimizer_state();
+        }
+    }
+
+    /// Returns the state of the gradient-free optimizer, if one is being
+    /// used and has taken a step.
+    pub fn gradient_free_search(&self) -> Option<&GradientFreeSearch> {
+        self.search.as_ref()
+    }
+
+    /// Returns the state of the second-order optimizer, if one is being
+    /// used and has taken a step.
+    pub fn second_order_search(&self) -> Option<&SecondOrderSearch> {
+        self.second_order.as_ref()
+    }
+
+    pub fn early_stopping(&self) -> EarlyStopping {
+        self.early_stopping
+    }
+
+    /// Changes the early stopping criteria, and gives training another
+    /// chance if it had stopped.
+    pub fn set_early_stopping(&mut self, early_stopping: EarlyStopping) {
+        self.early_stopping = early_stopping;
+        self.reset_early_stopping();
+    }
+
+    /// Starts training a different classifier, which has already been
+    /// trained for `steps` steps.
+    pub fn replace_classifier(&mut self, classifier: Classifier2D, steps: u64) {
+        self.classifier = classifier;
+        self.reset_optimizer_state();
+        self.metrics = Metrics {
+            step: steps,
+            ..Metrics::default()
+        };
+        self.remaining.clear();
+        self.reset_early_stopping();
+    }
+
+    /// Replaces the datapoints, keeping the current weights.
+    pub fn set_datapoints(&mut self, datapoints: Vec<Datapoint2D>) {
+        self.classifier.set_datapoints(datapoints);
+        // The curvature that second-order optimizers remember is of the
+        // old loss.
+        self.second_order = None;
+        self.remaining.clear();
+        self.reset_early_stopping();
+    }
+
+    /// Recalculates the metrics on all the datapoints.
+    pub fn refresh(&mut self) {
+        self.classifier.evaluate();
+        self.update_metrics();
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics
+    }
+
+    /// Returns why training stopped, if it has.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    /// Takes a single optimizer step, unless training has stopped early.
+    /// Returns why training stopped, if it has.
+    pub fn step(&mut self) -> Option<StopReason> {
+        match self.stop_reason {
+            // The caller's condition only ends that one run, so
+            // training can carry on afterwards.
+            Some(StopReason::ConditionMet) => self.stop_reason = None,
+            Some(reason) => return Some(reason),
+            None => {}
+        }
+        let num_datapoints = self.classifier.num_training_points();
+        let batch = match self.batch_size {
+            Some(batch_size) if batch_size < num_datapoints => {
+                if self.remaining.is_empty() {
+                    self.remaining = (0..num_datapoints).collect();
+                    self.remaining.shuffle();
+                }
+                let start = self.remaining.len().saturating_sub(batch_size);
+                Some(self.remaining.split_off(start))
+            }
+            _ => None,
+        };
+
+        let mut params = self.classifier.param_values();
+        match self.optimizer {
+            Optimizer::GradientFree { method, step_size } => {
+                let loss = self.classifier.forward_loss(batch.as_deref());
+                let search = self.search.get_or_insert_with(|| {
+                    GradientFreeSearch::new(method, step_size, macroquad::rand::rand() as u64)
+                });
+                let classifier = &mut self.classifier;
+                self.metrics.evaluations = search.step(&mut params, loss, |candidate| {
+                    classifier.loss_at(candidate, batch.as_deref())
+                });
+            }
+            Optimizer::SecondOrder { method } => {
+                // The line search compares losses found in inference
+                // mode, so the gradient has to be of the same loss.
+                let grads = self.classifier.inference_gradients(batch.as_deref());
+                let loss = self.classifier.loss() + self.classifier.regularization_loss();
+                let search = self
+                    .second_order
+                    .get_or_insert_with(|| SecondOrderSearch::new(method));
+                let mut objective =
+                    ClassifierObjective::new(&mut self.classifier, batch.as_deref());
+                let grads = objective.without_frozen(grads);
+                self.metrics.evaluations =
+                    1 + search.step(&mut params, loss, &grads, &mut objective);
+            }
+            Optimizer::Sgd { learning_rate } => {
+                let grads = self.classifier.gradients(batch.as_deref());
+                for (param, grad) in params.iter_mut().zip(grads) {
+                    *param -= learning_rate * grad;
+                }
+                self.metrics.evaluations = 1;
+            }
+        }
+        self.classifier.set_param_values(&params);
+        self.metrics.step += 1;
+
+        let end_of_epoch = self.remaining.is_empty();
+        if end_of_epoch {
+            if batch.is_some() {
+                // The loss of the last batch is a poor estimate of the
+                // loss on all of the datapoints.
+                self.classifier.evaluate();
+            }
+            self.metrics.epoch += 1;
+        }
+        self.update_metrics();
+        for observer in &mut self.observers {
+            observer.on_step(&self.metrics);
+        }
+        if end_of_epoch {
+            for observer in &mut self.observers {
+                observer.on_epoch(&self.metrics);
+            }
+            if let Some(reason) = self.check_early_stopping() {
+                self.stop(reason);
+            }
+        }
+        self.stop_reason
+    }
+
+    /// Takes steps until a complete pass over the datapoints has been
+    /// made, or training stops early.
+    pub fn run_epoch(&mut self) -> Option<StopReason> {
+        let epoch = self.metrics.epoch;
+        while self.metrics.epoch == epoch {
+            if let Some(reason) = self.step() {
+                return Some(reason);
+            }
+        }
+        None
+    }
+
+    /// Takes steps until `condition` returns true for the latest
+    /// metrics, or training stops early.
+    pub fn run_until<F: FnMut(&Metrics) -> bool>(&mut self, mut condition: F) -> StopReason {
+        loop {
+            if condition(&self.metrics) {
+                self.stop(StopReason::ConditionMet);
+                return StopReason::ConditionMet;
+            }
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+        }
+    }
+
+    fn stop(&mut self, reason: StopReason) {
+        self.stop_reason = Some(reason);
+        for observer in &mut self.observers {
+            observer.on_stop(reason, &self.metrics);
+        }
+    }
+
+    fn check_early_stopping(&mut self) -> Option<StopReason> {
+        if let Some(target) = self.early_stopping.target_accuracy
+            && self.metrics.accuracy >= target
+        {
+            return Some(StopReason::TargetAccuracy);
+        }
+        if let Some((patience, min_improvement)) = self.early_stopping.plateau {
+            let loss = self.metrics.total_loss();
+            if loss < self.best_loss - min_improvement {
+                self.best_loss = loss;
+                self.epochs_without_improvement = 0;
+            } else {
+                self.epochs_without_improvement += 1;
+                if self.epochs_without_improvement >= patience {
+                    return Some(StopReason::LossPlateau);
+                }
+            }
+        }
+        None
+    }
+
+    fn reset_early_stopping(&mut self) {
+        self.best_loss = f64::INFINITY;
+        self.epochs_without_improvement = 0;
+        self.stop_reason = None;
+        self.update_metrics();
+    }
+
+    fn reset_optimizer_state(&mut self) {
+        self.search = None;
+        self.second_order = None;
+    }
+
+    fn update_metrics(&mut self) {
+        self.metrics.loss = self.classifier.loss();
+        self.metrics.regularization_loss = self.classifier.regularization_loss();
+        self.metrics.accuracy = self.classifier.accuracy();
+        self.metrics.mean_absolute_error = self.classifier.mean_absolute_error();
+        let validation = self.classifier.validation_loss_and_accuracy();
+        self.metrics.validation_loss = validation.map(|(loss, _)| loss);
+        self.metrics.validation_accuracy = validation.map(|(_, accuracy)| accuracy);
+    }
+}
+
+/// The loss of a classifier on a batch of datapoints, as a function of
+/// its trainable parameters.
+struct ClassifierObjective<'a> {
+    classifier: &'a mut Classifier2D,
+    batch: Option<&'a [usize]>,
+    trainable: Vec<bool>,
+}
+
+impl<'a> ClassifierObjective<'a> {
+    fn new(classifier: &'a mut Classifier2D, batch: Option<&'a [usize]>) -> Self {
+        let trainable = classifier.trainable_params();
+        ClassifierObjective {
+            classifier,
+            batch,
+            trainable,
+        }
+    }
+
+    /// Zeroes the gradient of the frozen parameters, which can't change,
+    /// so the line search isn't misled by them.
+    fn without_frozen(&self, mut grads: Vec<f64>) -> Vec<f64> {
+        for (grad, &trainable) in grads.iter_mut().zip(&self.trainable) {
+            if !trainable {
+                *grad = 0.0;
+            }
+        }
+        grads
+    }
+}
+
+impl Objective for ClassifierObjective<'_> {
+    fn loss(&mut self, params: &[f64]) -> f64 {
+        self.classifier.loss_at(params, self.batch)
+    }
+
+    fn gradient(&mut self, params: &[f64]) -> Vec<f64> {
+        let grads = self.classifier.gradients_at(params, self.batch);
+        self.without_frozen(grads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{
+        classifier_2d::{Classifier2D, Datapoint2D, Label2D, Weights2D},
+        layer::LayerSpec,
+    };
+
+    use super::*;
+
+    fn classifier() -> Classifier2D {
+        let datapoints = vec![
+            Datapoint2D::new((-10, 9), Label2D::Red),
+            Datapoint2D::new((8, 8), Label2D::Red),
+            Datapoint2D::new((-5, -5), Label2D::Blue),
+            Datapoint2D::new((9, -10), Label2D::Blue),
+        ];
+        Classifier2D::new(
+            datapoints,
+            Weights2D::new(2, vec![LayerSpec::Dense(4)], false),
+        )
+    }
+
+    #[derive(Default)]
+    struct Log {
+        steps: u64,
+        epochs: u64,
+        stops: Vec<StopReason>,
+    }
+
+    struct Logger(Rc<RefCell<Log>>);
+
+    impl TrainingObserver for Logger {
+        fn on_step(&mut self, _metrics: &Metrics) {
+            self.0.borrow_mut().steps += 1;
+        }
+
+        fn on_epoch(&mut self, _metrics: &Metrics) {
+            self.0.borrow_mut().epochs += 1;
+        }
+
+        fn on_stop(&mut self, reason: StopReason, _metrics: &Metrics) {
+            self.0.borrow_mut().stops.push(reason);
+        }
+    }
+
+    #[test]
+    fn test_mini_batches_make_up_epochs() {
+        let log = Rc::new(RefCell::new(Log::default()));
+        let mut trainer = Trainer::new(classifier(), Optimizer::Sgd { learning_rate: 0.1 })
+            .with_batch_size(3)
+            .with_observer(Logger(log.clone()));
+        assert_eq!(trainer.run_epoch(), None);
+        assert_eq!(trainer.run_epoch(), None);
+        assert_eq!(trainer.metrics().step, 4);
+        assert_eq!(trainer.metrics().epoch, 2);
+        assert_eq!(log.borrow().steps, 4);
+        assert_eq!(log.borrow().epochs, 2);
+    }
+
+    #[test]
+    fn test_validation_points_are_held_out() {
+        let mut datapoints = classifier().datapoints().to_vec();
+        datapoints.push(Datapoint2D::new((-9, 10), Label2D::Red).with_validation(true));
+        let weights = Weights2D::new(2, vec![LayerSpec::Dense(4)], false);
+        let mut trainer = Trainer::new(
+            Classifier2D::new(datapoints, weights),
+            Optimizer::Sgd { learning_rate: 0.5 },
+        )
+        .with_batch_size(2);
+        assert_eq!(trainer.run_epoch(), None);
+        assert_eq!(trainer.metrics().step, 2);
+        trainer.run_until(|metrics| metrics.step == 500);
+        assert_eq!(trainer.metrics().accuracy, 1.0);
+        assert_eq!(trainer.metrics().validation_accuracy, Some(1.0));
+        assert!(trainer.metrics().validation_loss.unwrap() < 0.25);
+
+        let trainer = Trainer::new(classifier(), Optimizer::Sgd { learning_rate: 0.5 });
+        assert_eq!(trainer.metrics().validation_loss, None);
+    }
+
+    #[test]
+    fn test_run_until() {
+        let log = Rc::new(RefCell::new(Log::default()));
+        let mut trainer = Trainer::new(classifier(), Optimizer::Sgd { learning_rate: 0.1 })
+            .with_observer(Logger(log.clone()));
+        let reason = trainer.run_until(|metrics| metrics.step == 5);
+        assert_eq!(reason, StopReason::ConditionMet);
+        assert_eq!(trainer.metrics().epoch, 5);
+        assert_eq!(trainer.stop_reason(), Some(StopReason::ConditionMet));
+        assert_eq!(log.borrow().stops, vec![StopReason::ConditionMet]);
+
+        // Meeting a condition doesn't stop later training.
+        assert_eq!(trainer.step(), None);
+        assert_eq!(trainer.metrics().step, 6);
+        assert_eq!(trainer.stop_reason(), None);
+    }
+
+    #[test]
+    fn test_target_accuracy_stops_training() {
+        let mut trainer = Trainer::new(classifier(), Optimizer::Sgd { learning_rate: 0.5 })
+            .with_early_stopping(EarlyStopping::default().with_target_accuracy(1.0));
+        let reason = trainer.run_until(|metrics| metrics.step == 10_000);
+        assert_eq!(reason, StopReason::TargetAccuracy);
+        assert_eq!(trainer.metrics().accuracy, 1.0);
+
+        // Nothing changes once training has stopped.
+        let step = trainer.metrics().step;
+        assert_eq!(trainer.step(), Some(StopReason::TargetAccuracy));
+        assert_eq!(trainer.metrics().step, step);
+    }
+
+    #[test]
+    fn test_plateau_stops_training() {
+        // Nothing can improve with a learning rate of zero.
+        let mut trainer = Trainer::new(classifier(), Optimizer::Sgd { learning_rate: 0.0 })
+            .with_early_stopping(EarlyStopping::default().with_plateau(3, 1e-6));
+        let reason = trainer.run_until(|metrics| metrics.step == 100);
+        assert_eq!(reason, StopReason::LossPlateau);
+        // The first epoch sets the best loss.
+        assert_eq!(trainer.metrics().epoch, 4);
+    }
+
+    #[test]
+    fn test_gradient_free_optimizer() {
+        let optimizer = Optimizer::GradientFree {
+            method: GradientFreeMethod::OnePlusLambda { lambda: 6 },
+            step_size: 0.5,
+        };
+        let mut trainer = Trainer::new(classifier(), optimizer);
+        let initial_loss = trainer.metrics().total_loss();
+        trainer.run_until(|metrics| metrics.step == 100);
+        assert_eq!(trainer.metrics().evaluations, 6);
+        assert!(trainer.metrics().total_loss() < initial_loss);
+        assert!(trainer.gradient_free_search().is_some());
+
+        // Changing the optimizer starts the search again.
+        trainer.set_optimizer(Optimizer::Sgd { learning_rate: 0.1 });
+        assert!(trainer.gradient_free_search().is_none());
+        trainer.step();
+        assert_eq!(trainer.metrics().evaluations, 1);
+    }
+
+    #[test]
+    fn test_second_order_optimizers() {
+        for method in [
+            SecondOrderMethod::Lbfgs { memory: 5 },
+            SecondOrderMethod::DampedNewton,
+        ] {
+            let mut trainer = Trainer::new(classifier(), Optimizer::SecondOrder { method });
+            let initial_loss = trainer.metrics().total_loss();
+            trainer.run_until(|metrics| metrics.step == 20);
+            assert!(trainer.metrics().total_loss() < initial_loss / 10.0);
+            assert_eq!(trainer.metrics().accuracy, 1.0);
+            let line_search = trainer.second_order_search().unwrap().line_search();
+            assert!(line_search.is_some());
+        }
+    }
+}
@@ -35,6 +35,31 @@ impl Value {
         InnerValue::new(ValueType::UnaryOp(UnaryOp::Exp, self.clone()), exp).into()
     }
 
+    pub fn ln(&self) -> Value {
+        let ln = self.as_f64().ln();
+        InnerValue::new(ValueType::UnaryOp(UnaryOp::Ln, self.clone()), ln).into()
+    }
+
+    pub fn abs(&self) -> Value {
+        let abs = self.as_f64().abs();
+        InnerValue::new(ValueType::UnaryOp(UnaryOp::Abs, self.clone()), abs).into()
+    }
+
+    pub fn relu(&self) -> Value {
+        let relu = self.as_f64().max(0.0);
+        InnerValue::new(ValueType::UnaryOp(UnaryOp::Relu, self.clone()), relu).into()
+    }
+
+    pub fn sin(&self) -> Value {
+        let sin = self.as_f64().sin();
+        InnerValue::new(ValueType::UnaryOp(UnaryOp::Sin, self.clone()), sin).into()
+    }
+
+    pub fn cos(&self) -> Value {
+        let cos = self.as_f64().cos();
+        InnerValue::new(ValueType::UnaryOp(UnaryOp::Cos, self.clone()), cos).into()
+    }
+
     pub fn pow(&self, value: f64) -> Value {
         let pow = self.as_f64().powf(value);
         InnerValue::new(
@@ -60,7 +85,7 @@ impl Value {
 
     pub fn backward(&mut self) {
         self.0.borrow_mut().grad = 1.0;
-        for mut value in BackpropIterator::new(self.clone()) {
+        for mut value in topological_order(self.clone()) {
             value.local_backward();
         }
     }
@@ -80,6 +105,29 @@ impl Value {
             ValueType::UnaryOp(UnaryOp::Exp, a) => {
                 a.0.borrow_mut().grad += value.value * value.grad;
             }
+            ValueType::UnaryOp(UnaryOp::Ln, a) => {
+                let a_f64 = a.0.borrow().value;
+                a.0.borrow_mut().grad += value.grad / a_f64;
+            }
+            ValueType::UnaryOp(UnaryOp::Abs, a) => {
+                // The derivative is undefined at zero, so we just use zero there.
+                let a_f64 = a.0.borrow().value;
+                let sign = if a_f64 == 0.0 { 0.0 } else { a_f64.signum() };
+                a.0.borrow_mut().grad += sign * value.grad;
+            }
+            ValueType::UnaryOp(UnaryOp::Relu, a) => {
+                if a.0.borrow().value > 0.0 {
+                    a.0.borrow_mut().grad += value.grad;
+                }
+            }
+            ValueType::UnaryOp(UnaryOp::Sin, a) => {
+                let a_f64 = a.0.borrow().value;
+                a.0.borrow_mut().grad += a_f64.cos() * value.grad;
+            }
+            ValueType::UnaryOp(UnaryOp::Cos, a) => {
+                let a_f64 = a.0.borrow().value;
+                a.0.borrow_mut().grad -= a_f64.sin() * value.grad;
+            }
             ValueType::BinaryOp(BinaryOp::Pow, a, pow) => {
                 let a_f64 = a.0.borrow().value;
                 let pow_f64 = pow.0.borrow().value;
@@ -90,8 +138,12 @@ impl Value {
                 b.0.borrow_mut().grad += value.grad;
             }
             ValueType::BinaryOp(BinaryOp::Mul, a, b) => {
-                a.0.borrow_mut().grad += b.0.borrow().value * value.grad;
-                b.0.borrow_mut().grad += a.0.borrow().value * value.grad;
+                // Read both values first, since `a` and `b` may be the
+                // same value (e.g. when squaring something).
+                let a_f64 = a.0.borrow().value;
+                let b_f64 = b.0.borrow().value;
+                a.0.borrow_mut().grad += b_f64 * value.grad;
+                b.0.borrow_mut().grad += a_f64 * value.grad;
             }
         }
     }
@@ -175,6 +227,11 @@ impl Display for BinaryOp {
 #[derive(Debug)]
 enum UnaryOp {
     Exp,
+    Ln,
+    Abs,
+    Relu,
+    Sin,
+    Cos,
 }
 
 impl Display for UnaryOp {
@@ -184,6 +241,11 @@ impl Display for UnaryOp {
             "{}",
             match self {
                 UnaryOp::Exp => "exp",
+                UnaryOp::Ln => "ln",
+                UnaryOp::Abs => "abs",
+                UnaryOp::Relu => "relu",
+                UnaryOp::Sin => "sin",
+                UnaryOp::Cos => "cos",
             }
         )
     }
@@ -224,35 +286,31 @@ impl Display for InnerValue {
     }
 }
 
-struct BackpropIterator {
-    visited: HashSet<i64>,
-    to_visit: Vec<Value>,
-}
-
-impl BackpropIterator {
-    fn new(root: Value) -> Self {
-        BackpropIterator {
-            visited: HashSet::new(),
-            to_visit: vec![root],
+/// Returns the given value and everything it depends on, ordered so
+/// that every value comes before all the values it depends on. This is
+/// the order backprop needs to visit them in, so that each value has
+/// received all of its gradient before passing it on to its children.
+fn topological_order(root: Value) -> Vec<Value> {
+    let mut visited = HashSet::new();
+    let mut order = vec![];
+    // Each entry is a value and whether its children have already been
+    // visited. This is iterative rather than recursive since the graph
+    // of a loss over lots of datapoints can be very deep.
+    let mut stack = vec![(root, false)];
+    while let Some((value, children_visited)) = stack.pop() {
+        if children_visited {
+            order.push(value);
+            continue;
         }
-    }
-}
-
-impl Iterator for BackpropIterator {
-    type Item = Value;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        while let Some(value) = self.to_visit.pop() {
-            let ptr = Rc::as_ptr(&value.0) as i64;
-            if self.visited.contains(&ptr) {
-                continue;
-            }
-            self.to_visit.extend(value.children());
-            return Some(value.clone());
+        if !visited.insert(Rc::as_ptr(&value.0)) {
+            continue;
         }
-
-        None
+        let children = value.children();
+        stack.push((value, true));
+        stack.extend(children.into_iter().map(|child| (child, false)));
     }
+    order.reverse();
+    order
 }
 
 #[cfg(test)]
@@ -279,6 +337,24 @@ mod tests {
         assert_eq!(loss.grad(), 1.0);
     }
 
+    #[test]
+    fn test_sin_cos() {
+        let a = Value::new_param("a", 0.5);
+        let mut sum = a.sin() + a.cos();
+        sum.backward();
+        assert_eq!(sum.as_f64(), 0.5_f64.sin() + 0.5_f64.cos());
+        assert_eq!(a.grad(), 0.5_f64.cos() - 0.5_f64.sin());
+    }
+
+    #[test]
+    fn test_ln() {
+        let a = Value::new_param("a", 2.0);
+        let mut ln = (a.exp() * a.clone()).ln();
+        ln.backward();
+        assert!((ln.as_f64() - (2.0 + 2.0_f64.ln())).abs() < 1e-12);
+        assert!((a.grad() - 1.5).abs() < 1e-12);
+    }
+
     #[test]
     fn test_div() {
         let a = Value::new_param("a", 2.0);
@@ -297,6 +373,47 @@ mod tests {
         assert_eq!(a.grad(), (2.0_f64).exp());
     }
 
+    #[test]
+    fn test_abs() {
+        let a = Value::new_param("a", -3.0);
+        let mut abs = a.abs();
+        abs.backward();
+        assert_eq!(abs.as_f64(), 3.0);
+        assert_eq!(a.grad(), -1.0);
+    }
+
+    #[test]
+    fn test_relu() {
+        let a = Value::new_param("a", 2.0);
+        let b = Value::new_param("b", -2.0);
+        let mut sum = a.relu() + b.relu();
+        sum.backward();
+        assert_eq!(sum.as_f64(), 2.0);
+        assert_eq!(a.grad(), 1.0);
+        assert_eq!(b.grad(), 0.0);
+    }
+
+    #[test]
+    fn test_shared_values_get_their_gradient_once() {
+        let a = Value::new_param("a", 3.0);
+        let b = a.clone() * Value::from(2.0);
+        // `b` is used twice, and `c` depends on it through two paths.
+        let c = b.clone() + b.clone();
+        let mut d = c.clone() + c;
+        d.backward();
+        assert_eq!(b.grad(), 4.0);
+        assert_eq!(a.grad(), 8.0);
+    }
+
+    #[test]
+    fn test_mul_by_itself() {
+        let a = Value::new_param("a", 3.0);
+        let mut a_squared = a.clone() * a.clone();
+        a_squared.backward();
+        assert_eq!(a_squared.as_f64(), 9.0);
+        assert_eq!(a.grad(), 6.0);
+    }
+
     #[test]
     fn test_sub() {
         let diff = Value::new_param("a", 2.0) - (1.0).into();